@@ -0,0 +1,80 @@
+//! Decode wallet program accounts into JSON
+//!
+//! Usage: wallet-decode <wallet|buffer|session|history> [base64 account data]
+//!        wallet-decode error <custom error code>
+//!
+//! The account data is read from stdin when it is not given as an argument.
+
+use solana_contract_wallet::decoder::{
+    error_to_json, history_to_json, instruction_buffer_to_json, session_to_json, wallet_to_json,
+};
+use std::{env, io::Read, process};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!(
+            "usage: {} <wallet|buffer|session|history> [base64 account data]\n       {} error <custom error code>",
+            args[0], args[0]
+        );
+        process::exit(2);
+    }
+
+    if args[1] == "error" {
+        let code = match args.get(2).map(|code| code.trim().parse::<u32>()) {
+            Some(Ok(code)) => code,
+            _ => {
+                eprintln!("missing or invalid custom error code");
+                process::exit(2);
+            }
+        };
+        match error_to_json(code) {
+            Ok(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap()),
+            Err(_) => {
+                eprintln!("unknown wallet error code: {}", code);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let encoded = match args.get(2) {
+        Some(encoded) => encoded.clone(),
+        None => {
+            let mut encoded = String::new();
+            if let Err(err) = std::io::stdin().read_to_string(&mut encoded) {
+                eprintln!("failed to read stdin: {}", err);
+                process::exit(1);
+            }
+            encoded
+        }
+    };
+    let data = match base64::decode(encoded.trim()) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("invalid base64 account data: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let decoded = match args[1].as_str() {
+        "wallet" => wallet_to_json(&data),
+        "buffer" => instruction_buffer_to_json(&data),
+        "session" => session_to_json(&data),
+        "history" => history_to_json(&data),
+        kind => {
+            eprintln!(
+                "unknown account kind: {}, want wallet, buffer, session or history",
+                kind
+            );
+            process::exit(2);
+        }
+    };
+    match decoded {
+        Ok(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap()),
+        Err(err) => {
+            eprintln!("failed to decode account: {}", err);
+            process::exit(1);
+        }
+    }
+}
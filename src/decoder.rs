@@ -0,0 +1,544 @@
+//! Off-chain decoders rendering program accounts as JSON
+
+use crate::{
+    error::WalletError,
+    state::{Account, History, HistoryEntry, InstructionBuffer, Session, MIN_WEIGHT},
+};
+use num_traits::FromPrimitive;
+use solana_program::{instruction::Instruction, program_error::ProgramError};
+
+/// JSON view of a wallet account
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WalletView {
+    /// account state
+    pub state: String,
+    /// owner set epoch
+    pub owner_epoch: u64,
+    /// seconds an owner waits before completing a key rotation
+    pub rotation_delay: u64,
+    /// key rotation waiting for the rotation delay
+    pub pending_rotation: Option<KeyRotationView>,
+    /// key allowed to freeze the wallet on its own
+    pub freeze_authority: Option<String>,
+    /// owner weight allowed to freeze the wallet, 0 requires the threshold
+    pub freeze_weight: u32,
+    /// whether outgoing transfers are restricted to the address book
+    pub address_book: bool,
+    /// token mint relayers are reimbursed in, null reimburses SOL
+    pub reimbursement_mint: Option<String>,
+    /// most a single instruction may reimburse its relayer
+    pub reimbursement_cap: u64,
+    /// program called before and after every instruction the wallet invokes
+    pub guard: Option<String>,
+    /// module programs allowed to invoke instructions through the wallet
+    pub modules: Vec<String>,
+    /// whether authorized instructions are recorded in the history account
+    pub history: bool,
+    /// owners with their weights
+    pub owners: Vec<OwnerView>,
+    /// maximum number of owners the account can hold
+    pub max_owners: usize,
+    /// sum of all owner weights
+    pub total_weight: u64,
+    /// signature weight required by authorized instructions
+    pub threshold: u32,
+    /// whether the owners together can still reach the threshold
+    pub threshold_reachable: bool,
+}
+
+/// JSON view of a wallet owner
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OwnerView {
+    /// owner public key
+    pub pubkey: String,
+    /// owner key weight
+    pub weight: u32,
+}
+
+/// JSON view of a pending key rotation
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyRotationView {
+    /// owner key being replaced
+    pub old_key: String,
+    /// key taking over the owner's weight
+    pub new_key: String,
+    /// unix timestamp from which the rotation can be completed
+    pub ready_at: i64,
+}
+
+/// JSON view of an instruction buffer account
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InstructionBufferView {
+    /// buffer owner
+    pub owner: String,
+    /// the wallet the buffer is staged for
+    pub wallet: String,
+    /// owner epoch of the wallet when the buffer was initialized
+    pub owner_epoch: u64,
+    /// the vault signing the staged instructions, 0 is the wallet vault
+    pub vault_index: u16,
+    /// size of the instruction data area
+    pub capacity: usize,
+    /// staged instructions
+    pub instructions: Vec<InstructionView>,
+}
+
+/// JSON view of a staged instruction
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InstructionView {
+    /// program to invoke
+    pub program_id: String,
+    /// accounts passed to the program
+    pub accounts: Vec<AccountMetaView>,
+    /// instruction data, base58 encoded
+    pub data: String,
+}
+
+/// JSON view of an instruction account
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountMetaView {
+    /// account public key
+    pub pubkey: String,
+    /// whether the account signs the instruction
+    pub is_signer: bool,
+    /// whether the account is writable
+    pub is_writable: bool,
+}
+
+/// JSON view of a session record
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionView {
+    /// the wallet the session acts for
+    pub wallet: String,
+    /// key allowed to sign for the session
+    pub session_key: String,
+    /// unix timestamp the session expires at
+    pub expires_at: i64,
+    /// lamports the session may move out of the wallet vault
+    pub lamport_cap: u64,
+    /// lamports moved so far
+    pub lamports_spent: u64,
+    /// the only token mint the session may spend
+    pub token_mint: Option<String>,
+    /// tokens the session may move out of the wallet vault
+    pub token_cap: u64,
+    /// tokens moved so far
+    pub tokens_spent: u64,
+    /// programs the session may invoke
+    pub allowed_programs: Vec<String>,
+    /// allowed instruction data prefixes, base58 encoded
+    pub data_prefixes: Vec<String>,
+}
+
+/// JSON view of a wallet history account
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryView {
+    /// the wallet the history belongs to
+    pub wallet: String,
+    /// number of entries the ring has room for
+    pub capacity: u16,
+    /// number of entries ever recorded
+    pub total: u64,
+    /// entries still in the ring, oldest first
+    pub entries: Vec<HistoryEntryView>,
+}
+
+/// JSON view of a recorded instruction
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntryView {
+    /// slot the instruction executed in
+    pub slot: u64,
+    /// wallet instruction tag
+    pub kind: u8,
+    /// program the instruction targeted
+    pub program_id: String,
+    /// sha256 hash of the wallet instruction data, hex encoded
+    pub data_hash: String,
+    /// owner weight that signed the instruction
+    pub weight: u64,
+    /// number of owners that signed the instruction
+    pub signers: u16,
+}
+
+/// JSON view of a wallet program error
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorView {
+    /// custom program error code
+    pub code: u32,
+    /// error variant name
+    pub name: String,
+    /// human readable message
+    pub message: String,
+}
+
+impl From<&Account> for WalletView {
+    fn from(account: &Account) -> Self {
+        let total_weight = account
+            .owners
+            .values()
+            .map(|weight| u64::from(*weight))
+            .sum();
+        WalletView {
+            state: format!("{:?}", account.state),
+            owner_epoch: account.owner_epoch,
+            rotation_delay: account.rotation_delay,
+            pending_rotation: account.pending_rotation.map(|rotation| KeyRotationView {
+                old_key: rotation.old_key.to_string(),
+                new_key: rotation.new_key.to_string(),
+                ready_at: rotation.ready_at,
+            }),
+            freeze_authority: account.freeze_authority.map(|pubkey| pubkey.to_string()),
+            freeze_weight: account.freeze_weight,
+            address_book: account.address_book,
+            reimbursement_mint: account.reimbursement_mint.map(|mint| mint.to_string()),
+            reimbursement_cap: account.reimbursement_cap,
+            guard: account.guard.map(|guard| guard.to_string()),
+            modules: account
+                .modules
+                .iter()
+                .map(|module| module.to_string())
+                .collect(),
+            history: account.history,
+            owners: account
+                .owners
+                .iter()
+                .map(|(pubkey, weight)| OwnerView {
+                    pubkey: pubkey.to_string(),
+                    weight: *weight,
+                })
+                .collect(),
+            max_owners: account.max_owners,
+            total_weight,
+            threshold: MIN_WEIGHT,
+            threshold_reachable: total_weight >= u64::from(MIN_WEIGHT),
+        }
+    }
+}
+
+impl From<&Instruction> for InstructionView {
+    fn from(instruction: &Instruction) -> Self {
+        InstructionView {
+            program_id: instruction.program_id.to_string(),
+            accounts: instruction
+                .accounts
+                .iter()
+                .map(|account_meta| AccountMetaView {
+                    pubkey: account_meta.pubkey.to_string(),
+                    is_signer: account_meta.is_signer,
+                    is_writable: account_meta.is_writable,
+                })
+                .collect(),
+            data: bs58::encode(&instruction.data).into_string(),
+        }
+    }
+}
+
+impl From<&Session> for SessionView {
+    fn from(session: &Session) -> Self {
+        SessionView {
+            wallet: session.wallet.to_string(),
+            session_key: session.session_key.to_string(),
+            expires_at: session.expires_at,
+            lamport_cap: session.lamport_cap,
+            lamports_spent: session.lamports_spent,
+            token_mint: session.token_mint.map(|mint| mint.to_string()),
+            token_cap: session.token_cap,
+            tokens_spent: session.tokens_spent,
+            allowed_programs: session
+                .allowed_programs
+                .iter()
+                .map(|program_id| program_id.to_string())
+                .collect(),
+            data_prefixes: session
+                .data_prefixes
+                .iter()
+                .map(|prefix| bs58::encode(prefix).into_string())
+                .collect(),
+        }
+    }
+}
+
+impl From<&HistoryEntry> for HistoryEntryView {
+    fn from(entry: &HistoryEntry) -> Self {
+        HistoryEntryView {
+            slot: entry.slot,
+            kind: entry.kind,
+            program_id: entry.program_id.to_string(),
+            data_hash: entry
+                .data_hash
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            weight: entry.weight,
+            signers: entry.signers,
+        }
+    }
+}
+
+impl From<&History> for HistoryView {
+    fn from(history: &History) -> Self {
+        HistoryView {
+            wallet: history.wallet.to_string(),
+            capacity: history.capacity,
+            total: history.total,
+            entries: history.entries.iter().map(HistoryEntryView::from).collect(),
+        }
+    }
+}
+
+impl InstructionBufferView {
+    /// Build the view from a decoded buffer, parsing the staged instructions
+    pub fn new(instruction_buffer: &InstructionBuffer) -> Result<Self, ProgramError> {
+        Ok(InstructionBufferView {
+            owner: instruction_buffer.owner.to_string(),
+            wallet: instruction_buffer.wallet.to_string(),
+            owner_epoch: instruction_buffer.owner_epoch,
+            vault_index: instruction_buffer.vault_index,
+            capacity: instruction_buffer.data.len(),
+            instructions: instruction_buffer
+                .instructions()?
+                .iter()
+                .map(InstructionView::from)
+                .collect(),
+        })
+    }
+}
+
+/// Decode raw wallet account data
+pub fn decode_wallet(data: &[u8]) -> Result<WalletView, ProgramError> {
+    Ok(WalletView::from(&Account::unpack_from_slice(data)?))
+}
+
+/// Decode raw instruction buffer account data
+pub fn decode_instruction_buffer(data: &[u8]) -> Result<InstructionBufferView, ProgramError> {
+    if data.len() < InstructionBuffer::HEADER_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    InstructionBufferView::new(&InstructionBuffer::unpack(data)?)
+}
+
+/// Decode raw session record data
+pub fn decode_session(data: &[u8]) -> Result<SessionView, ProgramError> {
+    Ok(SessionView::from(&Session::unpack(data)?))
+}
+
+/// Decode raw history account data
+pub fn decode_history(data: &[u8]) -> Result<HistoryView, ProgramError> {
+    Ok(HistoryView::from(&History::unpack(data)?))
+}
+
+/// Decode a custom program error code returned by the wallet program, None when the code is not
+/// a wallet error
+pub fn decode_error(code: u32) -> Option<ErrorView> {
+    WalletError::from_u32(code).map(|error| ErrorView {
+        code,
+        name: format!("{:?}", error),
+        message: error.to_string(),
+    })
+}
+
+/// Decode the wallet error carried by a program error, None for other program errors
+pub fn decode_program_error(error: &ProgramError) -> Option<ErrorView> {
+    match error {
+        ProgramError::Custom(code) => decode_error(*code),
+        _ => None,
+    }
+}
+
+/// Decode raw wallet account data into a JSON value
+pub fn wallet_to_json(data: &[u8]) -> Result<serde_json::Value, ProgramError> {
+    serde_json::to_value(decode_wallet(data)?).or(Err(ProgramError::InvalidAccountData))
+}
+
+/// Decode raw instruction buffer account data into a JSON value
+pub fn instruction_buffer_to_json(data: &[u8]) -> Result<serde_json::Value, ProgramError> {
+    serde_json::to_value(decode_instruction_buffer(data)?).or(Err(ProgramError::InvalidAccountData))
+}
+
+/// Decode raw session record data into a JSON value
+pub fn session_to_json(data: &[u8]) -> Result<serde_json::Value, ProgramError> {
+    serde_json::to_value(decode_session(data)?).or(Err(ProgramError::InvalidAccountData))
+}
+
+/// Decode raw history account data into a JSON value
+pub fn history_to_json(data: &[u8]) -> Result<serde_json::Value, ProgramError> {
+    serde_json::to_value(decode_history(data)?).or(Err(ProgramError::InvalidAccountData))
+}
+
+/// Decode a custom program error code into a JSON value
+pub fn error_to_json(code: u32) -> Result<serde_json::Value, ProgramError> {
+    let error = decode_error(code).ok_or(ProgramError::InvalidArgument)?;
+    serde_json::to_value(error).or(Err(ProgramError::InvalidArgument))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{state::AccountState, utils::write_instruction};
+    use maplit::btreemap;
+    use serde_json::json;
+    use solana_program::{instruction::AccountMeta, pubkey::Pubkey};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_wallet_to_json() {
+        let owner = Pubkey::from_str("EvN4kgKmCmYzdbd5kL8Q8YgkUW5RoqMTpBczrfLExtx7").unwrap();
+        let account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 2,
+            owners: btreemap! { owner => 999 },
+            max_owners: 2,
+            ..Account::default()
+        };
+        let mut data = vec![0; 399];
+        account.pack_into_slice(&mut data).unwrap();
+
+        assert_eq!(
+            wallet_to_json(&data).unwrap(),
+            json!({
+                "state": "Initialized",
+                "ownerEpoch": 2,
+                "rotationDelay": 0,
+                "pendingRotation": null,
+                "freezeAuthority": null,
+                "freezeWeight": 0,
+                "addressBook": false,
+                "reimbursementMint": null,
+                "reimbursementCap": 0,
+                "guard": null,
+                "modules": [],
+                "history": false,
+                "owners": [{ "pubkey": owner.to_string(), "weight": 999 }],
+                "maxOwners": 2,
+                "totalWeight": 999,
+                "threshold": 1000,
+                "thresholdReachable": false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_instruction_buffer_to_json() {
+        let owner = Pubkey::from_str("EvN4kgKmCmYzdbd5kL8Q8YgkUW5RoqMTpBczrfLExtx7").unwrap();
+        let wallet = Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap();
+        let program_id = Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap();
+        let account = Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap();
+        let instruction = Instruction {
+            program_id,
+            accounts: vec![AccountMeta::new(account, false)],
+            data: vec![1, 2, 3],
+        };
+
+        let mut instruction_data = vec![0; 128];
+        let mut current = 0;
+        write_instruction(&mut current, &instruction, &mut instruction_data).unwrap();
+        let mut data = vec![0; InstructionBuffer::HEADER_LEN + 128];
+        InstructionBuffer::pack(
+            InstructionBuffer {
+                owner,
+                wallet,
+                owner_epoch: 1,
+                vault_index: 2,
+                data: instruction_data,
+            },
+            &mut data,
+        )
+        .unwrap();
+
+        assert_eq!(
+            instruction_buffer_to_json(&data).unwrap(),
+            json!({
+                "owner": owner.to_string(),
+                "wallet": wallet.to_string(),
+                "ownerEpoch": 1,
+                "vaultIndex": 2,
+                "capacity": 128,
+                "instructions": [{
+                    "programId": program_id.to_string(),
+                    "accounts": [{
+                        "pubkey": account.to_string(),
+                        "isSigner": false,
+                        "isWritable": true,
+                    }],
+                    "data": "Ldp",
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_history_to_json() {
+        let wallet = Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap();
+        let program_id = Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap();
+        let mut data = vec![0; History::get_packed_len(2)];
+        History {
+            wallet,
+            capacity: 2,
+            total: 0,
+            entries: vec![],
+        }
+        .pack(&mut data)
+        .unwrap();
+        let entry = HistoryEntry {
+            slot: 7,
+            kind: 3,
+            program_id,
+            data_hash: [0xab; 32],
+            weight: 1000,
+            signers: 1,
+        };
+        History::append(&mut data, &wallet, &entry).unwrap();
+
+        assert_eq!(
+            history_to_json(&data).unwrap(),
+            json!({
+                "wallet": wallet.to_string(),
+                "capacity": 2,
+                "total": 1,
+                "entries": [{
+                    "slot": 7,
+                    "kind": 3,
+                    "programId": program_id.to_string(),
+                    "dataHash": "ab".repeat(32),
+                    "weight": 1000,
+                    "signers": 1,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_error() {
+        assert_eq!(
+            decode_program_error(&WalletError::OwnerNotFound.into()),
+            Some(ErrorView {
+                code: WalletError::OwnerNotFound as u32,
+                name: "OwnerNotFound".to_string(),
+                message: "Owner not found".to_string(),
+            })
+        );
+        assert_eq!(
+            error_to_json(WalletError::InstructionCountMismatch as u32).unwrap(),
+            json!({
+                "code": WalletError::InstructionCountMismatch as u32,
+                "name": "InstructionCountMismatch",
+                "message": "Instruction count mismatch",
+            })
+        );
+        assert_eq!(decode_error(u32::MAX), None);
+        assert_eq!(
+            decode_program_error(&ProgramError::InvalidAccountData),
+            None
+        );
+    }
+}
@@ -30,6 +30,87 @@ pub enum WalletError {
     /// State is invalid for requested operation.
     #[error("State is invalid for requested operation")]
     InvalidState,
+    /// Instruction buffer was staged by a previous owner set.
+    #[error("Instruction buffer was staged by a previous owner set")]
+    StaleInstructionBuffer,
+    /// Account has been closed.
+    #[error("Account has been closed")]
+    AccountClosed,
+    /// Wallet vault still holds funds.
+    #[error("Wallet vault is not empty")]
+    VaultNotEmpty,
+    /// Key rotation delay has not passed yet.
+    #[error("Key rotation delay has not passed yet")]
+    KeyRotationPending,
+    /// Another owner's key rotation is pending.
+    #[error("Another key rotation is pending")]
+    KeyRotationConflict,
+    /// Wallet is frozen.
+    #[error("Wallet is frozen")]
+    WalletFrozen,
+    /// Session has expired.
+    #[error("Session has expired")]
+    SessionExpired,
+    /// Instruction is outside the session scope.
+    #[error("Instruction is outside the session scope")]
+    SessionScopeViolation,
+    /// Session spending cap exceeded.
+    #[error("Session spending cap exceeded")]
+    SessionCapExceeded,
+    /// Transfer destination is not an active address book entry.
+    #[error("Transfer destination is not in the address book")]
+    DestinationNotApproved,
+    /// Address book has no room for another entry.
+    #[error("Address book is full")]
+    AddressBookFull,
+    /// Relayer reimbursement exceeds the wallet cap.
+    #[error("Relayer reimbursement exceeds the wallet cap")]
+    ReimbursementCapExceeded,
+    /// Scheduled payment is not due yet.
+    #[error("Scheduled payment is not due yet")]
+    PaymentNotDue,
+    /// Scheduled payment has made all its payments.
+    #[error("Scheduled payment is finished")]
+    ScheduleFinished,
+    /// Stream has nothing vested to withdraw.
+    #[error("Stream has nothing to withdraw")]
+    NothingVested,
+    /// Calling program is not an enabled module.
+    #[error("Module is not enabled")]
+    ModuleNotEnabled,
+    /// Wallet has no room for another module.
+    #[error("Module limit reached")]
+    ModuleLimitReached,
+    /// Invoked instruction passes through too many nested wallets.
+    #[error("Wallet nesting too deep")]
+    NestingTooDeep,
+    /// Owner set would exceed the account capacity.
+    #[error("Too many owners")]
+    TooManyOwners,
+    /// Owner key weight is 0.
+    #[error("Key weight cannot be 0")]
+    ZeroKeyWeight,
+    /// Key is already an owner.
+    #[error("Owner already exists")]
+    OwnerAlreadyExists,
+    /// Key is not an owner.
+    #[error("Owner not found")]
+    OwnerNotFound,
+    /// Instruction buffer belongs to another owner.
+    #[error("Instruction buffer owner mismatch")]
+    InstructionBufferOwnerMismatch,
+    /// Instruction buffer holds a different number of instructions than expected.
+    #[error("Instruction count mismatch")]
+    InstructionCountMismatch,
+    /// Weight does not fit the account layout or weight arithmetic overflowed.
+    #[error("Weight overflow")]
+    WeightOverflow,
+    /// Setting cannot be stored in the wallet account layout.
+    #[error("Unsupported layout")]
+    UnsupportedLayout,
+    /// Instruction buffer was initialized for another vault.
+    #[error("Instruction buffer vault mismatch")]
+    InstructionBufferVaultMismatch,
 }
 
 impl From<WalletError> for ProgramError {
@@ -56,6 +137,41 @@ impl PrintProgramError for WalletError {
             WalletError::InsufficientWeight => msg!("WalletError: InsufficientWeight"),
             WalletError::InvalidInstruction => msg!("WalletError: InvalidInstruction"),
             WalletError::InvalidState => msg!("WalletError: InvalidState"),
+            WalletError::StaleInstructionBuffer => msg!("WalletError: StaleInstructionBuffer"),
+            WalletError::AccountClosed => msg!("WalletError: AccountClosed"),
+            WalletError::VaultNotEmpty => msg!("WalletError: VaultNotEmpty"),
+            WalletError::KeyRotationPending => msg!("WalletError: KeyRotationPending"),
+            WalletError::KeyRotationConflict => msg!("WalletError: KeyRotationConflict"),
+            WalletError::WalletFrozen => msg!("WalletError: WalletFrozen"),
+            WalletError::SessionExpired => msg!("WalletError: SessionExpired"),
+            WalletError::SessionScopeViolation => msg!("WalletError: SessionScopeViolation"),
+            WalletError::SessionCapExceeded => msg!("WalletError: SessionCapExceeded"),
+            WalletError::DestinationNotApproved => msg!("WalletError: DestinationNotApproved"),
+            WalletError::AddressBookFull => msg!("WalletError: AddressBookFull"),
+            WalletError::ReimbursementCapExceeded => {
+                msg!("WalletError: ReimbursementCapExceeded")
+            }
+            WalletError::PaymentNotDue => msg!("WalletError: PaymentNotDue"),
+            WalletError::ScheduleFinished => msg!("WalletError: ScheduleFinished"),
+            WalletError::NothingVested => msg!("WalletError: NothingVested"),
+            WalletError::ModuleNotEnabled => msg!("WalletError: ModuleNotEnabled"),
+            WalletError::ModuleLimitReached => msg!("WalletError: ModuleLimitReached"),
+            WalletError::NestingTooDeep => msg!("WalletError: NestingTooDeep"),
+            WalletError::TooManyOwners => msg!("WalletError: TooManyOwners"),
+            WalletError::ZeroKeyWeight => msg!("WalletError: ZeroKeyWeight"),
+            WalletError::OwnerAlreadyExists => msg!("WalletError: OwnerAlreadyExists"),
+            WalletError::OwnerNotFound => msg!("WalletError: OwnerNotFound"),
+            WalletError::InstructionBufferOwnerMismatch => {
+                msg!("WalletError: InstructionBufferOwnerMismatch")
+            }
+            WalletError::InstructionCountMismatch => {
+                msg!("WalletError: InstructionCountMismatch")
+            }
+            WalletError::WeightOverflow => msg!("WalletError: WeightOverflow"),
+            WalletError::UnsupportedLayout => msg!("WalletError: UnsupportedLayout"),
+            WalletError::InstructionBufferVaultMismatch => {
+                msg!("WalletError: InstructionBufferVaultMismatch")
+            }
         }
     }
 }
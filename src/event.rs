@@ -0,0 +1,1695 @@
+//! Event types
+
+use crate::{
+    error::WalletError,
+    utils::{read_u32, read_u64, write_pubkey, write_u16, write_u32, write_u64},
+};
+use solana_program::{
+    log::sol_log_data,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    serialize_utils::{read_pubkey, read_u16, read_u8},
+};
+use std::collections::BTreeMap;
+
+/// Prefix of the log line written by `sol_log_data`
+#[cfg(any(test, feature = "client"))]
+const PROGRAM_DATA_LOG_PREFIX: &str = "Program data: ";
+
+/// Events emitted by the multisig wallet program on every state change.
+///
+/// Events are packed as a tag byte followed by the fields in declaration order, pubkeys are 32
+/// bytes, integers are little endian and lists are prefixed by a u16 length. New events are only
+/// ever appended so tags stay stable. Owner weights are packed as u32.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalletEvent {
+    /// Wallet initialized with its first owners
+    WalletInitialized {
+        /// wallet account
+        wallet: Pubkey,
+        /// public key => key weight
+        owners: BTreeMap<Pubkey, u32>,
+    },
+    /// Owners added to the wallet
+    OwnerAdded {
+        /// wallet account
+        wallet: Pubkey,
+        /// public key => key weight
+        owners: BTreeMap<Pubkey, u32>,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// Owner removed from the wallet
+    OwnerRemoved {
+        /// wallet account
+        wallet: Pubkey,
+        /// the removed owner
+        pubkey: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// All owners were reset
+    Recovered {
+        /// wallet account
+        wallet: Pubkey,
+        /// public key => key weight
+        owners: BTreeMap<Pubkey, u32>,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// All owners were removed
+    Revoked {
+        /// wallet account
+        wallet: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The wallet invoked another program
+    Invoked {
+        /// wallet account
+        wallet: Pubkey,
+        /// the invoked program
+        program_id: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The instructions in an instruction buffer were executed
+    BufferExecuted {
+        /// wallet account
+        wallet: Pubkey,
+        /// instruction buffer account
+        instruction_buffer: Pubkey,
+        /// number of executed instructions
+        instruction_count: u16,
+        /// owner epoch of the wallet when the buffer was initialized
+        owner_epoch: u64,
+        /// owners signing the run, empty in events packed under tag 6
+        signers: Vec<Pubkey>,
+    },
+    /// An abandoned instruction buffer was closed by the wallet
+    BufferReclaimed {
+        /// wallet account
+        wallet: Pubkey,
+        /// instruction buffer account
+        instruction_buffer: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The wallet account was closed
+    WalletClosed {
+        /// wallet account
+        wallet: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// Lamports were transferred out of the wallet vault
+    LamportsTransferred {
+        /// wallet account
+        wallet: Pubkey,
+        /// receiving account
+        destination: Pubkey,
+        /// amount of lamports
+        amount: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// Tokens were transferred out of a token account owned by the wallet vault
+    TokensTransferred {
+        /// wallet account
+        wallet: Pubkey,
+        /// sending token account
+        source: Pubkey,
+        /// receiving token account
+        destination: Pubkey,
+        /// token mint
+        mint: Pubkey,
+        /// amount of tokens
+        amount: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// An associated token account was created with rent paid by the wallet vault
+    AssociatedTokenAccountCreated {
+        /// wallet account
+        wallet: Pubkey,
+        /// the associated token account
+        account: Pubkey,
+        /// owner of the associated token account
+        recipient: Pubkey,
+        /// token mint
+        mint: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A delegate was approved for a token account owned by the wallet vault
+    TokenDelegateApproved {
+        /// wallet account
+        wallet: Pubkey,
+        /// token account
+        account: Pubkey,
+        /// the approved delegate
+        delegate: Pubkey,
+        /// amount of tokens the delegate may transfer
+        amount: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The delegate of a token account owned by the wallet vault was revoked
+    TokenDelegateRevoked {
+        /// wallet account
+        wallet: Pubkey,
+        /// token account
+        account: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A token account owned by the wallet vault was closed
+    TokenAccountClosed {
+        /// wallet account
+        wallet: Pubkey,
+        /// token account
+        account: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// Owners were added, removed and reweighted at once
+    OwnersUpdated {
+        /// wallet account
+        wallet: Pubkey,
+        /// public key => key weight of the resulting owner set
+        owners: BTreeMap<Pubkey, u32>,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// An owner scheduled a key rotation
+    KeyRotationRequested {
+        /// wallet account
+        wallet: Pubkey,
+        /// owner key being replaced
+        old_key: Pubkey,
+        /// key taking over the owner's weight
+        new_key: Pubkey,
+        /// unix timestamp from which the rotation can be completed
+        ready_at: i64,
+    },
+    /// An owner key was replaced, keeping its weight
+    OwnerKeyRotated {
+        /// wallet account
+        wallet: Pubkey,
+        /// replaced owner key
+        old_key: Pubkey,
+        /// new owner key
+        new_key: Pubkey,
+        /// key weight carried over
+        weight: u32,
+    },
+    /// The key rotation delay was changed
+    RotationDelaySet {
+        /// wallet account
+        wallet: Pubkey,
+        /// delay in seconds
+        delay: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A pending key rotation was cancelled
+    KeyRotationCancelled {
+        /// wallet account
+        wallet: Pubkey,
+        /// owner key that was to be replaced
+        old_key: Pubkey,
+        /// key that was to take over
+        new_key: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The wallet was frozen
+    Frozen {
+        /// wallet account
+        wallet: Pubkey,
+        /// owners whose signature weight was counted, or the freeze authority
+        signers: Vec<Pubkey>,
+    },
+    /// The wallet was unfrozen
+    Unfrozen {
+        /// wallet account
+        wallet: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The freeze authority and freeze weight were changed
+    FreezeConfigSet {
+        /// wallet account
+        wallet: Pubkey,
+        /// key allowed to freeze the wallet on its own
+        freeze_authority: Option<Pubkey>,
+        /// owner weight allowed to freeze the wallet
+        freeze_weight: u32,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A session key was granted a scoped session
+    SessionCreated {
+        /// wallet account
+        wallet: Pubkey,
+        /// session key
+        session_key: Pubkey,
+        /// unix timestamp the session expires at
+        expires_at: i64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A session was closed before it expired
+    SessionRevoked {
+        /// wallet account
+        wallet: Pubkey,
+        /// session key
+        session_key: Pubkey,
+        /// owners whose signature weight was counted, or the session key
+        signers: Vec<Pubkey>,
+    },
+    /// A session key invoked another program
+    SessionInvoked {
+        /// wallet account
+        wallet: Pubkey,
+        /// session key
+        session_key: Pubkey,
+        /// invoked program
+        program_id: Pubkey,
+        /// lamports moved out of the wallet vault
+        lamports: u64,
+        /// tokens moved out of the wallet vault token accounts
+        tokens: u64,
+    },
+    /// The wallet address book was created
+    AddressBookCreated {
+        /// wallet account
+        wallet: Pubkey,
+        /// seconds before a new entry becomes active
+        activation_delay: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// An address book entry was added
+    AddressBookEntryAdded {
+        /// wallet account
+        wallet: Pubkey,
+        /// the approved counterparty
+        address: Pubkey,
+        /// unix timestamp the entry becomes active at
+        active_at: i64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// An address book entry was removed
+    AddressBookEntryRemoved {
+        /// wallet account
+        wallet: Pubkey,
+        /// the removed counterparty
+        address: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The relayer of an instruction was reimbursed from the wallet vault
+    RelayerReimbursed {
+        /// wallet account
+        wallet: Pubkey,
+        /// account receiving the reimbursement
+        relayer: Pubkey,
+        /// token mint reimbursed, None for SOL
+        mint: Option<Pubkey>,
+        /// amount reimbursed
+        amount: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The relayer reimbursement configuration changed
+    ReimbursementConfigSet {
+        /// wallet account
+        wallet: Pubkey,
+        /// token mint relayers are reimbursed in, None for SOL
+        mint: Option<Pubkey>,
+        /// most a single instruction may reimburse
+        cap: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A scheduled payment was created
+    ScheduledPaymentCreated {
+        /// wallet account
+        wallet: Pubkey,
+        /// scheduled payment account
+        schedule: Pubkey,
+        /// key receiving the payments
+        recipient: Pubkey,
+        /// amount paid every interval
+        amount: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A scheduled payment was made
+    ScheduledPaymentExecuted {
+        /// wallet account
+        wallet: Pubkey,
+        /// scheduled payment account
+        schedule: Pubkey,
+        /// key receiving the payment
+        recipient: Pubkey,
+        /// amount paid
+        amount: u64,
+        /// number of payments made so far
+        executed: u64,
+    },
+    /// A scheduled payment was closed
+    ScheduledPaymentClosed {
+        /// wallet account
+        wallet: Pubkey,
+        /// scheduled payment account
+        schedule: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A stream was created
+    StreamCreated {
+        /// wallet account
+        wallet: Pubkey,
+        /// stream account
+        stream: Pubkey,
+        /// key allowed to withdraw the vested amount
+        recipient: Pubkey,
+        /// amount vested at the end
+        total: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The recipient withdrew from a stream
+    StreamWithdrawn {
+        /// wallet account
+        wallet: Pubkey,
+        /// stream account
+        stream: Pubkey,
+        /// account receiving the withdrawal
+        destination: Pubkey,
+        /// amount withdrawn
+        amount: u64,
+    },
+    /// A stream was cancelled
+    StreamCancelled {
+        /// wallet account
+        wallet: Pubkey,
+        /// stream account
+        stream: Pubkey,
+        /// vested amount paid to the recipient on cancellation
+        amount: u64,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The guard program changed
+    GuardSet {
+        /// wallet account
+        wallet: Pubkey,
+        /// the guard program, None when removed
+        guard: Option<Pubkey>,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A module program was enabled
+    ModuleEnabled {
+        /// wallet account
+        wallet: Pubkey,
+        /// the module program
+        module: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A module program was disabled
+    ModuleDisabled {
+        /// wallet account
+        wallet: Pubkey,
+        /// the module program
+        module: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// A module invoked another program through the wallet
+    ModuleExecuted {
+        /// wallet account
+        wallet: Pubkey,
+        /// the module program
+        module: Pubkey,
+        /// invoked program
+        program_id: Pubkey,
+    },
+    /// The wallet invoked another program signed by one of its vaults
+    VaultInvoked {
+        /// wallet account
+        wallet: Pubkey,
+        /// the vault signing, 0 is the wallet vault
+        vault_index: u16,
+        /// invoked program
+        program_id: Pubkey,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+    /// The wallet history was created
+    HistoryCreated {
+        /// wallet account
+        wallet: Pubkey,
+        /// number of entries the ring has room for
+        capacity: u16,
+        /// owners whose signature weight was counted
+        signers: Vec<Pubkey>,
+    },
+}
+
+impl WalletEvent {
+    /// Log the event with `sol_log_data`
+    pub fn emit(&self) {
+        sol_log_data(&[&self.pack()]);
+    }
+
+    /// Pack the event into bytes
+    pub fn pack(&self) -> Vec<u8> {
+        let mut dst = vec![0; self.get_packed_len()];
+        let mut current = 0;
+        // the buffer is sized by get_packed_len, so writes cannot fail
+        self.pack_into_slice(&mut current, &mut dst).unwrap();
+        dst
+    }
+
+    fn pack_into_slice(&self, current: &mut usize, dst: &mut [u8]) -> Result<(), ProgramError> {
+        match self {
+            Self::WalletInitialized { wallet, owners } => {
+                write_u8(current, 0, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_owners(current, owners, dst)?;
+            }
+            Self::OwnerAdded {
+                wallet,
+                owners,
+                signers,
+            } => {
+                write_u8(current, 1, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_owners(current, owners, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::OwnerRemoved {
+                wallet,
+                pubkey,
+                signers,
+            } => {
+                write_u8(current, 2, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, pubkey, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::Recovered {
+                wallet,
+                owners,
+                signers,
+            } => {
+                write_u8(current, 3, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_owners(current, owners, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::Revoked { wallet, signers } => {
+                write_u8(current, 4, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::Invoked {
+                wallet,
+                program_id,
+                signers,
+            } => {
+                write_u8(current, 5, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, program_id, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::BufferExecuted {
+                wallet,
+                instruction_buffer,
+                instruction_count,
+                owner_epoch,
+                signers,
+            } => {
+                write_u8(current, 6, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, instruction_buffer, dst)?;
+                write_u16(current, *instruction_count, dst)?;
+                write_u64(current, *owner_epoch, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::BufferReclaimed {
+                wallet,
+                instruction_buffer,
+                signers,
+            } => {
+                write_u8(current, 7, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, instruction_buffer, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::WalletClosed { wallet, signers } => {
+                write_u8(current, 8, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::LamportsTransferred {
+                wallet,
+                destination,
+                amount,
+                signers,
+            } => {
+                write_u8(current, 9, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, destination, dst)?;
+                write_u64(current, *amount, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::TokensTransferred {
+                wallet,
+                source,
+                destination,
+                mint,
+                amount,
+                signers,
+            } => {
+                write_u8(current, 10, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, source, dst)?;
+                write_pubkey(current, destination, dst)?;
+                write_pubkey(current, mint, dst)?;
+                write_u64(current, *amount, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::AssociatedTokenAccountCreated {
+                wallet,
+                account,
+                recipient,
+                mint,
+                signers,
+            } => {
+                write_u8(current, 11, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, account, dst)?;
+                write_pubkey(current, recipient, dst)?;
+                write_pubkey(current, mint, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::TokenDelegateApproved {
+                wallet,
+                account,
+                delegate,
+                amount,
+                signers,
+            } => {
+                write_u8(current, 12, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, account, dst)?;
+                write_pubkey(current, delegate, dst)?;
+                write_u64(current, *amount, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::TokenDelegateRevoked {
+                wallet,
+                account,
+                signers,
+            } => {
+                write_u8(current, 13, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, account, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::TokenAccountClosed {
+                wallet,
+                account,
+                signers,
+            } => {
+                write_u8(current, 14, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, account, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::OwnersUpdated {
+                wallet,
+                owners,
+                signers,
+            } => {
+                write_u8(current, 15, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_owners(current, owners, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::KeyRotationRequested {
+                wallet,
+                old_key,
+                new_key,
+                ready_at,
+            } => {
+                write_u8(current, 16, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, old_key, dst)?;
+                write_pubkey(current, new_key, dst)?;
+                write_u64(current, *ready_at as u64, dst)?;
+            }
+            Self::OwnerKeyRotated {
+                wallet,
+                old_key,
+                new_key,
+                weight,
+            } => {
+                write_u8(current, 17, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, old_key, dst)?;
+                write_pubkey(current, new_key, dst)?;
+                write_u32(current, *weight, dst)?;
+            }
+            Self::RotationDelaySet {
+                wallet,
+                delay,
+                signers,
+            } => {
+                write_u8(current, 18, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_u64(current, *delay, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::KeyRotationCancelled {
+                wallet,
+                old_key,
+                new_key,
+                signers,
+            } => {
+                write_u8(current, 19, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, old_key, dst)?;
+                write_pubkey(current, new_key, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::Frozen { wallet, signers } => {
+                write_u8(current, 20, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::Unfrozen { wallet, signers } => {
+                write_u8(current, 21, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::FreezeConfigSet {
+                wallet,
+                freeze_authority,
+                freeze_weight,
+                signers,
+            } => {
+                write_u8(current, 22, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, &freeze_authority.unwrap_or_default(), dst)?;
+                write_u32(current, *freeze_weight, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::SessionCreated {
+                wallet,
+                session_key,
+                expires_at,
+                signers,
+            } => {
+                write_u8(current, 23, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, session_key, dst)?;
+                write_u64(current, *expires_at as u64, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::SessionRevoked {
+                wallet,
+                session_key,
+                signers,
+            } => {
+                write_u8(current, 24, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, session_key, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::SessionInvoked {
+                wallet,
+                session_key,
+                program_id,
+                lamports,
+                tokens,
+            } => {
+                write_u8(current, 25, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, session_key, dst)?;
+                write_pubkey(current, program_id, dst)?;
+                write_u64(current, *lamports, dst)?;
+                write_u64(current, *tokens, dst)?;
+            }
+            Self::AddressBookCreated {
+                wallet,
+                activation_delay,
+                signers,
+            } => {
+                write_u8(current, 26, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_u64(current, *activation_delay, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::AddressBookEntryAdded {
+                wallet,
+                address,
+                active_at,
+                signers,
+            } => {
+                write_u8(current, 27, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, address, dst)?;
+                write_u64(current, *active_at as u64, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::AddressBookEntryRemoved {
+                wallet,
+                address,
+                signers,
+            } => {
+                write_u8(current, 28, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, address, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::RelayerReimbursed {
+                wallet,
+                relayer,
+                mint,
+                amount,
+                signers,
+            } => {
+                write_u8(current, 29, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, relayer, dst)?;
+                write_pubkey(current, &mint.unwrap_or_default(), dst)?;
+                write_u64(current, *amount, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::ReimbursementConfigSet {
+                wallet,
+                mint,
+                cap,
+                signers,
+            } => {
+                write_u8(current, 30, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, &mint.unwrap_or_default(), dst)?;
+                write_u64(current, *cap, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::ScheduledPaymentCreated {
+                wallet,
+                schedule,
+                recipient,
+                amount,
+                signers,
+            } => {
+                write_u8(current, 31, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, schedule, dst)?;
+                write_pubkey(current, recipient, dst)?;
+                write_u64(current, *amount, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::ScheduledPaymentExecuted {
+                wallet,
+                schedule,
+                recipient,
+                amount,
+                executed,
+            } => {
+                write_u8(current, 32, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, schedule, dst)?;
+                write_pubkey(current, recipient, dst)?;
+                write_u64(current, *amount, dst)?;
+                write_u64(current, *executed, dst)?;
+            }
+            Self::ScheduledPaymentClosed {
+                wallet,
+                schedule,
+                signers,
+            } => {
+                write_u8(current, 33, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, schedule, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::StreamCreated {
+                wallet,
+                stream,
+                recipient,
+                total,
+                signers,
+            } => {
+                write_u8(current, 34, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, stream, dst)?;
+                write_pubkey(current, recipient, dst)?;
+                write_u64(current, *total, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::StreamWithdrawn {
+                wallet,
+                stream,
+                destination,
+                amount,
+            } => {
+                write_u8(current, 35, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, stream, dst)?;
+                write_pubkey(current, destination, dst)?;
+                write_u64(current, *amount, dst)?;
+            }
+            Self::StreamCancelled {
+                wallet,
+                stream,
+                amount,
+                signers,
+            } => {
+                write_u8(current, 36, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, stream, dst)?;
+                write_u64(current, *amount, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::GuardSet {
+                wallet,
+                guard,
+                signers,
+            } => {
+                write_u8(current, 37, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, &guard.unwrap_or_default(), dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::ModuleEnabled {
+                wallet,
+                module,
+                signers,
+            } => {
+                write_u8(current, 38, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, module, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::ModuleDisabled {
+                wallet,
+                module,
+                signers,
+            } => {
+                write_u8(current, 39, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, module, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::ModuleExecuted {
+                wallet,
+                module,
+                program_id,
+            } => {
+                write_u8(current, 40, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_pubkey(current, module, dst)?;
+                write_pubkey(current, program_id, dst)?;
+            }
+            Self::VaultInvoked {
+                wallet,
+                vault_index,
+                program_id,
+                signers,
+            } => {
+                write_u8(current, 41, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_u16(current, *vault_index, dst)?;
+                write_pubkey(current, program_id, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+            Self::HistoryCreated {
+                wallet,
+                capacity,
+                signers,
+            } => {
+                write_u8(current, 42, dst)?;
+                write_pubkey(current, wallet, dst)?;
+                write_u16(current, *capacity, dst)?;
+                write_pubkeys(current, signers, dst)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Length of the packed event
+    pub fn get_packed_len(&self) -> usize {
+        let owners_len = |owners: &BTreeMap<Pubkey, u32>| 2 + owners.len() * 36;
+        let pubkeys_len = |pubkeys: &Vec<Pubkey>| 2 + pubkeys.len() * 32;
+        1 + match self {
+            Self::WalletInitialized { owners, .. } => 32 + owners_len(owners),
+            Self::OwnerAdded {
+                owners, signers, ..
+            }
+            | Self::Recovered {
+                owners, signers, ..
+            }
+            | Self::OwnersUpdated {
+                owners, signers, ..
+            } => 32 + owners_len(owners) + pubkeys_len(signers),
+            Self::OwnerRemoved { signers, .. }
+            | Self::Invoked { signers, .. }
+            | Self::BufferReclaimed { signers, .. }
+            | Self::TokenDelegateRevoked { signers, .. }
+            | Self::TokenAccountClosed { signers, .. }
+            | Self::SessionRevoked { signers, .. }
+            | Self::AddressBookEntryRemoved { signers, .. }
+            | Self::ScheduledPaymentClosed { signers, .. }
+            | Self::GuardSet { signers, .. }
+            | Self::ModuleEnabled { signers, .. }
+            | Self::ModuleDisabled { signers, .. } => 64 + pubkeys_len(signers),
+            Self::ModuleExecuted { .. } => 96,
+            Self::VaultInvoked { signers, .. } => 64 + 2 + pubkeys_len(signers),
+            Self::HistoryCreated { signers, .. } => 32 + 2 + pubkeys_len(signers),
+            Self::Revoked { signers, .. }
+            | Self::WalletClosed { signers, .. }
+            | Self::Frozen { signers, .. }
+            | Self::Unfrozen { signers, .. } => 32 + pubkeys_len(signers),
+            Self::BufferExecuted { signers, .. } => 64 + 2 + 8 + pubkeys_len(signers),
+            Self::LamportsTransferred { signers, .. } => 64 + 8 + pubkeys_len(signers),
+            Self::TokensTransferred { signers, .. } => 128 + 8 + pubkeys_len(signers),
+            Self::AssociatedTokenAccountCreated { signers, .. } => 128 + pubkeys_len(signers),
+            Self::TokenDelegateApproved { signers, .. } => 96 + 8 + pubkeys_len(signers),
+            Self::KeyRotationRequested { .. } => 96 + 8,
+            Self::OwnerKeyRotated { .. } => 96 + 4,
+            Self::KeyRotationCancelled { signers, .. } => 96 + pubkeys_len(signers),
+            Self::FreezeConfigSet { signers, .. } => 64 + 4 + pubkeys_len(signers),
+            Self::SessionCreated { signers, .. } | Self::AddressBookEntryAdded { signers, .. } => {
+                64 + 8 + pubkeys_len(signers)
+            }
+            Self::RotationDelaySet { signers, .. } | Self::AddressBookCreated { signers, .. } => {
+                32 + 8 + pubkeys_len(signers)
+            }
+            Self::SessionInvoked { .. } => 96 + 8 + 8,
+            Self::RelayerReimbursed { signers, .. }
+            | Self::ScheduledPaymentCreated { signers, .. }
+            | Self::StreamCreated { signers, .. } => 96 + 8 + pubkeys_len(signers),
+            Self::ScheduledPaymentExecuted { .. } => 96 + 8 + 8,
+            Self::StreamWithdrawn { .. } => 96 + 8,
+            Self::StreamCancelled { signers, .. } => 64 + 8 + pubkeys_len(signers),
+            Self::ReimbursementConfigSet { signers, .. } => 64 + 8 + pubkeys_len(signers),
+        }
+    }
+
+    /// Unpack an event from bytes
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        let invalid = |_| ProgramError::from(WalletError::InvalidInstruction);
+        let mut current = 0;
+        let tag = read_u8(&mut current, input).map_err(invalid)?;
+        let event = match tag {
+            0 => Self::WalletInitialized {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                owners: read_owners(&mut current, input)?,
+            },
+            1 => Self::OwnerAdded {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                owners: read_owners(&mut current, input)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            2 => Self::OwnerRemoved {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                pubkey: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            3 => Self::Recovered {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                owners: read_owners(&mut current, input)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            4 => Self::Revoked {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            5 => Self::Invoked {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                program_id: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            6 => Self::BufferExecuted {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                instruction_buffer: read_pubkey(&mut current, input).map_err(invalid)?,
+                instruction_count: read_u16(&mut current, input).map_err(invalid)?,
+                owner_epoch: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            7 => Self::BufferReclaimed {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                instruction_buffer: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            8 => Self::WalletClosed {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            9 => Self::LamportsTransferred {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                destination: read_pubkey(&mut current, input).map_err(invalid)?,
+                amount: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            10 => Self::TokensTransferred {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                source: read_pubkey(&mut current, input).map_err(invalid)?,
+                destination: read_pubkey(&mut current, input).map_err(invalid)?,
+                mint: read_pubkey(&mut current, input).map_err(invalid)?,
+                amount: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            11 => Self::AssociatedTokenAccountCreated {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                account: read_pubkey(&mut current, input).map_err(invalid)?,
+                recipient: read_pubkey(&mut current, input).map_err(invalid)?,
+                mint: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            12 => Self::TokenDelegateApproved {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                account: read_pubkey(&mut current, input).map_err(invalid)?,
+                delegate: read_pubkey(&mut current, input).map_err(invalid)?,
+                amount: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            13 => Self::TokenDelegateRevoked {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                account: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            14 => Self::TokenAccountClosed {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                account: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            15 => Self::OwnersUpdated {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                owners: read_owners(&mut current, input)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            16 => Self::KeyRotationRequested {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                old_key: read_pubkey(&mut current, input).map_err(invalid)?,
+                new_key: read_pubkey(&mut current, input).map_err(invalid)?,
+                ready_at: read_u64(&mut current, input).map_err(invalid)? as i64,
+            },
+            17 => Self::OwnerKeyRotated {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                old_key: read_pubkey(&mut current, input).map_err(invalid)?,
+                new_key: read_pubkey(&mut current, input).map_err(invalid)?,
+                weight: read_u32(&mut current, input).map_err(invalid)?,
+            },
+            18 => Self::RotationDelaySet {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                delay: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            19 => Self::KeyRotationCancelled {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                old_key: read_pubkey(&mut current, input).map_err(invalid)?,
+                new_key: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            20 => Self::Frozen {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            21 => Self::Unfrozen {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            22 => Self::FreezeConfigSet {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                freeze_authority: Some(read_pubkey(&mut current, input).map_err(invalid)?)
+                    .filter(|pubkey| *pubkey != Pubkey::default()),
+                freeze_weight: read_u32(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            23 => Self::SessionCreated {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                session_key: read_pubkey(&mut current, input).map_err(invalid)?,
+                expires_at: read_u64(&mut current, input).map_err(invalid)? as i64,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            24 => Self::SessionRevoked {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                session_key: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            25 => Self::SessionInvoked {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                session_key: read_pubkey(&mut current, input).map_err(invalid)?,
+                program_id: read_pubkey(&mut current, input).map_err(invalid)?,
+                lamports: read_u64(&mut current, input).map_err(invalid)?,
+                tokens: read_u64(&mut current, input).map_err(invalid)?,
+            },
+            26 => Self::AddressBookCreated {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                activation_delay: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            27 => Self::AddressBookEntryAdded {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                address: read_pubkey(&mut current, input).map_err(invalid)?,
+                active_at: read_u64(&mut current, input).map_err(invalid)? as i64,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            28 => Self::AddressBookEntryRemoved {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                address: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            29 => Self::RelayerReimbursed {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                relayer: read_pubkey(&mut current, input).map_err(invalid)?,
+                mint: Some(read_pubkey(&mut current, input).map_err(invalid)?)
+                    .filter(|pubkey| *pubkey != Pubkey::default()),
+                amount: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            30 => Self::ReimbursementConfigSet {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                mint: Some(read_pubkey(&mut current, input).map_err(invalid)?)
+                    .filter(|pubkey| *pubkey != Pubkey::default()),
+                cap: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            31 => Self::ScheduledPaymentCreated {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                schedule: read_pubkey(&mut current, input).map_err(invalid)?,
+                recipient: read_pubkey(&mut current, input).map_err(invalid)?,
+                amount: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            32 => Self::ScheduledPaymentExecuted {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                schedule: read_pubkey(&mut current, input).map_err(invalid)?,
+                recipient: read_pubkey(&mut current, input).map_err(invalid)?,
+                amount: read_u64(&mut current, input).map_err(invalid)?,
+                executed: read_u64(&mut current, input).map_err(invalid)?,
+            },
+            33 => Self::ScheduledPaymentClosed {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                schedule: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            34 => Self::StreamCreated {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                stream: read_pubkey(&mut current, input).map_err(invalid)?,
+                recipient: read_pubkey(&mut current, input).map_err(invalid)?,
+                total: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            35 => Self::StreamWithdrawn {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                stream: read_pubkey(&mut current, input).map_err(invalid)?,
+                destination: read_pubkey(&mut current, input).map_err(invalid)?,
+                amount: read_u64(&mut current, input).map_err(invalid)?,
+            },
+            36 => Self::StreamCancelled {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                stream: read_pubkey(&mut current, input).map_err(invalid)?,
+                amount: read_u64(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            37 => Self::GuardSet {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                guard: Some(read_pubkey(&mut current, input).map_err(invalid)?)
+                    .filter(|pubkey| *pubkey != Pubkey::default()),
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            38 => Self::ModuleEnabled {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                module: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            39 => Self::ModuleDisabled {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                module: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            40 => Self::ModuleExecuted {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                module: read_pubkey(&mut current, input).map_err(invalid)?,
+                program_id: read_pubkey(&mut current, input).map_err(invalid)?,
+            },
+            41 => Self::VaultInvoked {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                vault_index: read_u16(&mut current, input).map_err(invalid)?,
+                program_id: read_pubkey(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            42 => Self::HistoryCreated {
+                wallet: read_pubkey(&mut current, input).map_err(invalid)?,
+                capacity: read_u16(&mut current, input).map_err(invalid)?,
+                signers: read_pubkeys(&mut current, input)?,
+            },
+            _ => return Err(WalletError::InvalidInstruction.into()),
+        };
+        if current != input.len() {
+            return Err(WalletError::InvalidInstruction.into());
+        }
+        Ok(event)
+    }
+
+    /// Decode the events emitted by `program_id` from the log messages of a transaction.
+    ///
+    /// Data logged by other programs, including programs invoked by the wallet, is skipped.
+    #[cfg(any(test, feature = "client"))]
+    pub fn parse_logs<S: AsRef<str>>(program_id: &Pubkey, logs: &[S]) -> Vec<Self> {
+        let program_id = program_id.to_string();
+        let mut invoke_stack: Vec<String> = Vec::new();
+        let mut events = Vec::new();
+
+        for log in logs {
+            let log = log.as_ref();
+            if let Some(data) = log.strip_prefix(PROGRAM_DATA_LOG_PREFIX) {
+                if invoke_stack.last() != Some(&program_id) {
+                    continue;
+                }
+                if let Ok(bytes) = base64::decode(data.trim()) {
+                    if let Ok(event) = Self::unpack(&bytes) {
+                        events.push(event);
+                    }
+                }
+            } else if let Some(rest) = log.strip_prefix("Program ") {
+                let mut words = rest.split_whitespace();
+                match (words.next(), words.next()) {
+                    (Some(id), Some("invoke")) => invoke_stack.push(id.to_string()),
+                    (Some(_), Some("success")) | (Some(_), Some("failed:")) => {
+                        invoke_stack.pop();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        events
+    }
+}
+
+fn write_u8(current: &mut usize, src: u8, dst: &mut [u8]) -> Result<(), ProgramError> {
+    if dst.len() < *current + 1 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    dst[*current] = src;
+    *current += 1;
+    Ok(())
+}
+
+fn write_owners(
+    current: &mut usize,
+    owners: &BTreeMap<Pubkey, u32>,
+    dst: &mut [u8],
+) -> Result<(), ProgramError> {
+    write_u16(current, owners.len() as u16, dst)?;
+    for (pubkey, weight) in owners {
+        write_pubkey(current, pubkey, dst)?;
+        write_u32(current, *weight, dst)?;
+    }
+    Ok(())
+}
+
+fn write_pubkeys(
+    current: &mut usize,
+    pubkeys: &[Pubkey],
+    dst: &mut [u8],
+) -> Result<(), ProgramError> {
+    write_u16(current, pubkeys.len() as u16, dst)?;
+    for pubkey in pubkeys {
+        write_pubkey(current, pubkey, dst)?;
+    }
+    Ok(())
+}
+
+fn read_owners(current: &mut usize, input: &[u8]) -> Result<BTreeMap<Pubkey, u32>, ProgramError> {
+    let invalid = |_| ProgramError::from(WalletError::InvalidInstruction);
+    let len = read_u16(current, input).map_err(invalid)?;
+    let mut owners = BTreeMap::new();
+    for _ in 0..len {
+        let pubkey = read_pubkey(current, input).map_err(invalid)?;
+        let weight = read_u32(current, input).map_err(invalid)?;
+        owners.insert(pubkey, weight);
+    }
+    Ok(owners)
+}
+
+fn read_pubkeys(current: &mut usize, input: &[u8]) -> Result<Vec<Pubkey>, ProgramError> {
+    let invalid = |_| ProgramError::from(WalletError::InvalidInstruction);
+    let len = read_u16(current, input).map_err(invalid)?;
+    let mut pubkeys = Vec::new();
+    for _ in 0..len {
+        pubkeys.push(read_pubkey(current, input).map_err(invalid)?);
+    }
+    Ok(pubkeys)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::btreemap;
+
+    #[test]
+    fn test_event_unpack_u32_weights() {
+        let wallet = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let event = WalletEvent::WalletInitialized {
+            wallet,
+            owners: btreemap! { owner => 70_000 },
+        };
+        let packed = event.pack();
+        assert_eq!(packed[0], 0);
+        assert_eq!(WalletEvent::unpack(&packed), Ok(event));
+
+        let mut packed = vec![17];
+        for key in [wallet, owner, Pubkey::new_unique()].iter() {
+            packed.extend_from_slice(key.as_ref());
+        }
+        packed.extend_from_slice(&500u16.to_le_bytes());
+        assert_eq!(
+            WalletEvent::unpack(&packed),
+            Err(WalletError::InvalidInstruction.into())
+        );
+        packed.extend_from_slice(&0u16.to_le_bytes());
+        assert!(matches!(
+            WalletEvent::unpack(&packed),
+            Ok(WalletEvent::OwnerKeyRotated { weight: 500, .. })
+        ));
+    }
+
+    #[test]
+    fn test_event_pack() {
+        let events = vec![
+            WalletEvent::WalletInitialized {
+                wallet: Pubkey::new_unique(),
+                owners: btreemap! { Pubkey::new_unique() => 1000 },
+            },
+            WalletEvent::OwnerAdded {
+                wallet: Pubkey::new_unique(),
+                owners: btreemap! { Pubkey::new_unique() => 1, Pubkey::new_unique() => 2 },
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::OwnerRemoved {
+                wallet: Pubkey::new_unique(),
+                pubkey: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique(), Pubkey::new_unique()],
+            },
+            WalletEvent::Recovered {
+                wallet: Pubkey::new_unique(),
+                owners: btreemap! { Pubkey::new_unique() => 1000 },
+                signers: vec![],
+            },
+            WalletEvent::Revoked {
+                wallet: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::Invoked {
+                wallet: Pubkey::new_unique(),
+                program_id: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::BufferExecuted {
+                wallet: Pubkey::new_unique(),
+                instruction_buffer: Pubkey::new_unique(),
+                instruction_count: 3,
+                owner_epoch: 9,
+                signers: vec![Pubkey::new_unique(), Pubkey::new_unique()],
+            },
+            WalletEvent::BufferReclaimed {
+                wallet: Pubkey::new_unique(),
+                instruction_buffer: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::WalletClosed {
+                wallet: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::LamportsTransferred {
+                wallet: Pubkey::new_unique(),
+                destination: Pubkey::new_unique(),
+                amount: 42,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::TokensTransferred {
+                wallet: Pubkey::new_unique(),
+                source: Pubkey::new_unique(),
+                destination: Pubkey::new_unique(),
+                mint: Pubkey::new_unique(),
+                amount: 42,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::AssociatedTokenAccountCreated {
+                wallet: Pubkey::new_unique(),
+                account: Pubkey::new_unique(),
+                recipient: Pubkey::new_unique(),
+                mint: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::TokenDelegateApproved {
+                wallet: Pubkey::new_unique(),
+                account: Pubkey::new_unique(),
+                delegate: Pubkey::new_unique(),
+                amount: 42,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::TokenDelegateRevoked {
+                wallet: Pubkey::new_unique(),
+                account: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::TokenAccountClosed {
+                wallet: Pubkey::new_unique(),
+                account: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::OwnersUpdated {
+                wallet: Pubkey::new_unique(),
+                owners: btreemap! { Pubkey::new_unique() => 1000 },
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::KeyRotationRequested {
+                wallet: Pubkey::new_unique(),
+                old_key: Pubkey::new_unique(),
+                new_key: Pubkey::new_unique(),
+                ready_at: -1,
+            },
+            WalletEvent::OwnerKeyRotated {
+                wallet: Pubkey::new_unique(),
+                old_key: Pubkey::new_unique(),
+                new_key: Pubkey::new_unique(),
+                weight: 500,
+            },
+            WalletEvent::RotationDelaySet {
+                wallet: Pubkey::new_unique(),
+                delay: 86400,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::KeyRotationCancelled {
+                wallet: Pubkey::new_unique(),
+                old_key: Pubkey::new_unique(),
+                new_key: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::Frozen {
+                wallet: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::Unfrozen {
+                wallet: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::FreezeConfigSet {
+                wallet: Pubkey::new_unique(),
+                freeze_authority: Some(Pubkey::new_unique()),
+                freeze_weight: 300,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::FreezeConfigSet {
+                wallet: Pubkey::new_unique(),
+                freeze_authority: None,
+                freeze_weight: 0,
+                signers: vec![],
+            },
+            WalletEvent::SessionCreated {
+                wallet: Pubkey::new_unique(),
+                session_key: Pubkey::new_unique(),
+                expires_at: 1_700_003_600,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::SessionRevoked {
+                wallet: Pubkey::new_unique(),
+                session_key: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::SessionInvoked {
+                wallet: Pubkey::new_unique(),
+                session_key: Pubkey::new_unique(),
+                program_id: Pubkey::new_unique(),
+                lamports: 5000,
+                tokens: 7,
+            },
+            WalletEvent::AddressBookCreated {
+                wallet: Pubkey::new_unique(),
+                activation_delay: 86400,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::AddressBookEntryAdded {
+                wallet: Pubkey::new_unique(),
+                address: Pubkey::new_unique(),
+                active_at: 1_700_086_400,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::AddressBookEntryRemoved {
+                wallet: Pubkey::new_unique(),
+                address: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::RelayerReimbursed {
+                wallet: Pubkey::new_unique(),
+                relayer: Pubkey::new_unique(),
+                mint: Some(Pubkey::new_unique()),
+                amount: 5000,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::ReimbursementConfigSet {
+                wallet: Pubkey::new_unique(),
+                mint: None,
+                cap: 10_000,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::ScheduledPaymentCreated {
+                wallet: Pubkey::new_unique(),
+                schedule: Pubkey::new_unique(),
+                recipient: Pubkey::new_unique(),
+                amount: 1_000,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::ScheduledPaymentExecuted {
+                wallet: Pubkey::new_unique(),
+                schedule: Pubkey::new_unique(),
+                recipient: Pubkey::new_unique(),
+                amount: 1_000,
+                executed: 3,
+            },
+            WalletEvent::ScheduledPaymentClosed {
+                wallet: Pubkey::new_unique(),
+                schedule: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::StreamCreated {
+                wallet: Pubkey::new_unique(),
+                stream: Pubkey::new_unique(),
+                recipient: Pubkey::new_unique(),
+                total: 1_000_000,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::StreamWithdrawn {
+                wallet: Pubkey::new_unique(),
+                stream: Pubkey::new_unique(),
+                destination: Pubkey::new_unique(),
+                amount: 250_000,
+            },
+            WalletEvent::StreamCancelled {
+                wallet: Pubkey::new_unique(),
+                stream: Pubkey::new_unique(),
+                amount: 0,
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::GuardSet {
+                wallet: Pubkey::new_unique(),
+                guard: Some(Pubkey::new_unique()),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::ModuleEnabled {
+                wallet: Pubkey::new_unique(),
+                module: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::ModuleDisabled {
+                wallet: Pubkey::new_unique(),
+                module: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::ModuleExecuted {
+                wallet: Pubkey::new_unique(),
+                module: Pubkey::new_unique(),
+                program_id: Pubkey::new_unique(),
+            },
+            WalletEvent::VaultInvoked {
+                wallet: Pubkey::new_unique(),
+                vault_index: 3,
+                program_id: Pubkey::new_unique(),
+                signers: vec![Pubkey::new_unique()],
+            },
+            WalletEvent::HistoryCreated {
+                wallet: Pubkey::new_unique(),
+                capacity: 64,
+                signers: vec![Pubkey::new_unique()],
+            },
+        ];
+
+        for event in events {
+            let packed = event.pack();
+            assert_eq!(packed.len(), event.get_packed_len());
+            assert_eq!(WalletEvent::unpack(&packed), Ok(event));
+        }
+    }
+
+    #[test]
+    fn test_event_unpack_rejects_trailing_data() {
+        let mut packed = WalletEvent::Revoked {
+            wallet: Pubkey::new_unique(),
+            signers: vec![],
+        }
+        .pack();
+        packed.push(0);
+        assert_eq!(
+            WalletEvent::unpack(&packed),
+            Err(WalletError::InvalidInstruction.into())
+        );
+    }
+
+    #[test]
+    fn test_parse_logs() {
+        let program_id = crate::id();
+        let other_program_id = Pubkey::new_unique();
+        let event = WalletEvent::Invoked {
+            wallet: Pubkey::new_unique(),
+            program_id: other_program_id,
+            signers: vec![Pubkey::new_unique()],
+        };
+        let other_data = WalletEvent::Revoked {
+            wallet: Pubkey::new_unique(),
+            signers: vec![],
+        };
+
+        let logs = vec![
+            format!("Program {} invoke [1]", program_id),
+            "Program log: Instruction: Invoke".to_string(),
+            format!("Program {} invoke [2]", other_program_id),
+            format!("Program data: {}", base64::encode(other_data.pack())),
+            format!("Program {} success", other_program_id),
+            format!("Program data: {}", base64::encode(event.pack())),
+            format!(
+                "Program {} consumed 20000 of 200000 compute units",
+                program_id
+            ),
+            format!("Program {} success", program_id),
+        ];
+
+        assert_eq!(WalletEvent::parse_logs(&program_id, &logs), vec![event]);
+    }
+}
@@ -1,6 +1,9 @@
 //! Instruction types
 
-use crate::error::WalletError;
+use crate::{
+    error::WalletError,
+    utils::{read_u32, read_u64, write_instruction, write_pubkey, write_u16},
+};
 use serde::Serialize;
 use solana_program::{
     account_info::AccountInfo,
@@ -9,39 +12,59 @@ use solana_program::{
     pubkey::Pubkey,
     serialize_utils::{read_pubkey, read_u16, read_u8},
 };
-use std::{collections::BTreeMap, str};
+use std::{collections::BTreeMap, convert::TryFrom, str};
 
-/// Instructions supported by the multisig wallet program.
+/// Instructions supported by the multisig wallet program. Owner weights are packed as u32.
+///
+/// Once `WalletInstruction::InitHistory` has run, the instructions marked "with history enabled"
+/// are recorded in the wallet history. They must then also pass the history account from
+/// `get_history_address`, after their own accounts and ahead of any relayer reimbursement
+/// accounts, or they fail with `NotEnoughAccountKeys`.
 #[repr(C)]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum WalletInstruction {
     /// Add a Pubkey to owner list
+    /// With history enabled, also pass the history account
     AddOwner {
         /// public key => key weight
-        owners: BTreeMap<Pubkey, u16>,
+        owners: BTreeMap<Pubkey, u32>,
     },
     /// Remove a Pubkey from owner list
+    /// With history enabled, also pass the history account
     RemoveOwner {
         /// The public key to remove from the owner list
         pubkey: Pubkey,
     },
     /// Recovery can reset all your account owners
+    /// With history enabled, also pass the history account
     Recovery {
         /// public key => key weight
-        owners: BTreeMap<Pubkey, u16>,
+        owners: BTreeMap<Pubkey, u32>,
     },
     /// Invoke an instruction to another program
+    /// With history enabled, also pass the history account
     Invoke {
         /// The instruction for the wallet to invoke
         instruction: Instruction,
     },
-    /// Revoke will freeze wallet
-    Revoke,
+    /// Revoke burns the wallet by clearing all owners, nothing can be recovered afterwards.
+    /// Use Freeze to stop the wallet temporarily
+    /// With history enabled, also pass the history account
+    Revoke {
+        /// the wallet account being burned, must match the wallet account as a confirmation
+        wallet: Pubkey,
+    },
     /// Say hello
+    /// With history enabled, also pass the history account
     Hello,
-    /// Init an instruction buffer account
-    InitInstructionBuffer,
+    /// Init an instruction buffer account for a wallet, binding it to the current owner set and
+    /// the vault that signs the staged instructions
+    InitInstructionBuffer {
+        /// the vault signing the staged instructions, 0 is the wallet vault, optional in the data
+        vault_index: u16,
+    },
     /// Append instruction to instruction buffer
+    /// With history enabled, also pass the history account
     AppendPartialInsturciton {
         /// offset
         offset: u16,
@@ -49,12 +72,366 @@ pub enum WalletInstruction {
         data: Vec<u8>,
     },
     /// Run instructions in the instruction buffer
+    /// With history enabled, also pass the history account
     RunInstructionBuffer {
         /// expected number of instructions
         expected_instruction_count: u16,
+        /// the vault the buffer was initialized for, the run fails on any other vault, optional
+        /// in the data
+        vault_index: u16,
     },
     /// Close an insturction buffer
     CloseInstructionBuffer,
+    /// Close an abandoned instruction buffer with wallet authorization, rent goes to the wallet vault
+    /// With history enabled, also pass the history account
+    ReclaimInstructionBuffer,
+    /// Close the wallet account, the wallet vault must be empty. The program cannot list the
+    /// vault's token accounts, so they are passed after the destination and must hold no tokens
+    /// With history enabled, also pass the history account
+    CloseWallet,
+    /// Transfer lamports from the wallet vault
+    /// With history enabled, also pass the history account
+    TransferLamports {
+        /// amount of lamports to transfer
+        amount: u64,
+    },
+    /// Transfer tokens from a token account owned by the wallet vault
+    /// With history enabled, also pass the history account
+    TokenTransfer {
+        /// amount of tokens to transfer
+        amount: u64,
+        /// decimals of the token mint
+        decimals: u8,
+    },
+    /// Create an associated token account paid by the wallet vault, skipped if it exists
+    /// With history enabled, also pass the history account
+    CreateAssociatedTokenAccount,
+    /// Approve a delegate for a token account owned by the wallet vault
+    /// With history enabled, also pass the history account
+    ApproveTokenDelegate {
+        /// amount of tokens the delegate may transfer
+        amount: u64,
+        /// decimals of the token mint
+        decimals: u8,
+    },
+    /// Revoke the delegate of a token account owned by the wallet vault
+    /// With history enabled, also pass the history account
+    RevokeTokenDelegate,
+    /// Close an empty token account owned by the wallet vault, rent goes to the wallet vault
+    /// With history enabled, also pass the history account
+    CloseTokenAccount,
+    /// Add, remove and reweight owners at once
+    /// With history enabled, also pass the history account
+    UpdateOwners {
+        /// public key => key weight of the owners to add
+        adds: BTreeMap<Pubkey, u32>,
+        /// the owners to remove
+        removes: Vec<Pubkey>,
+        /// public key => new key weight of existing owners
+        weight_changes: BTreeMap<Pubkey, u32>,
+    },
+    /// Replace the signing owner key with a new key keeping its weight, signed by both keys.
+    /// With a rotation delay the first call schedules the rotation and a later call completes it.
+    /// Another owner's rotation left uncompleted for a delay after it became ready is superseded
+    RotateOwnerKey {
+        /// the key taking over the owner's weight
+        new_key: Pubkey,
+    },
+    /// Set the delay owners wait before completing a key rotation
+    /// With history enabled, also pass the history account
+    SetRotationDelay {
+        /// delay in seconds, 0 rotates at once
+        delay: u64,
+    },
+    /// Cancel the pending key rotation
+    /// With history enabled, also pass the history account
+    CancelKeyRotation,
+    /// Freeze the wallet, signed by the freeze authority, the freeze weight or the threshold
+    Freeze,
+    /// Unfreeze the wallet
+    /// With history enabled, also pass the history account
+    Unfreeze,
+    /// Configure who can freeze the wallet
+    /// With history enabled, also pass the history account
+    SetFreezeConfig {
+        /// key allowed to freeze the wallet on its own
+        freeze_authority: Option<Pubkey>,
+        /// owner weight allowed to freeze the wallet, 0 requires the threshold
+        freeze_weight: u32,
+    },
+    /// Create a session record letting a session key invoke programs within a scope until it
+    /// expires
+    /// With history enabled, also pass the history account
+    CreateSession {
+        /// key allowed to sign for the session
+        session_key: Pubkey,
+        /// unix timestamp the session expires at
+        expires_at: i64,
+        /// lamports the session may move out of the wallet vault
+        lamport_cap: u64,
+        /// the only token mint the session may spend
+        token_mint: Option<Pubkey>,
+        /// tokens the session may move out of the wallet vault token accounts
+        token_cap: u64,
+        /// programs the session may invoke
+        allowed_programs: Vec<Pubkey>,
+        /// instruction data prefixes the session may invoke, empty allows any data
+        data_prefixes: Vec<Vec<u8>>,
+    },
+    /// Close a session record before it expires, signed by any owner or the session key
+    RevokeSession,
+    /// Invoke an instruction to another program with a session key, signed by the wallet vault.
+    /// The vault may only transfer lamports through the system program, and no account may change
+    /// owner or size
+    SessionInvoke {
+        /// The instruction for the wallet to invoke
+        instruction: Instruction,
+    },
+    /// Create the wallet address book, restricting outgoing transfers to its entries
+    /// With history enabled, also pass the history account
+    InitAddressBook {
+        /// number of entries the address book has room for
+        capacity: u16,
+        /// seconds before a new entry becomes active
+        activation_delay: u64,
+    },
+    /// Add an address book entry, active after the activation delay
+    /// With history enabled, also pass the history account
+    AddAddressBookEntry {
+        /// the approved counterparty
+        address: Pubkey,
+    },
+    /// Remove an address book entry
+    /// With history enabled, also pass the history account
+    RemoveAddressBookEntry {
+        /// the counterparty to remove
+        address: Pubkey,
+    },
+    /// Run an authorized instruction and reimburse its relayer from the wallet vault, in SOL or
+    /// the configured token mint. The reimbursement accounts follow the instruction accounts
+    /// With history enabled, also pass the history account
+    WithReimbursement {
+        /// amount the relayer is reimbursed, at most the wallet reimbursement cap
+        amount: u64,
+        /// the wallet instruction relayed
+        instruction: Box<WalletInstruction>,
+    },
+    /// Configure how relayers may be reimbursed
+    /// With history enabled, also pass the history account
+    SetReimbursementConfig {
+        /// token mint relayers are reimbursed in, None reimburses SOL
+        mint: Option<Pubkey>,
+        /// most a single instruction may reimburse, 0 disables reimbursement
+        cap: u64,
+    },
+    /// Create a scheduled payment record paying a recipient from the wallet vault every interval
+    /// With history enabled, also pass the history account
+    CreateScheduledPayment {
+        /// id telling the wallet schedules apart
+        id: u64,
+        /// key receiving the payments, paid into a token account it owns when paying tokens
+        recipient: Pubkey,
+        /// token mint paid, None pays SOL
+        mint: Option<Pubkey>,
+        /// amount paid every interval
+        amount: u64,
+        /// seconds between payments
+        interval: u64,
+        /// unix timestamp the first payment is due at
+        start: i64,
+        /// no payment is due after this unix timestamp, 0 never ends
+        end: i64,
+        /// number of payments, 0 pays until the end
+        count: u64,
+    },
+    /// Make the next due payment of a scheduled payment, anyone may run it
+    ExecuteScheduledPayment,
+    /// Close a scheduled payment record
+    /// With history enabled, also pass the history account
+    CloseScheduledPayment,
+    /// Create a stream record vesting a total to a recipient linearly between start and end
+    /// With history enabled, also pass the history account
+    CreateStream {
+        /// id telling the wallet streams apart
+        id: u64,
+        /// key allowed to withdraw the vested amount
+        recipient: Pubkey,
+        /// token mint paid, None pays SOL
+        mint: Option<Pubkey>,
+        /// amount vested at the end
+        total: u64,
+        /// unix timestamp vesting starts at
+        start: i64,
+        /// nothing can be withdrawn before this unix timestamp
+        cliff: i64,
+        /// unix timestamp the total is vested at
+        end: i64,
+    },
+    /// Withdraw the vested amount of a stream, signed by its recipient
+    WithdrawStream,
+    /// Cancel a stream, paying the recipient what has vested; the rest stays in the wallet vault.
+    /// Allowed while the wallet is frozen, the vested amount then stays in the vault as well
+    /// With history enabled, also pass the history account
+    CancelStream,
+    /// Set the guard program called around every instruction the wallet invokes, see
+    /// [guard_instruction](fn.guard_instruction.html). Replacing the guard must pass the current
+    /// guard
+    /// With history enabled, also pass the history account
+    SetGuard {
+        /// the guard program, None removes the guard
+        guard: Option<Pubkey>,
+    },
+    /// Invoke an instruction to another program from an enabled module, signed by the wallet vault
+    /// once the module authority the module program derives from the wallet address signs
+    ExecuteFromModule {
+        /// The instruction for the wallet to invoke
+        instruction: Instruction,
+    },
+    /// Allow a module program to invoke instructions through the wallet
+    /// With history enabled, also pass the history account
+    EnableModule {
+        /// the module program
+        module: Pubkey,
+    },
+    /// Stop a module program from invoking instructions through the wallet
+    /// With history enabled, also pass the history account
+    DisableModule {
+        /// the module program
+        module: Pubkey,
+    },
+    /// Invoke an instruction to another program signed by one of the wallet vaults. Sub-vaults
+    /// are only reached through InvokeFromVault and instruction buffers. The built-in transfer and
+    /// token instructions, session keys, modules, scheduled payments, streams and relayer
+    /// reimbursements always use the wallet vault: what sessions, modules, schedules, streams and
+    /// relayers may spend was granted against it, and the built-in instructions keep their wire
+    /// format. Move sub-vault funds by invoking the system or token program from the sub-vault
+    /// With history enabled, also pass the history account
+    InvokeFromVault {
+        /// the vault signing the instruction, 0 is the wallet vault
+        vault_index: u16,
+        /// The instruction for the wallet to invoke
+        instruction: Instruction,
+    },
+    /// Create the wallet history, recording every authorized instruction in a ring. Afterwards
+    /// authorized instructions must also pass the history account, ahead of any relayer
+    /// reimbursement accounts
+    InitHistory {
+        /// number of entries the ring has room for
+        capacity: u16,
+    },
+}
+
+/// Creates an Invoke instruction running `instruction` through the wallet.
+///
+/// Accounts: the wallet, the first signer, the payer, the other signers, then the accounts and the
+/// program of `instruction`. To sign for a parent wallet owned by this wallet's vault, pass the
+/// parent wallet instruction with the vault marked as a signer. Once the wallet keeps a history,
+/// push the history account from `get_history_address` onto the returned accounts.
+pub fn invoke(
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    payer: &Pubkey,
+    signers: &[Pubkey],
+    instruction: &Instruction,
+) -> Result<Instruction, ProgramError> {
+    let (first_signer, other_signers) = signers
+        .split_first()
+        .ok_or(WalletError::InvalidInstruction)?;
+    let mut accounts = vec![
+        AccountMeta::new(*wallet, false),
+        AccountMeta::new_readonly(*first_signer, true),
+        AccountMeta::new(*payer, true),
+    ];
+    accounts.extend(
+        other_signers
+            .iter()
+            .map(|signer| AccountMeta::new_readonly(*signer, true)),
+    );
+
+    let mut index_of = |pubkey: &Pubkey, is_writable: bool| {
+        let index = match accounts
+            .iter()
+            .position(|account| account.pubkey == *pubkey)
+        {
+            Some(index) => index,
+            None => {
+                accounts.push(AccountMeta::new_readonly(*pubkey, false));
+                accounts.len() - 1
+            }
+        };
+        accounts[index].is_writable |= is_writable;
+        u8::try_from(index).or(Err(WalletError::InvalidInstruction))
+    };
+
+    let mut data = vec![3, index_of(&instruction.program_id, false)?];
+    data.extend_from_slice(&(instruction.accounts.len() as u16).to_le_bytes());
+    for account in &instruction.accounts {
+        data.push(index_of(&account.pubkey, account.is_writable)?);
+        data.push((account.is_signer as u8) << 1 | account.is_writable as u8);
+    }
+    data.extend_from_slice(&instruction.data);
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// When the guard program is called
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuardPhase {
+    /// before the wallet invokes the instruction
+    PreExecution,
+    /// after the wallet invoked the instruction
+    PostExecution,
+}
+
+/// Creates the instruction the wallet sends its guard program.
+///
+/// Data: phase (u8), approving signers (u16 length + public keys), then the pending instruction
+/// in the instruction buffer layout. Accounts: the wallet followed by the accounts of the pending
+/// instruction, all read-only and not signing.
+pub fn guard_instruction(
+    guard: &Pubkey,
+    wallet: &Pubkey,
+    phase: GuardPhase,
+    instruction: &Instruction,
+    signers: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![
+        0;
+        1 + 2
+            + signers.len() * 32
+            + 2
+            + instruction.accounts.len() * 33
+            + 32
+            + 2
+            + instruction.data.len()
+    ];
+    let mut current = 0;
+    data[current] = phase as u8;
+    current += 1;
+    write_u16(&mut current, signers.len() as u16, &mut data)?;
+    for signer in signers {
+        write_pubkey(&mut current, signer, &mut data)?;
+    }
+    write_instruction(&mut current, instruction, &mut data)?;
+
+    let mut accounts = vec![AccountMeta::new_readonly(*wallet, false)];
+    accounts.extend(
+        instruction
+            .accounts
+            .iter()
+            .map(|account| AccountMeta::new_readonly(account.pubkey, false)),
+    );
+
+    Ok(Instruction {
+        program_id: *guard,
+        accounts,
+        data,
+    })
 }
 
 impl WalletInstruction {
@@ -69,7 +446,7 @@ impl WalletInstruction {
                 let mut owners = BTreeMap::new();
                 while current < rest.len() {
                     let pubkey = read_pubkey(&mut current, rest).unwrap();
-                    let weight = read_u16(&mut current, rest).unwrap();
+                    let weight = read_u32(&mut current, rest).or(Err(InvalidInstruction))?;
                     owners.insert(pubkey, weight);
                 }
                 Self::AddOwner { owners: owners }
@@ -86,55 +463,353 @@ impl WalletInstruction {
                 let mut owners = BTreeMap::new();
                 while current < rest.len() {
                     let pubkey = read_pubkey(&mut current, rest).unwrap();
-                    let weight = read_u16(&mut current, rest).unwrap();
+                    let weight = read_u32(&mut current, rest).or(Err(InvalidInstruction))?;
                     owners.insert(pubkey, weight);
                 }
                 Self::Recovery { owners: owners }
             }
             // Invoke
-            3 => {
+            3 => Self::Invoke {
+                instruction: Self::unpack_indexed_instruction(rest, accounts)?,
+            },
+            4 => {
                 let mut current = 0;
-                let program_id_idx = usize::from(read_u8(&mut current, rest).unwrap());
-                let account_len = usize::from(read_u16(&mut current, rest).unwrap());
-
-                let mut invoke_accounts = Vec::new();
-                for _ in 0..account_len {
-                    let account_idx = usize::from(read_u8(&mut current, rest).unwrap());
-                    let account_metadata = read_u8(&mut current, rest).unwrap();
-
-                    let account_meta = AccountMeta {
-                        pubkey: *accounts[account_idx].key,
-                        is_signer: account_metadata >> 1 & 1 == 1,
-                        is_writable: account_metadata & 1 == 1,
-                    };
-                    invoke_accounts.push(account_meta);
-                }
-
-                Self::Invoke {
-                    instruction: Instruction {
-                        program_id: *accounts[program_id_idx].key,
-                        accounts: invoke_accounts,
-                        data: rest[current..].to_vec(),
-                    },
-                }
+                let wallet = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::Revoke { wallet }
             }
-            4 => Self::Revoke,
             // Hello (testing)
             5 => Self::Hello,
-            6 => Self::InitInstructionBuffer,
+            6 => {
+                let mut current = 0;
+                // older clients only send the tag and stage for the wallet vault
+                let vault_index = if current < rest.len() {
+                    read_u16(&mut current, rest).or(Err(InvalidInstruction))?
+                } else {
+                    0
+                };
+                Self::InitInstructionBuffer { vault_index }
+            }
             7 => {
                 let mut current = 0;
                 let offset = read_u16(&mut current, rest).unwrap();
-                let data = rest[current..].iter().cloned().collect();
+                let data = rest[current..].to_vec();
                 Self::AppendPartialInsturciton { offset, data }
             }
             8 => {
                 let mut current = 0;
                 let expected_instruction_count = read_u16(&mut current, rest).unwrap();
-                Self::RunInstructionBuffer { expected_instruction_count }
+                // older clients only send the instruction count
+                let vault_index = if current < rest.len() {
+                    read_u16(&mut current, rest).or(Err(InvalidInstruction))?
+                } else {
+                    0
+                };
+                Self::RunInstructionBuffer {
+                    expected_instruction_count,
+                    vault_index,
+                }
             }
             9 => Self::CloseInstructionBuffer,
+            10 => Self::ReclaimInstructionBuffer,
+            11 => Self::CloseWallet,
+            12 => {
+                let mut current = 0;
+                let amount = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::TransferLamports { amount }
+            }
+            13 => {
+                let mut current = 0;
+                let amount = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let decimals = read_u8(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::TokenTransfer { amount, decimals }
+            }
+            14 => Self::CreateAssociatedTokenAccount,
+            15 => {
+                let mut current = 0;
+                let amount = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let decimals = read_u8(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::ApproveTokenDelegate { amount, decimals }
+            }
+            16 => Self::RevokeTokenDelegate,
+            17 => Self::CloseTokenAccount,
+            18 => {
+                let mut current = 0;
+                let adds = Self::unpack_owners(&mut current, rest)?;
+                let removes_len = read_u16(&mut current, rest).or(Err(InvalidInstruction))?;
+                let mut removes = Vec::new();
+                for _ in 0..removes_len {
+                    removes.push(read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?);
+                }
+                let weight_changes = Self::unpack_owners(&mut current, rest)?;
+                Self::UpdateOwners {
+                    adds,
+                    removes,
+                    weight_changes,
+                }
+            }
+            19 => {
+                let mut current = 0;
+                let new_key = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::RotateOwnerKey { new_key }
+            }
+            20 => {
+                let mut current = 0;
+                let delay = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::SetRotationDelay { delay }
+            }
+            21 => Self::CancelKeyRotation,
+            22 => Self::Freeze,
+            23 => Self::Unfreeze,
+            24 => {
+                let mut current = 0;
+                let freeze_authority =
+                    read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                let freeze_weight = read_u32(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::SetFreezeConfig {
+                    // the default pubkey clears the freeze authority
+                    freeze_authority: if freeze_authority == Pubkey::default() {
+                        None
+                    } else {
+                        Some(freeze_authority)
+                    },
+                    freeze_weight,
+                }
+            }
+            25 => {
+                let mut current = 0;
+                let session_key = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                let expires_at = read_u64(&mut current, rest).or(Err(InvalidInstruction))? as i64;
+                let lamport_cap = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let token_mint = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                let token_cap = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let mut allowed_programs = Vec::new();
+                for _ in 0..read_u16(&mut current, rest).or(Err(InvalidInstruction))? {
+                    allowed_programs
+                        .push(read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?);
+                }
+                let mut data_prefixes = Vec::new();
+                for _ in 0..read_u16(&mut current, rest).or(Err(InvalidInstruction))? {
+                    let len =
+                        usize::from(read_u16(&mut current, rest).or(Err(InvalidInstruction))?);
+                    if rest.len() < current + len {
+                        return Err(InvalidInstruction.into());
+                    }
+                    data_prefixes.push(rest[current..current + len].to_vec());
+                    current += len;
+                }
+                Self::CreateSession {
+                    session_key,
+                    expires_at,
+                    lamport_cap,
+                    // the default pubkey allows no token spending
+                    token_mint: if token_mint == Pubkey::default() {
+                        None
+                    } else {
+                        Some(token_mint)
+                    },
+                    token_cap,
+                    allowed_programs,
+                    data_prefixes,
+                }
+            }
+            26 => Self::RevokeSession,
+            27 => Self::SessionInvoke {
+                instruction: Self::unpack_indexed_instruction(rest, accounts)?,
+            },
+            28 => {
+                let mut current = 0;
+                let capacity = read_u16(&mut current, rest).or(Err(InvalidInstruction))?;
+                let activation_delay = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::InitAddressBook {
+                    capacity,
+                    activation_delay,
+                }
+            }
+            29 => {
+                let mut current = 0;
+                let address = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::AddAddressBookEntry { address }
+            }
+            30 => {
+                let mut current = 0;
+                let address = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::RemoveAddressBookEntry { address }
+            }
+            31 => {
+                let mut current = 0;
+                let amount = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let instruction = Self::unpack(&rest[current..], accounts)?;
+                // a relayed instruction is reimbursed once
+                if let Self::WithReimbursement { .. } = instruction {
+                    return Err(InvalidInstruction.into());
+                }
+                Self::WithReimbursement {
+                    amount,
+                    instruction: Box::new(instruction),
+                }
+            }
+            32 => {
+                let mut current = 0;
+                let mint = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                let cap = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::SetReimbursementConfig {
+                    // the default pubkey reimburses SOL
+                    mint: if mint == Pubkey::default() {
+                        None
+                    } else {
+                        Some(mint)
+                    },
+                    cap,
+                }
+            }
+            33 => {
+                let mut current = 0;
+                let id = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let recipient = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                let mint = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                let amount = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let interval = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let start = read_u64(&mut current, rest).or(Err(InvalidInstruction))? as i64;
+                let end = read_u64(&mut current, rest).or(Err(InvalidInstruction))? as i64;
+                let count = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::CreateScheduledPayment {
+                    id,
+                    recipient,
+                    // the default pubkey pays SOL
+                    mint: if mint == Pubkey::default() {
+                        None
+                    } else {
+                        Some(mint)
+                    },
+                    amount,
+                    interval,
+                    start,
+                    end,
+                    count,
+                }
+            }
+            34 => Self::ExecuteScheduledPayment,
+            35 => Self::CloseScheduledPayment,
+            36 => {
+                let mut current = 0;
+                let id = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let recipient = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                let mint = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                let total = read_u64(&mut current, rest).or(Err(InvalidInstruction))?;
+                let start = read_u64(&mut current, rest).or(Err(InvalidInstruction))? as i64;
+                let cliff = read_u64(&mut current, rest).or(Err(InvalidInstruction))? as i64;
+                let end = read_u64(&mut current, rest).or(Err(InvalidInstruction))? as i64;
+                Self::CreateStream {
+                    id,
+                    recipient,
+                    // the default pubkey pays SOL
+                    mint: if mint == Pubkey::default() {
+                        None
+                    } else {
+                        Some(mint)
+                    },
+                    total,
+                    start,
+                    cliff,
+                    end,
+                }
+            }
+            37 => Self::WithdrawStream,
+            38 => Self::CancelStream,
+            39 => {
+                let mut current = 0;
+                let guard = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::SetGuard {
+                    // the default pubkey removes the guard
+                    guard: if guard == Pubkey::default() {
+                        None
+                    } else {
+                        Some(guard)
+                    },
+                }
+            }
+            40 => Self::ExecuteFromModule {
+                instruction: Self::unpack_indexed_instruction(rest, accounts)?,
+            },
+            41 => {
+                let mut current = 0;
+                let module = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::EnableModule { module }
+            }
+            42 => {
+                let mut current = 0;
+                let module = read_pubkey(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::DisableModule { module }
+            }
+            43 => {
+                let mut current = 0;
+                let vault_index = read_u16(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::InvokeFromVault {
+                    vault_index,
+                    instruction: Self::unpack_indexed_instruction(&rest[current..], accounts)?,
+                }
+            }
+            44 => {
+                let mut current = 0;
+                let capacity = read_u16(&mut current, rest).or(Err(InvalidInstruction))?;
+                Self::InitHistory { capacity }
+            }
             _ => return Err(WalletError::InvalidInstruction.into()),
         })
     }
+
+    /// Unpack an instruction whose program and accounts are indexes into the instruction accounts
+    fn unpack_indexed_instruction(
+        input: &[u8],
+        accounts: &[AccountInfo],
+    ) -> Result<Instruction, ProgramError> {
+        use WalletError::InvalidInstruction;
+        let account_key = |idx: u8| {
+            accounts
+                .get(usize::from(idx))
+                .map(|account| *account.key)
+                .ok_or(InvalidInstruction)
+        };
+
+        let mut current = 0;
+        let program_id_idx = read_u8(&mut current, input).or(Err(InvalidInstruction))?;
+        let account_len = read_u16(&mut current, input).or(Err(InvalidInstruction))?;
+
+        let mut invoke_accounts = Vec::new();
+        for _ in 0..account_len {
+            let account_idx = read_u8(&mut current, input).or(Err(InvalidInstruction))?;
+            let account_metadata = read_u8(&mut current, input).or(Err(InvalidInstruction))?;
+
+            let account_meta = AccountMeta {
+                pubkey: account_key(account_idx)?,
+                is_signer: account_metadata >> 1 & 1 == 1,
+                is_writable: account_metadata & 1 == 1,
+            };
+            invoke_accounts.push(account_meta);
+        }
+
+        Ok(Instruction {
+            program_id: account_key(program_id_idx)?,
+            accounts: invoke_accounts,
+            data: input[current..].to_vec(),
+        })
+    }
+
+    /// Unpack a u16 length prefixed list of (public key, key weight)
+    fn unpack_owners(
+        current: &mut usize,
+        input: &[u8],
+    ) -> Result<BTreeMap<Pubkey, u32>, ProgramError> {
+        use WalletError::InvalidInstruction;
+        let len = read_u16(current, input).or(Err(InvalidInstruction))?;
+        let mut owners = BTreeMap::new();
+        for _ in 0..len {
+            let pubkey = read_pubkey(current, input).or(Err(InvalidInstruction))?;
+            let weight = read_u32(current, input).or(Err(InvalidInstruction))?;
+            if owners.insert(pubkey, weight).is_some() {
+                return Err(InvalidInstruction.into());
+            }
+        }
+        Ok(owners)
+    }
 }
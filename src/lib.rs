@@ -6,10 +6,17 @@
 #[macro_use]
 extern crate serde_derive;
 
+#[cfg(any(test, feature = "client"))]
+pub mod decoder;
 pub mod error;
+pub mod event;
 pub mod instruction;
+pub mod planner;
 pub mod processor;
 pub mod state;
+#[cfg(any(test, feature = "test-utils"))]
+#[doc(hidden)]
+pub mod test_utils;
 pub mod utils;
 
 #[cfg(not(feature = "no-entrypoint"))]
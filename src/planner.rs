@@ -0,0 +1,253 @@
+//! Signer planning helpers for clients
+//!
+//! These functions count weight with `Account::signed_weight`, the same rule the program uses to
+//! authorize instructions, so a plan that meets the threshold here passes the on-chain check.
+
+use crate::state::{Account, MIN_WEIGHT};
+use solana_program::pubkey::Pubkey;
+use std::collections::BTreeSet;
+
+/// What is still needed to authorize an instruction
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SignerPlan {
+    /// signature weight required by authorized instructions
+    pub threshold: u32,
+    /// weight of the owners that already signed
+    pub signed_weight: u64,
+    /// owners that already signed and were counted
+    pub signed: Vec<Pubkey>,
+    /// whether the signed weight already reaches the threshold
+    pub threshold_met: bool,
+    /// weight still missing to reach the threshold, 0 when it is met
+    pub missing_weight: u32,
+    /// fewest candidates that still have to sign, None when the candidates cannot reach the
+    /// threshold
+    pub min_count_signers: Option<Vec<Pubkey>>,
+    /// candidates whose weight overshoots the missing weight the least, ties broken by count,
+    /// None when the candidates cannot reach the threshold
+    pub min_weight_signers: Option<Vec<Pubkey>>,
+}
+
+/// Plan which of the `candidates` have to sign next, given the keys that already `signed`.
+///
+/// Keys that are not owners, or that appear more than once, are ignored the same way the
+/// on-chain check ignores them.
+pub fn plan_signers(account: &Account, signed: &[Pubkey], candidates: &[Pubkey]) -> SignerPlan {
+    let (signed_weight, signed) = account.signed_weight(signed);
+    let threshold_met = signed_weight >= u64::from(MIN_WEIGHT);
+    // never above MIN_WEIGHT, so it fits in u32
+    let missing_weight = (u64::from(MIN_WEIGHT).saturating_sub(signed_weight)) as u32;
+
+    let signed_keys: BTreeSet<&Pubkey> = signed.iter().collect();
+    let mut seen = BTreeSet::new();
+    let candidates: Vec<(Pubkey, u32)> = candidates
+        .iter()
+        .filter(|pubkey| !signed_keys.contains(pubkey) && seen.insert(**pubkey))
+        .filter_map(|pubkey| account.owners.get(pubkey).map(|weight| (*pubkey, *weight)))
+        .collect();
+
+    SignerPlan {
+        threshold: MIN_WEIGHT,
+        signed_weight,
+        signed,
+        threshold_met,
+        missing_weight,
+        min_count_signers: min_count_signers(&candidates, missing_weight),
+        min_weight_signers: min_weight_signers(&candidates, missing_weight),
+    }
+}
+
+/// Fewest keys whose weight reaches `missing_weight`: taking the heaviest keys first is optimal
+fn min_count_signers(candidates: &[(Pubkey, u32)], missing_weight: u32) -> Option<Vec<Pubkey>> {
+    let mut sorted = candidates.to_vec();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut weight = 0u64;
+    let mut signers = Vec::new();
+    for (pubkey, key_weight) in sorted {
+        if weight >= u64::from(missing_weight) {
+            break;
+        }
+        weight += u64::from(key_weight);
+        signers.push(pubkey);
+    }
+
+    if weight >= u64::from(missing_weight) {
+        Some(signers)
+    } else {
+        None
+    }
+}
+
+/// Keys whose total weight is the smallest one reaching `missing_weight`, found with a 0/1
+/// knapsack over the reachable weight sums
+fn min_weight_signers(candidates: &[(Pubkey, u32)], missing_weight: u32) -> Option<Vec<Pubkey>> {
+    if missing_weight == 0 {
+        return Some(vec![]);
+    }
+
+    // a key that covers the missing weight alone is never combined with others, so only the
+    // lightest such key competes with the knapsack over the lighter keys
+    let heavy = candidates
+        .iter()
+        .filter(|(_, weight)| *weight >= missing_weight)
+        .min_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)))
+        .copied();
+    let light: Vec<(Pubkey, u32)> = candidates
+        .iter()
+        .filter(|(_, weight)| *weight < missing_weight)
+        .copied()
+        .collect();
+
+    match (heavy, light_min_weight_signers(&light, missing_weight)) {
+        (Some((pubkey, weight)), Some((sum, signers))) => {
+            if u64::from(weight) < sum {
+                Some(vec![pubkey])
+            } else {
+                Some(signers)
+            }
+        }
+        (Some((pubkey, _)), None) => Some(vec![pubkey]),
+        (None, Some((_, signers))) => Some(signers),
+        (None, None) => None,
+    }
+}
+
+/// Knapsack over keys lighter than `missing_weight`, returning the reached sum and the keys
+fn light_min_weight_signers(
+    candidates: &[(Pubkey, u32)],
+    missing_weight: u32,
+) -> Option<(u64, Vec<Pubkey>)> {
+    // a minimal subset never overshoots by a whole key weight, so larger sums are not needed
+    let max_key_weight = candidates.iter().map(|(_, weight)| *weight).max()?;
+    let limit = missing_weight as usize + max_key_weight as usize - 1;
+
+    // counts[i][s] = fewest of the first i candidates summing to exactly s
+    let unreachable = usize::MAX;
+    let mut counts = vec![vec![unreachable; limit + 1]; candidates.len() + 1];
+    counts[0][0] = 0;
+    for (i, (_, weight)) in candidates.iter().enumerate() {
+        let weight = *weight as usize;
+        for sum in 0..=limit {
+            let mut best = counts[i][sum];
+            if sum >= weight && counts[i][sum - weight] != unreachable {
+                best = best.min(counts[i][sum - weight] + 1);
+            }
+            counts[i + 1][sum] = best;
+        }
+    }
+
+    let mut sum = (missing_weight as usize..=limit)
+        .find(|sum| counts[candidates.len()][*sum] != unreachable)?;
+
+    let reached = sum as u64;
+    let mut signers = Vec::new();
+    for i in (0..candidates.len()).rev() {
+        if counts[i + 1][sum] == counts[i][sum] {
+            continue;
+        }
+        let (pubkey, weight) = candidates[i];
+        signers.push(pubkey);
+        sum -= weight as usize;
+    }
+    signers.reverse();
+
+    Some((reached, signers))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::AccountState;
+    use std::collections::BTreeMap;
+
+    fn wallet(weights: &[u32]) -> (Account, Vec<Pubkey>) {
+        let keys: Vec<Pubkey> = weights.iter().map(|_| Pubkey::new_unique()).collect();
+        let owners: BTreeMap<Pubkey, u32> =
+            keys.iter().cloned().zip(weights.iter().cloned()).collect();
+        (
+            Account {
+                state: AccountState::Initialized,
+                owner_epoch: 1,
+                owners,
+                max_owners: 101,
+                ..Account::default()
+            },
+            keys,
+        )
+    }
+
+    #[test]
+    fn plan_should_report_met_threshold() {
+        let (account, keys) = wallet(&[600, 400, 1]);
+        let plan = plan_signers(&account, &keys[..2], &keys[2..]);
+
+        assert!(plan.threshold_met);
+        assert_eq!(plan.signed_weight, 1000);
+        assert_eq!(plan.missing_weight, 0);
+        assert_eq!(plan.min_count_signers, Some(vec![]));
+        assert_eq!(plan.min_weight_signers, Some(vec![]));
+    }
+
+    #[test]
+    fn plan_should_ignore_non_owners_and_duplicates() {
+        let (account, keys) = wallet(&[500, 500]);
+        let stranger = Pubkey::new_unique();
+        let plan = plan_signers(
+            &account,
+            &[keys[0], keys[0], stranger],
+            &[keys[0], stranger],
+        );
+
+        assert_eq!(plan.signed_weight, 500);
+        assert_eq!(plan.signed, vec![keys[0]]);
+        assert!(!plan.threshold_met);
+        assert_eq!(plan.missing_weight, 500);
+        assert_eq!(plan.min_count_signers, None);
+        assert_eq!(plan.min_weight_signers, None);
+    }
+
+    #[test]
+    fn plan_should_pick_min_count_and_min_weight_subsets() {
+        let (account, keys) = wallet(&[100, 700, 350, 300, 250]);
+        let plan = plan_signers(&account, &keys[..1], &keys[1..]);
+
+        assert_eq!(plan.missing_weight, 900);
+        // 700 + 350 is the fewest keys
+        assert_eq!(plan.min_count_signers, Some(vec![keys[1], keys[2]]));
+        // 350 + 300 + 250 hits the missing weight exactly
+        assert_eq!(
+            plan.min_weight_signers,
+            Some(vec![keys[2], keys[3], keys[4]])
+        );
+    }
+
+    #[test]
+    fn plan_should_match_on_chain_weight_rule() {
+        let (account, keys) = wallet(&[999, 1]);
+        let plan = plan_signers(&account, &keys, &[]);
+        let (weight, counted) = account.signed_weight(&keys);
+
+        assert_eq!(plan.signed_weight, weight);
+        assert_eq!(plan.signed, counted);
+        assert!(plan.threshold_met);
+    }
+
+    #[test]
+    fn plan_should_handle_extreme_weights() {
+        let (account, keys) = wallet(&[u32::MAX, u32::MAX, 999, 1]);
+        let plan = plan_signers(&account, &keys[..2], &keys[2..]);
+        assert!(plan.threshold_met);
+        assert_eq!(plan.signed_weight, 2 * u64::from(u32::MAX));
+
+        let plan = plan_signers(&account, &[], &keys);
+        assert_eq!(plan.missing_weight, 1000);
+        assert_eq!(plan.min_count_signers.map(|s| s.len()), Some(1));
+        // 999 + 1 reaches the threshold exactly, a u32::MAX key overshoots
+        let mut expected = vec![keys[2], keys[3]];
+        let mut found = plan.min_weight_signers.unwrap();
+        expected.sort();
+        found.sort();
+        assert_eq!(found, expected);
+    }
+}
@@ -2,22 +2,50 @@
 
 use crate::{
     error::WalletError,
-    instruction::WalletInstruction,
-    state::{Account, AccountState, InstructionBuffer, MIN_WEIGHT},
-    utils::read_instruction,
+    event::WalletEvent,
+    instruction::{guard_instruction, GuardPhase, WalletInstruction},
+    state::{
+        Account, AccountLayout, AccountState, AddressBook, History, HistoryEntry,
+        InstructionBuffer, KeyRotation, Owners, OwnersMut, ScheduledPayment, Session, Stream,
+        CLOSED_ACCOUNT_DISCRIMINATOR, MAX_NESTING_DEPTH, MIN_WEIGHT,
+    },
+    utils::{
+        get_address_book_address, get_history_address, get_module_authority_address,
+        get_scheduled_payment_address, get_session_address, get_stream_address,
+        get_sub_vault_address, get_wallet_vault_address,
+    },
 };
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    clock::Clock,
     entrypoint::ProgramResult,
-    instruction::Instruction,
+    hash::hash,
+    instruction::{AccountMeta, Instruction},
     msg,
-    program::invoke_signed,
+    program::{invoke, invoke_signed},
     program_error::ProgramError,
-    program_pack::IsInitialized,
+    program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::{self, SystemInstruction},
+    system_program,
+    sysvar::Sysvar,
+};
+use spl_token::{
+    instruction::TokenInstruction,
+    state::{Account as TokenAccount, Mint},
 };
 use std::collections::BTreeMap;
 
+/// What the wallet guard is called with around an instruction the wallet vault signs: the
+/// instruction accounts, starting with the wallet, the wallet account and the owners approving
+#[derive(Clone, Copy)]
+struct GuardContext<'a, 'b> {
+    accounts: &'a [AccountInfo<'b>],
+    wallet_account: &'a Account,
+    signers: &'a [Pubkey],
+}
+
 /// Program state handler.
 pub struct Processor {}
 impl Processor {
@@ -31,70 +59,66 @@ impl Processor {
     /// Process an AddOwner instruction and initialize the wallet
     fn process_initialize_wallet(
         wallet_account: &mut Account,
-        owners: BTreeMap<Pubkey, u16>,
+        owners: BTreeMap<Pubkey, u32>,
     ) -> ProgramResult {
         // check key weight
         Self::is_key_weight_enough(&owners)?;
 
         wallet_account.state = AccountState::Initialized;
-
-        for (pubkey, weight) in owners {
-            wallet_account.owners.insert(pubkey, weight);
-        }
+        wallet_account.owners = owners;
+        wallet_account.increase_owner_epoch();
 
         Ok(())
     }
 
-    /// Process an AddOwner instruction
-    fn process_add_owner(
-        wallet_account: &mut Account,
-        owners: BTreeMap<Pubkey, u16>,
-    ) -> ProgramResult {
-        if wallet_account.owners.len() + owners.len() > wallet_account.max_owners {
+    /// Process an AddOwner instruction, inserting the owners into the packed wallet account data
+    /// in place
+    fn process_add_owner(wallet_data: &mut [u8], owners: BTreeMap<Pubkey, u32>) -> ProgramResult {
+        let mut wallet_account = Account::unpack_header_from_slice(wallet_data)?;
+        let mut owner_list = OwnersMut::from_account_data(wallet_data)?;
+        let owner_count = owner_list.as_owners().len();
+        if owner_count + owners.len() > owner_list.as_owners().capacity() {
             msg!("WalletError: too many owners");
-            return Err(WalletError::InvalidInstruction.into());
+            return Err(WalletError::TooManyOwners.into());
         }
 
         for (pubkey, weight) in owners {
-            if weight == 0 {
-                msg!("WalletError: Key weight cannot be 0");
-                return Err(WalletError::InvalidInstruction.into());
-            }
-            if wallet_account.owners.contains_key(&pubkey) {
-                msg!("WalletError: Owner already exists");
-                return Err(WalletError::InvalidInstruction.into());
-            }
-            wallet_account.owners.insert(pubkey, weight);
+            owner_list.insert(pubkey, weight)?;
         }
+        wallet_account.increase_owner_epoch();
 
-        Ok(())
+        wallet_account.pack_header_into_slice(wallet_data)
     }
 
-    /// Process a RemoveOwner instruction
-    fn process_remove_owner(wallet_account: &mut Account, pubkey: Pubkey) -> ProgramResult {
-        // check target exist
-        if !wallet_account.owners.contains_key(&pubkey) {
+    /// Process a RemoveOwner instruction, removing the owner from the packed wallet account data
+    /// in place
+    fn process_remove_owner(wallet_data: &mut [u8], pubkey: Pubkey) -> ProgramResult {
+        let mut wallet_account = Account::unpack_header_from_slice(wallet_data)?;
+        let mut owner_list = OwnersMut::from_account_data(wallet_data)?;
+
+        // remove, the target must exist
+        if owner_list.remove(&pubkey).is_none() {
             msg!("WalletError: Cannot find the target owner to remove");
-            return Err(WalletError::InvalidInstruction.into());
+            return Err(WalletError::OwnerNotFound.into());
         }
 
-        // remove
-        wallet_account.owners.remove(&pubkey);
-
         // check key weight
-        Self::is_key_weight_enough(&wallet_account.owners)?;
+        if owner_list.as_owners().total_weight() < u64::from(MIN_WEIGHT) {
+            return Err(WalletError::InsufficientWeight.into());
+        }
+        wallet_account.increase_owner_epoch();
 
-        Ok(())
+        wallet_account.pack_header_into_slice(wallet_data)
     }
 
     /// Process an Recovery instruction
     fn process_recovery(
         wallet_account: &mut Account,
-        owners: BTreeMap<Pubkey, u16>,
+        owners: BTreeMap<Pubkey, u32>,
     ) -> ProgramResult {
         if owners.len() > wallet_account.max_owners {
             msg!("WalletError: too many owners");
-            return Err(WalletError::InvalidInstruction.into());
+            return Err(WalletError::TooManyOwners.into());
         }
 
         // check key weight
@@ -105,26 +129,267 @@ impl Processor {
         for (pubkey, weight) in owners {
             if weight == 0 {
                 msg!("WalletError: Key weight cannot be 0");
-                return Err(WalletError::InvalidInstruction.into());
+                return Err(WalletError::ZeroKeyWeight.into());
             }
             if wallet_account.owners.contains_key(&pubkey) {
                 msg!("WalletError: Owner already exists");
-                return Err(WalletError::InvalidInstruction.into());
+                return Err(WalletError::OwnerAlreadyExists.into());
             }
             wallet_account.owners.insert(pubkey, weight);
         }
+        wallet_account.increase_owner_epoch();
+
+        Ok(())
+    }
+
+    /// Process an UpdateOwners instruction, the diff is applied as a whole and only the final
+    /// owner set has to hold enough weight
+    fn process_update_owners(
+        wallet_account: &mut Account,
+        adds: BTreeMap<Pubkey, u32>,
+        removes: Vec<Pubkey>,
+        weight_changes: BTreeMap<Pubkey, u32>,
+    ) -> ProgramResult {
+        let mut owners = wallet_account.owners.clone();
+        let mut touched = BTreeMap::new();
+
+        for pubkey in removes {
+            if touched.insert(pubkey, true).is_some() {
+                msg!("WalletError: Owner updated more than once");
+                return Err(WalletError::InvalidInstruction.into());
+            }
+            if owners.remove(&pubkey).is_none() {
+                msg!("WalletError: Cannot find the target owner to remove");
+                return Err(WalletError::OwnerNotFound.into());
+            }
+        }
+
+        for (pubkey, weight) in weight_changes {
+            if touched.insert(pubkey, true).is_some() {
+                msg!("WalletError: Owner updated more than once");
+                return Err(WalletError::InvalidInstruction.into());
+            }
+            if weight == 0 {
+                msg!("WalletError: Key weight cannot be 0");
+                return Err(WalletError::ZeroKeyWeight.into());
+            }
+            match owners.get_mut(&pubkey) {
+                Some(current_weight) => *current_weight = weight,
+                None => {
+                    msg!("WalletError: Cannot find the target owner to update");
+                    return Err(WalletError::OwnerNotFound.into());
+                }
+            }
+        }
+
+        for (pubkey, weight) in adds {
+            if touched.insert(pubkey, true).is_some() {
+                msg!("WalletError: Owner updated more than once");
+                return Err(WalletError::InvalidInstruction.into());
+            }
+            if weight == 0 {
+                msg!("WalletError: Key weight cannot be 0");
+                return Err(WalletError::ZeroKeyWeight.into());
+            }
+            if owners.insert(pubkey, weight).is_some() {
+                msg!("WalletError: Owner already exists");
+                return Err(WalletError::OwnerAlreadyExists.into());
+            }
+        }
+
+        if owners.len() > wallet_account.max_owners {
+            msg!("WalletError: too many owners");
+            return Err(WalletError::TooManyOwners.into());
+        }
+
+        // check key weight
+        Self::is_key_weight_enough(&owners)?;
+
+        wallet_account.owners = owners;
+        wallet_account.increase_owner_epoch();
+
+        Ok(())
+    }
+
+    /// Process a RotateOwnerKey instruction, returns whether the key was replaced or the rotation
+    /// was only scheduled because of the wallet's rotation delay
+    fn process_rotate_owner_key(
+        wallet_account: &mut Account,
+        old_key: Pubkey,
+        new_key: Pubkey,
+        now: i64,
+    ) -> Result<bool, ProgramError> {
+        let weight = match wallet_account.owners.get(&old_key) {
+            Some(weight) => *weight,
+            None => {
+                msg!("WalletError: Cannot find the owner to rotate");
+                return Err(WalletError::InvalidOwner.into());
+            }
+        };
+        if wallet_account.owners.contains_key(&new_key) {
+            msg!("WalletError: New key is already an owner");
+            return Err(WalletError::InvalidInstruction.into());
+        }
+
+        if wallet_account.rotation_delay > 0 {
+            let delay = wallet_account.rotation_delay.min(i64::MAX as u64) as i64;
+            match wallet_account.pending_rotation {
+                Some(rotation) if rotation.old_key == old_key && rotation.new_key == new_key => {
+                    if now < rotation.ready_at {
+                        msg!(&format!("key rotation is ready at {}", rotation.ready_at));
+                        return Err(WalletError::KeyRotationPending.into());
+                    }
+                }
+                // another owner's rotation blocks new requests until it has been left uncompleted
+                // for a whole delay after it became ready
+                Some(rotation)
+                    if rotation.old_key != old_key
+                        && now < rotation.ready_at.saturating_add(delay) =>
+                {
+                    msg!(&format!("key rotation of {} is pending", rotation.old_key));
+                    return Err(WalletError::KeyRotationConflict.into());
+                }
+                // nothing pending, the same owner picked another new key, or the pending rotation
+                // expired
+                _ => {
+                    wallet_account.pending_rotation = Some(KeyRotation {
+                        old_key,
+                        new_key,
+                        ready_at: now.saturating_add(delay),
+                    });
+                    return Ok(false);
+                }
+            }
+        }
+
+        wallet_account.owners.remove(&old_key);
+        wallet_account.owners.insert(new_key, weight);
+        wallet_account.increase_owner_epoch();
+
+        Ok(true)
+    }
+
+    /// Process a CancelKeyRotation instruction
+    fn process_cancel_key_rotation(
+        wallet_account: &mut Account,
+    ) -> Result<KeyRotation, ProgramError> {
+        match wallet_account.pending_rotation.take() {
+            Some(rotation) => Ok(rotation),
+            None => {
+                msg!("WalletError: No key rotation is pending");
+                Err(WalletError::InvalidState.into())
+            }
+        }
+    }
+
+    /// Process a Freeze instruction
+    fn process_freeze(wallet_account: &mut Account) -> ProgramResult {
+        if wallet_account.state == AccountState::Frozen {
+            msg!("WalletError: Wallet is already frozen");
+            return Err(WalletError::InvalidState.into());
+        }
+        wallet_account.state = AccountState::Frozen;
+        Ok(())
+    }
 
+    /// Process an Unfreeze instruction
+    fn process_unfreeze(wallet_account: &mut Account) -> ProgramResult {
+        if wallet_account.state != AccountState::Frozen {
+            msg!("WalletError: Wallet is not frozen");
+            return Err(WalletError::InvalidState.into());
+        }
+        wallet_account.state = AccountState::Initialized;
         Ok(())
     }
 
     /// Process an Revoke insturction
     fn process_revoke(wallet_account: &mut Account) -> ProgramResult {
         wallet_account.owners.clear();
+        wallet_account.increase_owner_epoch();
         Ok(())
     }
 
-    /// Process an Invoke instruction and call another program
-    fn process_invoke(accounts: &[AccountInfo], instruction: Instruction) -> ProgramResult {
+    /// Check an instruction the wallet is about to invoke passes through at most
+    /// `MAX_NESTING_DEPTH` other wallets, following Invoke and ExecuteFromModule into the wallet
+    /// program
+    fn check_nesting_depth(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction: &Instruction,
+    ) -> ProgramResult {
+        let mut depth = 0;
+        let mut instruction = instruction.clone();
+        while instruction.program_id == *program_id {
+            depth += 1;
+            if depth > MAX_NESTING_DEPTH {
+                msg!(&format!(
+                    "instruction passes through more than {} nested wallets",
+                    MAX_NESTING_DEPTH
+                ));
+                return Err(WalletError::NestingTooDeep.into());
+            }
+
+            let nested_accounts = instruction
+                .accounts
+                .iter()
+                .map(|account_meta| {
+                    accounts
+                        .iter()
+                        .find(|account| *account.key == account_meta.pubkey)
+                        .cloned()
+                        .ok_or(ProgramError::NotEnoughAccountKeys)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut nested = WalletInstruction::unpack(&instruction.data, &nested_accounts)?;
+            if let WalletInstruction::WithReimbursement {
+                instruction: relayed,
+                ..
+            } = nested
+            {
+                nested = *relayed;
+            }
+            instruction = match nested {
+                WalletInstruction::Invoke { instruction }
+                | WalletInstruction::ExecuteFromModule { instruction }
+                | WalletInstruction::InvokeFromVault { instruction, .. } => instruction,
+                _ => break,
+            };
+        }
+        Ok(())
+    }
+
+    /// Address and signer seeds of the wallet vault with the given index, 0 being the vault
+    /// derived from the wallet address alone
+    fn vault_address_and_seeds(
+        program_id: &Pubkey,
+        wallet: &Pubkey,
+        vault_index: u16,
+    ) -> Result<(Pubkey, Vec<Vec<u8>>), ProgramError> {
+        if vault_index == 0 {
+            let vault =
+                get_wallet_vault_address(wallet, program_id).or(Err(ProgramError::InvalidSeeds))?;
+            return Ok((vault, vec![wallet.to_bytes().to_vec()]));
+        }
+        let (vault, bump) = get_sub_vault_address(wallet, vault_index, program_id);
+        Ok((
+            vault,
+            vec![
+                Account::SUB_VAULT_SEED.to_vec(),
+                wallet.to_bytes().to_vec(),
+                vault_index.to_le_bytes().to_vec(),
+                vec![bump],
+            ],
+        ))
+    }
+
+    /// Process an Invoke instruction and call another program, signed by the vault with the
+    /// given index
+    fn process_invoke(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        vault_index: u16,
+        instruction: Instruction,
+    ) -> ProgramResult {
         let accounts_iter = &mut accounts.iter();
         let wallet_account = next_account_info(accounts_iter)?;
         let auth_account = next_account_info(accounts_iter)?;
@@ -152,504 +417,6358 @@ impl Processor {
             }
         }
 
-        invoke_signed(
-            &instruction,
-            pass_accounts.as_slice(),
-            &[&[&wallet_account.key.to_bytes()]],
-        )?;
+        let (_, seeds) =
+            Self::vault_address_and_seeds(program_id, wallet_account.key, vault_index)?;
+        let seeds = seeds.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        invoke_signed(&instruction, pass_accounts.as_slice(), &[&seeds])?;
 
         Ok(())
     }
 
-    fn is_key_weight_enough(owners: &BTreeMap<Pubkey, u16>) -> ProgramResult {
-        let mut sum_of_key_weight = 0;
-        for (_, weight) in owners {
-            sum_of_key_weight += weight;
-        }
-        if sum_of_key_weight < MIN_WEIGHT {
-            return Err(WalletError::InsufficientWeight.into());
-        }
-        Ok(())
-    }
+    /// Process a TransferLamports instruction, moving SOL out of the wallet vault
+    fn process_transfer_lamports(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+        rent: &Rent,
+        guard: GuardContext,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let vault_account_info = next_account_info(accounts_iter)?;
+        let destination_account_info = next_account_info(accounts_iter)?;
+        let system_program_info = next_account_info(accounts_iter)?;
 
-    /// Check if signatures have enought weight
-    fn check_signatures(accounts: &[AccountInfo], wallet_account: &Account) -> ProgramResult {
-        let mut total_key_weight = 0;
-        let mut counted = BTreeMap::new();
+        Self::check_wallet_vault(program_id, wallet_account_info, vault_account_info)?;
 
-        for account in accounts.iter() {
-            if account.is_signer
-                && wallet_account.owners.contains_key(account.key)
-                && !counted.contains_key(account.key)
-            {
-                counted.insert(account.key, true);
-                total_key_weight += wallet_account.owners[account.key];
-            }
-        }
+        let vault_lamports = vault_account_info.lamports();
+        let remaining_lamports = vault_lamports.checked_sub(amount).ok_or_else(|| {
+            msg!(&format!(
+                "vault has {} lamports, cannot transfer {}",
+                vault_lamports, amount
+            ));
+            ProgramError::from(WalletError::InsufficientFunds)
+        })?;
 
-        if total_key_weight < MIN_WEIGHT {
-            msg!("WalletError: Signature weight too low");
-            return Err(WalletError::InsufficientWeight.into());
+        // the vault may be drained, but must not be left paying rent
+        if remaining_lamports != 0
+            && !rent.is_exempt(remaining_lamports, vault_account_info.data_len())
+        {
+            msg!(&format!(
+                "vault would keep {} lamports, below the rent-exempt minimum {}",
+                remaining_lamports,
+                rent.minimum_balance(vault_account_info.data_len())
+            ));
+            return Err(WalletError::NotRentExempt.into());
         }
 
-        Ok(())
+        Self::invoke_vault_signed(
+            guard,
+            &system_instruction::transfer(
+                vault_account_info.key,
+                destination_account_info.key,
+                amount,
+            ),
+            &[
+                vault_account_info.clone(),
+                destination_account_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[&wallet_account_info.key.to_bytes()],
+        )
     }
 
-    /// Load wallet account data
-    fn load_wallet_account(
+    /// Process a TokenTransfer instruction
+    fn process_token_transfer(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-    ) -> Result<Account, ProgramError> {
-        // Iterating accounts is safer then indexing
+        amount: u64,
+        decimals: u8,
+        guard: GuardContext,
+    ) -> ProgramResult {
         let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let vault_account_info = next_account_info(accounts_iter)?;
+        let source_account_info = next_account_info(accounts_iter)?;
+        let mint_account_info = next_account_info(accounts_iter)?;
+        let destination_account_info = next_account_info(accounts_iter)?;
+        let token_program_info = next_account_info(accounts_iter)?;
 
-        // The account containing wallet information
-        let walllet_account = next_account_info(accounts_iter)?;
-
-        // The account must be owned by the program in order to modify its data
-        if walllet_account.owner != program_id {
-            msg!("Wallet account does not have the correct program id");
-            return Err(ProgramError::IncorrectProgramId);
-        }
+        Self::check_wallet_vault(program_id, wallet_account_info, vault_account_info)?;
+        Self::check_token_program(token_program_info)?;
 
-        Account::unpack_from_slice(&walllet_account.data.borrow())
+        Self::invoke_vault_signed(
+            guard,
+            &spl_token::instruction::transfer_checked(
+                token_program_info.key,
+                source_account_info.key,
+                mint_account_info.key,
+                destination_account_info.key,
+                vault_account_info.key,
+                &[],
+                amount,
+                decimals,
+            )?,
+            &[
+                source_account_info.clone(),
+                mint_account_info.clone(),
+                destination_account_info.clone(),
+                vault_account_info.clone(),
+                token_program_info.clone(),
+            ],
+            &[&wallet_account_info.key.to_bytes()],
+        )
     }
 
-    /// Store wallet account data
-    fn store_wallet_account(
+    /// Process a CreateAssociatedTokenAccount instruction, returns whether an account was created
+    fn process_create_associated_token_account(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        wallet_account: Account,
-    ) -> Result<(), ProgramError> {
-        // Iterating accounts is safer then indexing
+        guard: GuardContext,
+    ) -> Result<bool, ProgramError> {
         let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let vault_account_info = next_account_info(accounts_iter)?;
+        let associated_account_info = next_account_info(accounts_iter)?;
+        let recipient_account_info = next_account_info(accounts_iter)?;
+        let mint_account_info = next_account_info(accounts_iter)?;
+        let system_program_info = next_account_info(accounts_iter)?;
+        let token_program_info = next_account_info(accounts_iter)?;
+        let rent_sysvar_info = next_account_info(accounts_iter)?;
+        let associated_token_program_info = next_account_info(accounts_iter)?;
 
-        // Get the account to say hello to
-        let account = next_account_info(accounts_iter)?;
+        Self::check_wallet_vault(program_id, wallet_account_info, vault_account_info)?;
+        Self::check_token_program(token_program_info)?;
 
-        // The account must be owned by the program in order to modify its data
-        if account.owner != program_id {
-            msg!("Wallet account does not have the correct program id");
-            return Err(ProgramError::IncorrectProgramId);
+        let associated_address = spl_associated_token_account::get_associated_token_address(
+            recipient_account_info.key,
+            mint_account_info.key,
+        );
+        if *associated_account_info.key != associated_address {
+            msg!(&format!(
+                "associated token account mismatch, want: {}, got: {}",
+                associated_address, associated_account_info.key
+            ));
+            return Err(ProgramError::InvalidSeeds);
         }
 
-        // The account must be declaired writable
-        if !account.is_writable {
-            msg!("Wallet account was not declaired writable");
-            return Err(ProgramError::InvalidAccountData);
+        // the recipient may already have one
+        if associated_account_info.owner == token_program_info.key {
+            msg!("Associated token account already exists");
+            return Ok(false);
         }
 
-        Account::pack_into_slice(&wallet_account, &mut account.data.borrow_mut())?;
+        Self::invoke_vault_signed(
+            guard,
+            &spl_associated_token_account::create_associated_token_account(
+                vault_account_info.key,
+                recipient_account_info.key,
+                mint_account_info.key,
+            ),
+            &[
+                vault_account_info.clone(),
+                associated_account_info.clone(),
+                recipient_account_info.clone(),
+                mint_account_info.clone(),
+                system_program_info.clone(),
+                token_program_info.clone(),
+                rent_sysvar_info.clone(),
+                associated_token_program_info.clone(),
+            ],
+            &[&wallet_account_info.key.to_bytes()],
+        )?;
 
-        Ok(())
+        Ok(true)
     }
 
-    fn process_init_instruction_buffer(
-        _program_id: &Pubkey,
+    /// Process an ApproveTokenDelegate instruction
+    fn process_approve_token_delegate(
+        program_id: &Pubkey,
         accounts: &[AccountInfo],
+        amount: u64,
+        decimals: u8,
+        guard: GuardContext,
     ) -> ProgramResult {
         let accounts_iter = &mut accounts.iter();
-        let instruction_account_info = next_account_info(accounts_iter)?;
-        let owner_account_info = next_account_info(accounts_iter)?;
-        let mut sequence_instructions =
-            InstructionBuffer::unpack(&instruction_account_info.data.borrow())?;
-        if sequence_instructions.owner != Pubkey::default() {
-            return Err(ProgramError::AccountAlreadyInitialized);
-        }
-
-        sequence_instructions.owner = *owner_account_info.key;
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let vault_account_info = next_account_info(accounts_iter)?;
+        let source_account_info = next_account_info(accounts_iter)?;
+        let mint_account_info = next_account_info(accounts_iter)?;
+        let delegate_account_info = next_account_info(accounts_iter)?;
+        let token_program_info = next_account_info(accounts_iter)?;
 
-        InstructionBuffer::pack(
-            sequence_instructions,
-            &mut instruction_account_info.data.borrow_mut(),
-        )?;
+        Self::check_wallet_vault(program_id, wallet_account_info, vault_account_info)?;
+        Self::check_token_program(token_program_info)?;
 
-        Ok(())
+        Self::invoke_vault_signed(
+            guard,
+            &spl_token::instruction::approve_checked(
+                token_program_info.key,
+                source_account_info.key,
+                mint_account_info.key,
+                delegate_account_info.key,
+                vault_account_info.key,
+                &[],
+                amount,
+                decimals,
+            )?,
+            &[
+                source_account_info.clone(),
+                mint_account_info.clone(),
+                delegate_account_info.clone(),
+                vault_account_info.clone(),
+                token_program_info.clone(),
+            ],
+            &[&wallet_account_info.key.to_bytes()],
+        )
     }
 
-    fn process_append_partial_instruction(
-        _program_id: &Pubkey,
+    /// Process a RevokeTokenDelegate instruction
+    fn process_revoke_token_delegate(
+        program_id: &Pubkey,
         accounts: &[AccountInfo],
-        offset: u16,
-        data: Vec<u8>,
+        guard: GuardContext,
     ) -> ProgramResult {
         let accounts_iter = &mut accounts.iter();
-        let _wallet_account_info = next_account_info(accounts_iter)?;
-        let instruction_buffer_account_info = next_account_info(accounts_iter)?;
-        let owner_account_info = next_account_info(accounts_iter)?;
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let vault_account_info = next_account_info(accounts_iter)?;
+        let source_account_info = next_account_info(accounts_iter)?;
+        let token_program_info = next_account_info(accounts_iter)?;
 
-        if !owner_account_info.is_signer {
-            msg!(&format!("{} should be a signer", owner_account_info.key));
-            return Err(ProgramError::MissingRequiredSignature);
-        }
+        Self::check_wallet_vault(program_id, wallet_account_info, vault_account_info)?;
+        Self::check_token_program(token_program_info)?;
 
-        let mut instruction_buffer =
-            InstructionBuffer::unpack(&instruction_buffer_account_info.data.borrow())?;
-        if instruction_buffer.owner != *owner_account_info.key {
+        Self::invoke_vault_signed(
+            guard,
+            &spl_token::instruction::revoke(
+                token_program_info.key,
+                source_account_info.key,
+                vault_account_info.key,
+                &[],
+            )?,
+            &[
+                source_account_info.clone(),
+                vault_account_info.clone(),
+                token_program_info.clone(),
+            ],
+            &[&wallet_account_info.key.to_bytes()],
+        )
+    }
+
+    /// Create a program owned PDA paid by the payer, `signer_seeds` must derive `address`
+    #[allow(clippy::too_many_arguments)]
+    fn create_pda_account<'a>(
+        program_id: &Pubkey,
+        payer_account_info: &AccountInfo<'a>,
+        pda_account_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        space: usize,
+        rent: &Rent,
+        address: &Pubkey,
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        if pda_account_info.key != address {
             msg!(&format!(
-                "buffer account owner mismatch, want: {}, got: {}",
-                instruction_buffer.owner, *owner_account_info.key
+                "account address mismatch, want: {}, got: {}",
+                address, pda_account_info.key
             ));
-            return Err(ProgramError::InvalidAccountData);
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if !payer_account_info.is_signer {
+            msg!(&format!("{} should be a signer", payer_account_info.key));
+            return Err(ProgramError::MissingRequiredSignature);
         }
 
-        instruction_buffer.data[offset as usize..offset as usize + data.len()]
-            .copy_from_slice(&data[..]);
-
-        InstructionBuffer::pack(
-            instruction_buffer,
-            &mut instruction_buffer_account_info.data.borrow_mut(),
+        // create_account fails on an address someone already funded, so top the account up
+        // to rent exemption and allocate and assign it instead
+        let required_lamports = rent
+            .minimum_balance(space)
+            .saturating_sub(pda_account_info.lamports());
+        if required_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(
+                    payer_account_info.key,
+                    pda_account_info.key,
+                    required_lamports,
+                ),
+                &[
+                    payer_account_info.clone(),
+                    pda_account_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(pda_account_info.key, space as u64),
+            &[pda_account_info.clone(), system_program_info.clone()],
+            &[signer_seeds],
         )?;
-
-        Ok(())
+        invoke_signed(
+            &system_instruction::assign(pda_account_info.key, program_id),
+            &[pda_account_info.clone(), system_program_info.clone()],
+            &[signer_seeds],
+        )
     }
 
-    fn process_run_insturction_buffer(
-        _program_id: &Pubkey,
+    /// Process a CreateSession instruction, creating the session record PDA paid by the payer
+    fn process_create_session(
+        program_id: &Pubkey,
         accounts: &[AccountInfo],
-        expected_instruction_count: u16,
+        session: &Session,
+        now: i64,
+        rent: &Rent,
     ) -> ProgramResult {
         let accounts_iter = &mut accounts.iter();
-        let wallet_account = next_account_info(accounts_iter)?;
-        let instruction_buffer_account_info = next_account_info(accounts_iter)?;
-        let owner_account_info = next_account_info(accounts_iter)?;
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let session_account_info = next_account_info(accounts_iter)?;
+        let payer_account_info = next_account_info(accounts_iter)?;
+        let system_program_info = next_account_info(accounts_iter)?;
 
-        if !owner_account_info.is_signer {
-            msg!(&format!("{} should be a signer", owner_account_info.key));
-            return Err(ProgramError::MissingRequiredSignature);
+        if session.expires_at <= now {
+            msg!("WalletError: Session expires in the past");
+            return Err(WalletError::InvalidInstruction.into());
         }
-        let instruction_buffer =
-            InstructionBuffer::unpack(&instruction_buffer_account_info.data.borrow())?;
-        if instruction_buffer.owner != *owner_account_info.key {
+        if session.allowed_programs.contains(program_id) {
+            msg!("WalletError: Session cannot invoke the wallet program");
+            return Err(WalletError::InvalidInstruction.into());
+        }
+
+        let (session_address, bump_seed) =
+            get_session_address(wallet_account_info.key, &session.session_key, program_id);
+        Self::create_pda_account(
+            program_id,
+            payer_account_info,
+            session_account_info,
+            system_program_info,
+            session.get_packed_len(),
+            rent,
+            &session_address,
+            &[
+                Session::SEED,
+                &wallet_account_info.key.to_bytes(),
+                &session.session_key.to_bytes(),
+                &[bump_seed],
+            ],
+        )?;
+
+        session.pack(&mut session_account_info.data.borrow_mut())
+    }
+
+    /// Load a session record of the wallet
+    fn load_session(
+        program_id: &Pubkey,
+        wallet_account_info: &AccountInfo,
+        session_account_info: &AccountInfo,
+    ) -> Result<Session, ProgramError> {
+        if session_account_info.owner != program_id {
+            msg!("Session account does not have the correct program id");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let session = Session::unpack(&session_account_info.data.borrow())?;
+        if session.wallet != *wallet_account_info.key {
             msg!(&format!(
-                "buffer account owner mismatch, want: {}, got: {}",
-                instruction_buffer.owner, *owner_account_info.key
+                "session wallet mismatch, want: {}, got: {}",
+                wallet_account_info.key, session.wallet
             ));
             return Err(ProgramError::InvalidAccountData);
         }
+        Ok(session)
+    }
 
-        // prepare account info
-        let mut pass_accounts = Vec::new();
-        for account in accounts_iter {
-            let mut pass_account = account.clone();
-            if pass_account.key == owner_account_info.key {
-                pass_account.is_signer = false;
+    /// Process a RevokeSession instruction, returns the session key and the keys that signed
+    fn process_revoke_session(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        wallet_account: &Account,
+    ) -> Result<(Pubkey, Vec<Pubkey>), ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let session_account_info = next_account_info(accounts_iter)?;
+        let destination_account_info = next_account_info(accounts_iter)?;
+
+        let session = Self::load_session(program_id, wallet_account_info, session_account_info)?;
+
+        // a single owner is enough to cut a session short
+        let signer_keys = accounts
+            .iter()
+            .filter(|account| account.is_signer)
+            .map(|account| account.key);
+        let (_, mut signers) = wallet_account.signed_weight(signer_keys.clone());
+        if signers.is_empty() {
+            if !signer_keys.clone().any(|key| *key == session.session_key) {
+                msg!("session can only be revoked by an owner or its session key");
+                return Err(ProgramError::MissingRequiredSignature);
             }
-            pass_accounts.push(pass_account);
+            signers.push(session.session_key);
         }
 
-        // execute instructions
-        let mut current = 0;
-        let mut instruction_count = 0;
-        while current < instruction_buffer.data.len() {
-            let instruction = read_instruction(&mut current, &instruction_buffer.data[..])?;
-            if instruction.program_id == Pubkey::default()
-                && instruction.accounts.len() == 0
-                && instruction.data.len() == 0
+        Self::close_account(session_account_info, destination_account_info)?;
+
+        Ok((session.session_key, signers))
+    }
+
+    /// Token accounts owned by the wallet vault among the given accounts
+    fn vault_token_accounts(
+        accounts: &[AccountInfo],
+        vault: &Pubkey,
+    ) -> BTreeMap<Pubkey, TokenAccount> {
+        accounts
+            .iter()
+            .filter(|account| *account.owner == spl_token::id())
+            .filter_map(|account| {
+                TokenAccount::unpack(&account.data.borrow())
+                    .ok()
+                    .filter(|token_account| token_account.owner == *vault)
+                    .map(|token_account| (*account.key, token_account))
+            })
+            .collect()
+    }
+
+    /// Owner and data length of each of the accounts
+    fn account_shapes(accounts: &[AccountInfo]) -> Vec<(Pubkey, usize)> {
+        accounts
+            .iter()
+            .map(|account| (*account.owner, account.data_len()))
+            .collect()
+    }
+
+    /// Process a SessionInvoke instruction, returns the session with the lamports and tokens
+    /// the instruction moved out of the wallet vault
+    fn process_session_invoke(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction: Instruction,
+        now: i64,
+        wallet_account: &Account,
+    ) -> Result<(Session, u64, u64), ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let session_account_info = next_account_info(accounts_iter)?;
+        let session_key_account_info = next_account_info(accounts_iter)?;
+        let vault_account_info = next_account_info(accounts_iter)?;
+
+        let mut session =
+            Self::load_session(program_id, wallet_account_info, session_account_info)?;
+        if *session_key_account_info.key != session.session_key
+            || !session_key_account_info.is_signer
+        {
+            msg!(&format!("{} should be a signer", session.session_key));
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if now >= session.expires_at {
+            msg!("WalletError: Session has expired");
+            return Err(WalletError::SessionExpired.into());
+        }
+        Self::check_wallet_vault(program_id, wallet_account_info, vault_account_info)?;
+        if instruction.program_id == *program_id {
+            msg!("WalletError: Session cannot invoke the wallet program");
+            return Err(WalletError::SessionScopeViolation.into());
+        }
+        session.check_scope(&instruction)?;
+        // the vault may pay out lamports, but not be reassigned, resized or spent into new accounts
+        if instruction.program_id == system_program::id()
+            && instruction
+                .accounts
+                .iter()
+                .any(|meta| meta.pubkey == *vault_account_info.key)
+        {
+            match bincode::deserialize::<SystemInstruction>(&instruction.data) {
+                Ok(SystemInstruction::Transfer { .. })
+                | Ok(SystemInstruction::TransferWithSeed { .. }) => {}
+                _ => {
+                    msg!("session may only transfer lamports out of the vault");
+                    return Err(WalletError::SessionScopeViolation.into());
+                }
+            }
+        }
+
+        let lamports_before = vault_account_info.lamports();
+        let token_accounts_before = Self::vault_token_accounts(accounts, vault_account_info.key);
+        let shapes_before = Self::account_shapes(accounts);
+
+        Self::invoke_vault_signed(
+            GuardContext {
+                accounts,
+                wallet_account,
+                signers: &[session.session_key],
+            },
+            &instruction,
+            accounts,
+            &[&wallet_account_info.key.to_bytes()],
+        )?;
+
+        for (account, before) in accounts.iter().zip(shapes_before) {
+            if (*account.owner, account.data_len()) != before {
+                msg!(&format!(
+                    "session may not change the owner or size of {}",
+                    account.key
+                ));
+                return Err(WalletError::SessionScopeViolation.into());
+            }
+        }
+        let lamports = lamports_before.saturating_sub(vault_account_info.lamports());
+        let mut tokens = 0u64;
+        for account in accounts {
+            let before = match token_accounts_before.get(account.key) {
+                Some(before) => before,
+                None => continue,
+            };
+            // a closed token account has nothing left to spend
+            let after = TokenAccount::unpack(&account.data.borrow()).unwrap_or_default();
+            if after.is_initialized()
+                && (after.owner != before.owner
+                    || after.delegate != before.delegate
+                    || after.close_authority != before.close_authority)
             {
-                break;
+                msg!(&format!(
+                    "session may not change the authorities of {}",
+                    account.key
+                ));
+                return Err(WalletError::SessionScopeViolation.into());
             }
-            invoke_signed(
-                &instruction,
-                &pass_accounts,
-                &[&[&wallet_account.key.to_bytes()]],
-            )?;
-            instruction_count += 1;
+            let spent = before.amount.saturating_sub(after.amount);
+            if spent > 0 && session.token_mint != Some(before.mint) {
+                msg!(&format!("session may not spend {}", before.mint));
+                return Err(WalletError::SessionScopeViolation.into());
+            }
+            tokens = tokens.saturating_add(spent);
         }
 
-        // check instruction count
-        if instruction_count != expected_instruction_count {
-            msg!(&format!(
-                "instruction count mismatch, want: {}, got: {}",
-                expected_instruction_count, instruction_count
-            ));
-            return Err(ProgramError::InvalidAccountData);
+        session.record_spending(lamports, tokens)?;
+        session.pack(&mut session_account_info.data.borrow_mut())?;
+
+        Ok((session, lamports, tokens))
+    }
+
+    /// Process an InitAddressBook instruction, creating the address book PDA paid by the payer
+    fn process_init_address_book(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        wallet_account: &mut Account,
+        capacity: u16,
+        activation_delay: u64,
+        rent: &Rent,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let address_book_account_info = next_account_info(accounts_iter)?;
+        let payer_account_info = next_account_info(accounts_iter)?;
+        let system_program_info = next_account_info(accounts_iter)?;
+
+        if wallet_account.address_book {
+            msg!("WalletError: Address book already exists");
+            return Err(WalletError::InvalidState.into());
         }
 
-        // close buffer account
-        let dest_starting_lamports = owner_account_info.lamports();
-        **owner_account_info.lamports.borrow_mut() = dest_starting_lamports
-            .checked_add(instruction_buffer_account_info.lamports())
-            .ok_or(ProgramError::InvalidAccountData)?;
-        **instruction_buffer_account_info.lamports.borrow_mut() = 0;
+        let (address_book_address, bump_seed) =
+            get_address_book_address(wallet_account_info.key, program_id);
+        Self::create_pda_account(
+            program_id,
+            payer_account_info,
+            address_book_account_info,
+            system_program_info,
+            AddressBook::get_packed_len(capacity),
+            rent,
+            &address_book_address,
+            &[
+                AddressBook::SEED,
+                &wallet_account_info.key.to_bytes(),
+                &[bump_seed],
+            ],
+        )?;
+
+        AddressBook {
+            wallet: *wallet_account_info.key,
+            activation_delay,
+            capacity,
+            entries: BTreeMap::new(),
+        }
+        .pack(&mut address_book_account_info.data.borrow_mut())?;
+        wallet_account.address_book = true;
 
         Ok(())
     }
 
-    fn process_close_instruction_buffer(
-        _program_id: &Pubkey,
+    /// Process an InitHistory instruction, creating the wallet history PDA
+    fn process_init_history(
+        program_id: &Pubkey,
         accounts: &[AccountInfo],
+        wallet_account: &mut Account,
+        capacity: u16,
+        rent: &Rent,
     ) -> ProgramResult {
         let accounts_iter = &mut accounts.iter();
-        let instruction_account_info = next_account_info(accounts_iter)?;
-        let owner_account_info = next_account_info(accounts_iter)?;
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let history_account_info = next_account_info(accounts_iter)?;
+        let payer_account_info = next_account_info(accounts_iter)?;
+        let system_program_info = next_account_info(accounts_iter)?;
 
-        if !owner_account_info.is_signer {
-            msg!(&format!("{} should be a signer", owner_account_info.key));
-            return Err(ProgramError::MissingRequiredSignature);
+        if wallet_account.history {
+            msg!("WalletError: History already exists");
+            return Err(WalletError::InvalidState.into());
+        }
+        if capacity == 0 {
+            msg!("WalletError: History capacity cannot be 0");
+            return Err(WalletError::InvalidInstruction.into());
         }
 
-        let sequence_instructions =
-            InstructionBuffer::unpack(&instruction_account_info.data.borrow())?;
+        let (history_address, bump_seed) = get_history_address(wallet_account_info.key, program_id);
+        Self::create_pda_account(
+            program_id,
+            payer_account_info,
+            history_account_info,
+            system_program_info,
+            History::get_packed_len(capacity),
+            rent,
+            &history_address,
+            &[
+                History::SEED,
+                &wallet_account_info.key.to_bytes(),
+                &[bump_seed],
+            ],
+        )?;
 
-        if sequence_instructions.owner != *owner_account_info.key {
+        History {
+            wallet: *wallet_account_info.key,
+            capacity,
+            total: 0,
+            entries: vec![],
+        }
+        .pack(&mut history_account_info.data.borrow_mut())?;
+        wallet_account.history = true;
+
+        Ok(())
+    }
+
+    /// The history entry of an instruction about to run, None when the wallet keeps no history
+    /// or the instruction is not authorized by the owners. Signatures are weighed against the
+    /// owners before the instruction runs, the slot is filled in when recording.
+    fn history_entry(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        input: &[u8],
+        instruction: &WalletInstruction,
+    ) -> Result<Option<HistoryEntry>, ProgramError> {
+        if matches!(
+            instruction,
+            WalletInstruction::RotateOwnerKey { .. }
+                | WalletInstruction::RevokeSession
+                | WalletInstruction::SessionInvoke { .. }
+                | WalletInstruction::Freeze
+                | WalletInstruction::ExecuteScheduledPayment
+                | WalletInstruction::WithdrawStream
+                | WalletInstruction::ExecuteFromModule { .. }
+                | WalletInstruction::InitInstructionBuffer { .. }
+                | WalletInstruction::CloseInstructionBuffer
+        ) {
+            return Ok(None);
+        }
+        // the instruction reports a missing or invalid wallet account itself
+        match Self::load_wallet_header(program_id, accounts) {
+            Ok(wallet_account) if wallet_account.history => {}
+            _ => return Ok(None),
+        }
+
+        let wallet_data = accounts[0].data.borrow();
+        let (weight, signers) = Owners::from_account_data(&wallet_data)?.signed_weight(
+            accounts
+                .iter()
+                .filter(|account| account.is_signer)
+                .map(|account| account.key),
+        );
+        let target = match instruction {
+            WalletInstruction::WithReimbursement { instruction, .. } => instruction,
+            instruction => instruction,
+        };
+        let target_program_id = match target {
+            WalletInstruction::Invoke { instruction }
+            | WalletInstruction::InvokeFromVault { instruction, .. } => instruction.program_id,
+            _ => *program_id,
+        };
+
+        Ok(Some(HistoryEntry {
+            slot: 0,
+            kind: input[0],
+            program_id: target_program_id,
+            data_hash: hash(input).to_bytes(),
+            weight,
+            signers: signers.len() as u16,
+        }))
+    }
+
+    /// The wallet history account, passed anywhere after the wallet account
+    fn history_account_info<'a, 'b>(
+        program_id: &Pubkey,
+        accounts: &'b [AccountInfo<'a>],
+    ) -> Result<&'b AccountInfo<'a>, ProgramError> {
+        let wallet_account_info = next_account_info(&mut accounts.iter())?;
+        let (history_address, _) = get_history_address(wallet_account_info.key, program_id);
+        let history_account_info = accounts
+            .iter()
+            .find(|account| *account.key == history_address)
+            .ok_or_else(|| {
+                msg!("the wallet history account is missing");
+                ProgramError::NotEnoughAccountKeys
+            })?;
+        if history_account_info.owner != program_id {
+            msg!("History account does not have the correct program id");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Ok(history_account_info)
+    }
+
+    /// Record an executed instruction in the wallet history account
+    fn append_history(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        entry: &HistoryEntry,
+    ) -> ProgramResult {
+        let wallet_account_info = next_account_info(&mut accounts.iter())?;
+        let history_account_info = Self::history_account_info(program_id, accounts)?;
+        History::append(
+            &mut history_account_info.data.borrow_mut(),
+            wallet_account_info.key,
+            entry,
+        )
+    }
+
+    /// Load the wallet address book from the instruction accounts
+    fn load_address_book(
+        program_id: &Pubkey,
+        wallet_account_info: &AccountInfo,
+        address_book_account_info: &AccountInfo,
+    ) -> Result<AddressBook, ProgramError> {
+        if address_book_account_info.owner != program_id {
+            msg!("Address book account does not have the correct program id");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let address_book = AddressBook::unpack(&address_book_account_info.data.borrow())?;
+        if address_book.wallet != *wallet_account_info.key {
+            msg!(&format!(
+                "address book wallet mismatch, want: {}, got: {}",
+                wallet_account_info.key, address_book.wallet
+            ));
             return Err(ProgramError::InvalidAccountData);
         }
+        Ok(address_book)
+    }
 
-        let dest_starting_lamports = owner_account_info.lamports();
-        **owner_account_info.lamports.borrow_mut() = dest_starting_lamports
-            .checked_add(instruction_account_info.lamports())
-            .ok_or(ProgramError::InvalidAccountData)?;
-        **instruction_account_info.lamports.borrow_mut() = 0;
+    /// Process an AddAddressBookEntry instruction, returns when the entry becomes active
+    fn process_add_address_book_entry(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        address: Pubkey,
+        now: i64,
+    ) -> Result<i64, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let address_book_account_info = next_account_info(accounts_iter)?;
 
-        Ok(())
+        let mut address_book =
+            Self::load_address_book(program_id, wallet_account_info, address_book_account_info)?;
+        if address == Pubkey::default() || address_book.entries.contains_key(&address) {
+            msg!("WalletError: Address book entry already exists");
+            return Err(WalletError::InvalidInstruction.into());
+        }
+        if address_book.entries.len() >= usize::from(address_book.capacity) {
+            msg!("WalletError: Address book is full");
+            return Err(WalletError::AddressBookFull.into());
+        }
+
+        let delay = address_book.activation_delay.min(i64::MAX as u64) as i64;
+        let active_at = now.saturating_add(delay);
+        address_book.entries.insert(address, active_at);
+        address_book.pack(&mut address_book_account_info.data.borrow_mut())?;
+
+        Ok(active_at)
     }
 
-    /// Process a WalletInstruction
-    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
-        let instruction = WalletInstruction::unpack(input, &accounts)?;
+    /// Process a RemoveAddressBookEntry instruction
+    fn process_remove_address_book_entry(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        address: Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let address_book_account_info = next_account_info(accounts_iter)?;
 
-        match instruction {
-            WalletInstruction::AddOwner { owners } => {
-                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
-                let is_wallet_initialized = wallet_account.is_initialized();
+        let mut address_book =
+            Self::load_address_book(program_id, wallet_account_info, address_book_account_info)?;
+        if address_book.entries.remove(&address).is_none() {
+            msg!("WalletError: Cannot find the address book entry to remove");
+            return Err(WalletError::InvalidInstruction.into());
+        }
+        address_book.pack(&mut address_book_account_info.data.borrow_mut())
+    }
 
-                // TODO add init instruction to handle it
-                if !is_wallet_initialized {
-                    msg!("Instruction: AddOwner (Initialize Wallet)");
-                    Self::process_initialize_wallet(&mut wallet_account, owners)?;
-                } else {
-                    msg!("Instruction: AddOwner");
-                    Self::check_signatures(accounts, &wallet_account)?;
-                    Self::process_add_owner(&mut wallet_account, owners)?;
+    /// Addresses that the instructions send lamports or tokens from the wallet vault with the
+    /// given index to, or give control over its tokens to. When `strict`, a system or token
+    /// instruction the vault signs without a known destination is rejected.
+    fn outgoing_destinations(
+        program_id: &Pubkey,
+        wallet: &Pubkey,
+        vault_index: u16,
+        instructions: &[Instruction],
+        strict: bool,
+    ) -> Result<Vec<Pubkey>, ProgramError> {
+        let (vault, _) = Self::vault_address_and_seeds(program_id, wallet, vault_index)?;
+        let mut destinations = Vec::new();
+        for instruction in instructions {
+            let account = |idx: usize| instruction.accounts.get(idx).map(|meta| meta.pubkey);
+            // (authority index, destination), moving nothing out without a destination
+            let transfer = if instruction.program_id == system_program::id() {
+                match bincode::deserialize::<SystemInstruction>(&instruction.data) {
+                    Ok(SystemInstruction::Transfer { .. }) => Some((0, account(1))),
+                    Ok(SystemInstruction::TransferWithSeed { .. }) => Some((1, account(2))),
+                    Ok(SystemInstruction::CreateAccount { .. }) => Some((0, account(1))),
+                    Ok(SystemInstruction::CreateAccountWithSeed { .. }) => Some((0, account(1))),
+                    _ => None,
                 }
-
-                Self::store_wallet_account(program_id, accounts, wallet_account)
-            }
-            WalletInstruction::RemoveOwner { pubkey } => {
-                msg!("Instruction: RemoveOwner");
-                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
-                if !wallet_account.is_initialized() {
-                    return Err(ProgramError::UninitializedAccount);
+            } else if instruction.program_id == spl_token::id() {
+                match TokenInstruction::unpack(&instruction.data) {
+                    Ok(TokenInstruction::Transfer { .. }) => Some((2, account(1))),
+                    Ok(TokenInstruction::TransferChecked { .. }) => Some((3, account(2))),
+                    Ok(TokenInstruction::Approve { .. }) => Some((2, account(1))),
+                    Ok(TokenInstruction::ApproveChecked { .. }) => Some((3, account(2))),
+                    Ok(TokenInstruction::SetAuthority { new_authority, .. }) => {
+                        Some((1, Option::from(new_authority)))
+                    }
+                    Ok(TokenInstruction::CloseAccount) => Some((2, account(1))),
+                    Ok(TokenInstruction::Revoke) => Some((1, None)),
+                    _ => None,
                 }
-                Self::check_signatures(accounts, &wallet_account)?;
-                Self::process_remove_owner(&mut wallet_account, pubkey)?;
-
-                Self::store_wallet_account(program_id, accounts, wallet_account)
-            }
-            WalletInstruction::Recovery { owners } => {
-                msg!("Instruction: Recovery");
-                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
-                if !wallet_account.is_initialized() {
-                    return Err(ProgramError::UninitializedAccount);
+            } else {
+                continue;
+            };
+            match transfer {
+                Some((authority_idx, destination)) if account(authority_idx) == Some(vault) => {
+                    destinations.extend(destination);
                 }
-                Self::check_signatures(accounts, &wallet_account)?;
-                Self::process_recovery(&mut wallet_account, owners)?;
-
-                Self::store_wallet_account(program_id, accounts, wallet_account)
-            }
-            WalletInstruction::Revoke => {
-                msg!("Instruction: Revoke");
-                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
-                if !wallet_account.is_initialized() {
-                    return Err(ProgramError::UninitializedAccount);
+                _ if strict
+                    && instruction
+                        .accounts
+                        .iter()
+                        .any(|meta| meta.is_signer && meta.pubkey == vault) =>
+                {
+                    msg!(&format!(
+                        "{} signs an instruction without a known destination",
+                        vault
+                    ));
+                    return Err(WalletError::DestinationNotApproved.into());
                 }
-                Self::check_signatures(accounts, &wallet_account)?;
-                Self::process_revoke(&mut wallet_account)?;
-
-                Self::store_wallet_account(program_id, accounts, wallet_account)
+                _ => {}
             }
-            WalletInstruction::Invoke {
-                instruction: internal_instruction,
-            } => {
-                msg!("Instruction: Invoke");
-                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
-                if !wallet_account.is_initialized() {
-                    return Err(ProgramError::UninitializedAccount);
-                }
-                Self::check_signatures(accounts, &wallet_account)?;
-                Self::process_invoke(accounts, internal_instruction)
+        }
+        Ok(destinations)
+    }
+
+    /// Reject destinations outside the wallet address book, when the wallet has one
+    fn check_address_book(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        wallet_account: &Account,
+        destinations: &[Pubkey],
+    ) -> ProgramResult {
+        if !wallet_account.address_book {
+            return Ok(());
+        }
+        Self::check_address_book_destinations(
+            program_id,
+            accounts,
+            destinations,
+            Clock::get()?.unix_timestamp,
+        )
+    }
+
+    /// Reject destinations that are not active address book entries. A token account
+    /// destination is also accepted when its owner is an active entry.
+    fn check_address_book_destinations(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        destinations: &[Pubkey],
+        now: i64,
+    ) -> ProgramResult {
+        let wallet_account_info = next_account_info(&mut accounts.iter())?;
+        let (address_book_address, _) =
+            get_address_book_address(wallet_account_info.key, program_id);
+        let address_book_account_info = accounts
+            .iter()
+            .find(|account| *account.key == address_book_address)
+            .ok_or_else(|| {
+                msg!("the wallet address book account is missing");
+                ProgramError::NotEnoughAccountKeys
+            })?;
+        let address_book =
+            Self::load_address_book(program_id, wallet_account_info, address_book_account_info)?;
+
+        for destination in destinations {
+            if address_book.is_active(destination, now) {
+                continue;
             }
-            WalletInstruction::Hello => {
-                msg!("Instruction: Hello");
-                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
-                if !wallet_account.is_initialized() {
-                    return Err(ProgramError::UninitializedAccount);
+            let token_account_owner = accounts
+                .iter()
+                .find(|account| account.key == destination && *account.owner == spl_token::id())
+                .and_then(|account| TokenAccount::unpack(&account.data.borrow()).ok())
+                .map(|token_account| token_account.owner);
+            match token_account_owner {
+                Some(owner) if address_book.is_active(&owner, now) => continue,
+                _ => {
+                    msg!(&format!("{} is not in the address book", destination));
+                    return Err(WalletError::DestinationNotApproved.into());
                 }
-                Self::check_signatures(accounts, &wallet_account)?;
-                Self::process_hello()
             }
-            WalletInstruction::InitInstructionBuffer => {
-                msg!("Instruction: InitInstructionBuffer");
-                Self::process_init_instruction_buffer(program_id, accounts)
+        }
+        Ok(())
+    }
+
+    /// Process a CloseTokenAccount instruction
+    fn process_close_token_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        guard: GuardContext,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let vault_account_info = next_account_info(accounts_iter)?;
+        let token_account_info = next_account_info(accounts_iter)?;
+        let token_program_info = next_account_info(accounts_iter)?;
+
+        Self::check_wallet_vault(program_id, wallet_account_info, vault_account_info)?;
+        Self::check_token_program(token_program_info)?;
+
+        Self::invoke_vault_signed(
+            guard,
+            &spl_token::instruction::close_account(
+                token_program_info.key,
+                token_account_info.key,
+                vault_account_info.key,
+                vault_account_info.key,
+                &[],
+            )?,
+            &[
+                token_account_info.clone(),
+                vault_account_info.clone(),
+                token_program_info.clone(),
+            ],
+            &[&wallet_account_info.key.to_bytes()],
+        )
+    }
+
+    /// Pay from the wallet vault in SOL or the given token mint, returns the account paid.
+    /// The accounts are the wallet, the wallet vault, the destination and the system program
+    /// for SOL, or the wallet, the wallet vault, the vault token account, the mint, the
+    /// destination token account and the token program for tokens
+    fn process_vault_payment(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        mint: Option<Pubkey>,
+        amount: u64,
+        rent: &Rent,
+        guard: GuardContext,
+    ) -> Result<Pubkey, ProgramError> {
+        match mint {
+            None => {
+                let destination_account_info =
+                    accounts.get(2).ok_or(ProgramError::NotEnoughAccountKeys)?;
+                Self::process_transfer_lamports(program_id, accounts, amount, rent, guard)?;
+                Ok(*destination_account_info.key)
             }
-            WalletInstruction::AppendPartialInsturciton { offset, data } => {
-                msg!("Instruction: AppendPartialInsturciton");
-                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
-                if !wallet_account.is_initialized() {
-                    return Err(ProgramError::UninitializedAccount);
+            Some(mint) => {
+                let mint_account_info =
+                    accounts.get(3).ok_or(ProgramError::NotEnoughAccountKeys)?;
+                let destination_account_info =
+                    accounts.get(4).ok_or(ProgramError::NotEnoughAccountKeys)?;
+                if *mint_account_info.key != mint {
+                    msg!(&format!(
+                        "mint mismatch, want: {}, got: {}",
+                        mint, mint_account_info.key
+                    ));
+                    return Err(ProgramError::InvalidArgument);
                 }
-                Self::check_signatures(accounts, &wallet_account)?;
-                Self::process_append_partial_instruction(program_id, accounts, offset, data)
+                let decimals = Mint::unpack(&mint_account_info.data.borrow())?.decimals;
+                Self::process_token_transfer(program_id, accounts, amount, decimals, guard)?;
+                Ok(*destination_account_info.key)
             }
-            WalletInstruction::RunInstructionBuffer {
-                expected_instruction_count,
-            } => {
-                msg!("Instruction: RunInstructionBuffer");
-                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
-                if !wallet_account.is_initialized() {
-                    return Err(ProgramError::UninitializedAccount);
-                }
-                Self::process_run_insturction_buffer(
-                    program_id,
-                    accounts,
-                    expected_instruction_count,
+        }
+    }
+
+    /// Destination of a vault payment among the accounts taken by `process_vault_payment`
+    fn vault_payment_destination<'a, 'b>(
+        accounts: &'b [AccountInfo<'a>],
+        mint: Option<Pubkey>,
+    ) -> Result<&'b AccountInfo<'a>, ProgramError> {
+        let destination_index = match mint {
+            None => 2,
+            Some(_) => 4,
+        };
+        accounts
+            .get(destination_index)
+            .ok_or(ProgramError::NotEnoughAccountKeys)
+    }
+
+    /// Check the destination of a vault payment belongs to the recipient: the recipient itself
+    /// for SOL, a token account it owns for tokens. Scheduled payments created before their
+    /// recipient was the owner still name the token account itself
+    fn check_payment_recipient(
+        accounts: &[AccountInfo],
+        mint: Option<Pubkey>,
+        recipient: &Pubkey,
+    ) -> ProgramResult {
+        let destination_account_info = Self::vault_payment_destination(accounts, mint)?;
+        let owner = match mint {
+            _ if destination_account_info.key == recipient => *recipient,
+            None => *destination_account_info.key,
+            Some(_) => TokenAccount::unpack(&destination_account_info.data.borrow())?.owner,
+        };
+        if owner != *recipient {
+            msg!(&format!(
+                "recipient mismatch, want: {}, got: {}",
+                recipient, owner
+            ));
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Reimburse the relayer of an instruction from the wallet vault, returns the account
+    /// reimbursed. The reimbursement accounts follow the relayed instruction accounts: the
+    /// wallet vault, the relayer and the system program for SOL, or the wallet vault, the vault
+    /// token account, the mint, the relayer token account and the token program for tokens
+    fn process_reimbursement(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        wallet_account: &Account,
+        signers: &[Pubkey],
+        amount: u64,
+        rent: &Rent,
+    ) -> Result<Pubkey, ProgramError> {
+        if wallet_account.reimbursement_cap == 0 || amount > wallet_account.reimbursement_cap {
+            msg!(&format!(
+                "reimbursement {} exceeds the wallet cap {}",
+                amount, wallet_account.reimbursement_cap
+            ));
+            return Err(WalletError::ReimbursementCapExceeded.into());
+        }
+
+        let tail_len = match wallet_account.reimbursement_mint {
+            None => 3,
+            Some(_) => 5,
+        };
+        let tail_start = accounts
+            .len()
+            .checked_sub(tail_len)
+            .filter(|start| *start > 0)
+            .ok_or(ProgramError::NotEnoughAccountKeys)?;
+        let mut reimbursement_accounts = vec![accounts[0].clone()];
+        reimbursement_accounts.extend_from_slice(&accounts[tail_start..]);
+
+        let relayer = Self::process_vault_payment(
+            program_id,
+            &reimbursement_accounts,
+            wallet_account.reimbursement_mint,
+            amount,
+            rent,
+            GuardContext {
+                accounts,
+                wallet_account,
+                signers,
+            },
+        )?;
+        Self::check_address_book(program_id, accounts, wallet_account, &[relayer])?;
+        Ok(relayer)
+    }
+
+    /// Process a CreateScheduledPayment instruction, creating the scheduled payment PDA paid by
+    /// the payer
+    fn process_create_scheduled_payment(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        schedule: &ScheduledPayment,
+        rent: &Rent,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let schedule_account_info = next_account_info(accounts_iter)?;
+        let payer_account_info = next_account_info(accounts_iter)?;
+        let system_program_info = next_account_info(accounts_iter)?;
+
+        if schedule.amount == 0 || schedule.interval == 0 {
+            msg!("WalletError: Scheduled payment needs an amount and an interval");
+            return Err(WalletError::InvalidInstruction.into());
+        }
+        if schedule.end != 0 && schedule.end < schedule.start {
+            msg!("WalletError: Scheduled payment ends before it starts");
+            return Err(WalletError::InvalidInstruction.into());
+        }
+
+        let (schedule_address, bump_seed) =
+            get_scheduled_payment_address(wallet_account_info.key, schedule.id, program_id);
+        Self::create_pda_account(
+            program_id,
+            payer_account_info,
+            schedule_account_info,
+            system_program_info,
+            ScheduledPayment::LEN,
+            rent,
+            &schedule_address,
+            &[
+                ScheduledPayment::SEED,
+                &wallet_account_info.key.to_bytes(),
+                &schedule.id.to_le_bytes(),
+                &[bump_seed],
+            ],
+        )?;
+
+        schedule.pack(&mut schedule_account_info.data.borrow_mut())
+    }
+
+    /// Load a scheduled payment of the wallet
+    fn load_scheduled_payment(
+        program_id: &Pubkey,
+        wallet_account_info: &AccountInfo,
+        schedule_account_info: &AccountInfo,
+    ) -> Result<ScheduledPayment, ProgramError> {
+        if schedule_account_info.owner != program_id {
+            msg!("Scheduled payment account does not have the correct program id");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let schedule = ScheduledPayment::unpack(&schedule_account_info.data.borrow())?;
+        if schedule.wallet != *wallet_account_info.key {
+            msg!(&format!(
+                "scheduled payment wallet mismatch, want: {}, got: {}",
+                wallet_account_info.key, schedule.wallet
+            ));
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(schedule)
+    }
+
+    /// Process an ExecuteScheduledPayment instruction, returns the schedule after the payment.
+    /// The scheduled payment account follows the wallet, the remaining accounts are the vault
+    /// payment accounts
+    fn process_execute_scheduled_payment(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        now: i64,
+        rent: &Rent,
+        guard: GuardContext,
+    ) -> Result<ScheduledPayment, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let schedule_account_info = next_account_info(accounts_iter)?;
+
+        let mut schedule =
+            Self::load_scheduled_payment(program_id, wallet_account_info, schedule_account_info)?;
+        schedule.record_execution(now)?;
+
+        let mut payment_accounts = vec![wallet_account_info.clone()];
+        payment_accounts.extend(accounts_iter.cloned());
+        Self::check_payment_recipient(&payment_accounts, schedule.mint, &schedule.recipient)?;
+        Self::process_vault_payment(
+            program_id,
+            &payment_accounts,
+            schedule.mint,
+            schedule.amount,
+            rent,
+            guard,
+        )?;
+
+        schedule.pack(&mut schedule_account_info.data.borrow_mut())?;
+        Ok(schedule)
+    }
+
+    /// Process a CreateStream instruction, creating the stream PDA paid by the payer
+    fn process_create_stream(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        stream: &Stream,
+        rent: &Rent,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let stream_account_info = next_account_info(accounts_iter)?;
+        let payer_account_info = next_account_info(accounts_iter)?;
+        let system_program_info = next_account_info(accounts_iter)?;
+
+        if stream.total == 0 {
+            msg!("WalletError: Stream needs a total");
+            return Err(WalletError::InvalidInstruction.into());
+        }
+        if stream.start >= stream.end || stream.cliff < stream.start || stream.cliff > stream.end {
+            msg!("WalletError: Stream needs start <= cliff <= end and start < end");
+            return Err(WalletError::InvalidInstruction.into());
+        }
+
+        let (stream_address, bump_seed) =
+            get_stream_address(wallet_account_info.key, stream.id, program_id);
+        Self::create_pda_account(
+            program_id,
+            payer_account_info,
+            stream_account_info,
+            system_program_info,
+            Stream::LEN,
+            rent,
+            &stream_address,
+            &[
+                Stream::SEED,
+                &wallet_account_info.key.to_bytes(),
+                &stream.id.to_le_bytes(),
+                &[bump_seed],
+            ],
+        )?;
+
+        stream.pack(&mut stream_account_info.data.borrow_mut())
+    }
+
+    /// Load a stream of the wallet
+    fn load_stream(
+        program_id: &Pubkey,
+        wallet_account_info: &AccountInfo,
+        stream_account_info: &AccountInfo,
+    ) -> Result<Stream, ProgramError> {
+        if stream_account_info.owner != program_id {
+            msg!("Stream account does not have the correct program id");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let stream = Stream::unpack(&stream_account_info.data.borrow())?;
+        if stream.wallet != *wallet_account_info.key {
+            msg!(&format!(
+                "stream wallet mismatch, want: {}, got: {}",
+                wallet_account_info.key, stream.wallet
+            ));
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(stream)
+    }
+
+    /// Process a WithdrawStream instruction, returns the destination and the amount withdrawn.
+    /// The stream and its signing recipient follow the wallet, the remaining accounts are the
+    /// vault payment accounts
+    fn process_withdraw_stream(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        now: i64,
+        rent: &Rent,
+        guard: GuardContext,
+    ) -> Result<(Pubkey, u64), ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let stream_account_info = next_account_info(accounts_iter)?;
+        let recipient_account_info = next_account_info(accounts_iter)?;
+
+        let mut stream = Self::load_stream(program_id, wallet_account_info, stream_account_info)?;
+        if *recipient_account_info.key != stream.recipient || !recipient_account_info.is_signer {
+            msg!(&format!("{} should sign the withdrawal", stream.recipient));
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let amount = stream.withdrawable(now);
+        if amount == 0 {
+            msg!("WalletError: Nothing vested to withdraw");
+            return Err(WalletError::NothingVested.into());
+        }
+        stream.withdrawn += amount;
+        stream.pack(&mut stream_account_info.data.borrow_mut())?;
+
+        let mut payment_accounts = vec![wallet_account_info.clone()];
+        payment_accounts.extend(accounts_iter.cloned());
+        let destination = Self::process_vault_payment(
+            program_id,
+            &payment_accounts,
+            stream.mint,
+            amount,
+            rent,
+            guard,
+        )?;
+        Ok((destination, amount))
+    }
+
+    /// Process a CancelStream instruction, paying the recipient what has vested and closing the
+    /// stream, returns the amount paid. The stream and the rent destination follow the wallet,
+    /// the remaining accounts are the vault payment accounts. A frozen wallet can still cancel,
+    /// but nothing leaves the vault so the vested amount is not paid
+    fn process_cancel_stream(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        now: i64,
+        rent: &Rent,
+        frozen: bool,
+        guard: GuardContext,
+    ) -> Result<u64, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let stream_account_info = next_account_info(accounts_iter)?;
+        let destination_account_info = next_account_info(accounts_iter)?;
+
+        let stream = Self::load_stream(program_id, wallet_account_info, stream_account_info)?;
+        let amount = if frozen { 0 } else { stream.withdrawable(now) };
+        if amount != 0 {
+            let mut payment_accounts = vec![wallet_account_info.clone()];
+            payment_accounts.extend(accounts_iter.cloned());
+
+            // the vested amount belongs to the recipient
+            Self::check_payment_recipient(&payment_accounts, stream.mint, &stream.recipient)?;
+            Self::process_vault_payment(
+                program_id,
+                &payment_accounts,
+                stream.mint,
+                amount,
+                rent,
+                guard,
+            )?;
+        }
+
+        Self::close_account(stream_account_info, destination_account_info)?;
+        Ok(amount)
+    }
+
+    /// Process a CloseScheduledPayment instruction, rent goes to the destination
+    fn process_close_scheduled_payment(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let schedule_account_info = next_account_info(accounts_iter)?;
+        let destination_account_info = next_account_info(accounts_iter)?;
+
+        Self::load_scheduled_payment(program_id, wallet_account_info, schedule_account_info)?;
+        Self::close_account(schedule_account_info, destination_account_info)
+    }
+
+    /// Call the wallet guard program with an instruction the wallet is about to run, or has just
+    /// run, and the owners approving it. A failing guard aborts the whole instruction
+    fn call_guard(
+        accounts: &[AccountInfo],
+        wallet_account: &Account,
+        wallet: &Pubkey,
+        phase: GuardPhase,
+        instruction: &Instruction,
+        signers: &[Pubkey],
+    ) -> ProgramResult {
+        let guard = match wallet_account.guard {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
+        if !accounts.iter().any(|account| *account.key == guard) {
+            msg!(&format!("guard program {} is missing", guard));
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+
+        invoke(
+            &guard_instruction(&guard, wallet, phase, instruction, signers)?,
+            accounts,
+        )
+    }
+
+    /// Invoke an instruction signed by the wallet vault, passing it through the wallet guard
+    /// before and after
+    fn invoke_vault_signed(
+        guard: GuardContext,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let wallet = next_account_info(&mut guard.accounts.iter())?.key;
+        Self::call_guard(
+            guard.accounts,
+            guard.wallet_account,
+            wallet,
+            GuardPhase::PreExecution,
+            instruction,
+            guard.signers,
+        )?;
+        invoke_signed(instruction, account_infos, &[signer_seeds])?;
+        Self::call_guard(
+            guard.accounts,
+            guard.wallet_account,
+            wallet,
+            GuardPhase::PostExecution,
+            instruction,
+            guard.signers,
+        )
+    }
+
+    /// Process an EnableModule instruction
+    fn process_enable_module(
+        program_id: &Pubkey,
+        wallet_account: &mut Account,
+        module: Pubkey,
+    ) -> ProgramResult {
+        if module == *program_id || wallet_account.modules.contains(&module) {
+            msg!(&format!("{} cannot be enabled as a module", module));
+            return Err(WalletError::InvalidInstruction.into());
+        }
+        if wallet_account.modules.len() >= Account::MAX_MODULES {
+            msg!("WalletError: Module limit reached");
+            return Err(WalletError::ModuleLimitReached.into());
+        }
+        wallet_account.modules.push(module);
+        Ok(())
+    }
+
+    /// Process a DisableModule instruction
+    fn process_disable_module(wallet_account: &mut Account, module: Pubkey) -> ProgramResult {
+        let len = wallet_account.modules.len();
+        wallet_account.modules.retain(|enabled| *enabled != module);
+        if wallet_account.modules.len() == len {
+            msg!(&format!("{} is not an enabled module", module));
+            return Err(WalletError::ModuleNotEnabled.into());
+        }
+        Ok(())
+    }
+
+    /// Check the second account is the signing authority of an enabled module, returns the module
+    fn check_module(
+        accounts: &[AccountInfo],
+        wallet_account: &Account,
+    ) -> Result<Pubkey, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let authority_account_info = next_account_info(accounts_iter)?;
+
+        if !authority_account_info.is_signer {
+            msg!(&format!(
+                "{} should be a signer",
+                authority_account_info.key
+            ));
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        wallet_account
+            .modules
+            .iter()
+            .find(|module| {
+                get_module_authority_address(wallet_account_info.key, module).0
+                    == *authority_account_info.key
+            })
+            .copied()
+            .ok_or_else(|| {
+                msg!(&format!(
+                    "{} is not the authority of an enabled module",
+                    authority_account_info.key
+                ));
+                WalletError::ModuleNotEnabled.into()
+            })
+    }
+
+    /// Process a SetGuard instruction, the current guard has to pass the change
+    fn process_set_guard(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        wallet_account: &mut Account,
+        guard: Option<Pubkey>,
+        signers: &[Pubkey],
+    ) -> ProgramResult {
+        if guard == Some(*program_id) {
+            msg!("WalletError: The wallet program cannot guard itself");
+            return Err(WalletError::InvalidInstruction.into());
+        }
+        let wallet = accounts
+            .first()
+            .ok_or(ProgramError::NotEnoughAccountKeys)?
+            .key;
+        // the guard sees the SetGuard instruction being run
+        let mut data = vec![39];
+        data.extend_from_slice(&guard.unwrap_or_default().to_bytes());
+        let set_guard_instruction = Instruction {
+            program_id: *program_id,
+            accounts: vec![AccountMeta::new(*wallet, false)],
+            data,
+        };
+        Self::call_guard(
+            accounts,
+            wallet_account,
+            wallet,
+            GuardPhase::PreExecution,
+            &set_guard_instruction,
+            signers,
+        )?;
+
+        wallet_account.guard = guard;
+        Ok(())
+    }
+
+    /// Check the account is the SPL Token program
+    fn check_token_program(token_program_info: &AccountInfo) -> ProgramResult {
+        if *token_program_info.key != spl_token::id() {
+            msg!(&format!(
+                "token program mismatch, want: {}, got: {}",
+                spl_token::id(),
+                token_program_info.key
+            ));
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Ok(())
+    }
+
+    fn is_key_weight_enough(owners: &BTreeMap<Pubkey, u32>) -> ProgramResult {
+        let mut sum_of_key_weight = 0u64;
+        for weight in owners.values() {
+            sum_of_key_weight = sum_of_key_weight
+                .checked_add(u64::from(*weight))
+                .ok_or(WalletError::WeightOverflow)?;
+        }
+        if sum_of_key_weight < u64::from(MIN_WEIGHT) {
+            return Err(WalletError::InsufficientWeight.into());
+        }
+        Ok(())
+    }
+
+    /// Check if signatures have enought weight, returns the owners whose weight was counted
+    fn check_signatures(
+        accounts: &[AccountInfo],
+        wallet_account: &Account,
+    ) -> Result<Vec<Pubkey>, ProgramError> {
+        let (total_key_weight, counted) = wallet_account.signed_weight(
+            accounts
+                .iter()
+                .filter(|account| account.is_signer)
+                .map(|account| account.key),
+        );
+
+        if total_key_weight < u64::from(MIN_WEIGHT) {
+            msg!("WalletError: Signature weight too low");
+            return Err(WalletError::InsufficientWeight.into());
+        }
+
+        Ok(counted)
+    }
+
+    /// Same as `check_signatures`, reading the owners in place from the wallet account data
+    /// instead of a decoded owner set
+    fn check_packed_signatures(accounts: &[AccountInfo]) -> Result<Vec<Pubkey>, ProgramError> {
+        let wallet_account_info = next_account_info(&mut accounts.iter())?;
+        let wallet_data = wallet_account_info.data.borrow();
+        let (total_key_weight, counted) = Owners::from_account_data(&wallet_data)?.signed_weight(
+            accounts
+                .iter()
+                .filter(|account| account.is_signer)
+                .map(|account| account.key),
+        );
+
+        if total_key_weight < u64::from(MIN_WEIGHT) {
+            msg!("WalletError: Signature weight too low");
+            return Err(WalletError::InsufficientWeight.into());
+        }
+
+        Ok(counted)
+    }
+
+    /// Check the signatures allowed to freeze the wallet: the freeze authority alone, the freeze
+    /// weight when configured, or the full threshold
+    fn check_freeze_signatures(
+        accounts: &[AccountInfo],
+        wallet_account: &Account,
+    ) -> Result<Vec<Pubkey>, ProgramError> {
+        let signer_keys = accounts
+            .iter()
+            .filter(|account| account.is_signer)
+            .map(|account| account.key);
+
+        if let Some(freeze_authority) = wallet_account.freeze_authority {
+            if signer_keys.clone().any(|key| *key == freeze_authority) {
+                return Ok(vec![freeze_authority]);
+            }
+        }
+
+        let required_weight = match wallet_account.freeze_weight {
+            0 => MIN_WEIGHT,
+            freeze_weight => freeze_weight.min(MIN_WEIGHT),
+        };
+        let (total_key_weight, counted) = wallet_account.signed_weight(signer_keys);
+        if total_key_weight < u64::from(required_weight) {
+            msg!("WalletError: Signature weight too low to freeze");
+            return Err(WalletError::InsufficientWeight.into());
+        }
+
+        Ok(counted)
+    }
+
+    /// Reject instructions that move assets out of a frozen wallet
+    fn check_not_frozen(wallet_account: &Account) -> ProgramResult {
+        if wallet_account.state == AccountState::Frozen {
+            msg!("WalletError: Wallet is frozen");
+            return Err(WalletError::WalletFrozen.into());
+        }
+        Ok(())
+    }
+
+    /// Load wallet account data
+    fn load_wallet_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> Result<Account, ProgramError> {
+        // Iterating accounts is safer then indexing
+        let accounts_iter = &mut accounts.iter();
+
+        // The account containing wallet information
+        let walllet_account = next_account_info(accounts_iter)?;
+
+        // The account must be owned by the program in order to modify its data
+        if walllet_account.owner != program_id {
+            msg!("Wallet account does not have the correct program id");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        Account::unpack_from_slice(&walllet_account.data.borrow())
+    }
+
+    /// Load the wallet account fields before the owner list, leaving the owners in place for
+    /// instructions that only weigh signatures
+    fn load_wallet_header(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> Result<Account, ProgramError> {
+        let walllet_account = next_account_info(&mut accounts.iter())?;
+        if walllet_account.owner != program_id {
+            msg!("Wallet account does not have the correct program id");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        Account::unpack_header_from_slice(&walllet_account.data.borrow())
+    }
+
+    /// The wallet account info, checked to be writable by the program
+    fn writable_wallet_account<'a, 'b>(
+        program_id: &Pubkey,
+        accounts: &'b [AccountInfo<'a>],
+    ) -> Result<&'b AccountInfo<'a>, ProgramError> {
+        // Iterating accounts is safer then indexing
+        let accounts_iter = &mut accounts.iter();
+
+        // Get the account to say hello to
+        let account = next_account_info(accounts_iter)?;
+
+        // The account must be owned by the program in order to modify its data
+        if account.owner != program_id {
+            msg!("Wallet account does not have the correct program id");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // The account must be declaired writable
+        if !account.is_writable {
+            msg!("Wallet account was not declaired writable");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(account)
+    }
+
+    /// Store wallet account data
+    fn store_wallet_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        wallet_account: Account,
+    ) -> Result<(), ProgramError> {
+        let account = Self::writable_wallet_account(program_id, accounts)?;
+        Account::pack_into_slice(&wallet_account, &mut account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_init_instruction_buffer(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        vault_index: u16,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let instruction_account_info = next_account_info(accounts_iter)?;
+        let owner_account_info = next_account_info(accounts_iter)?;
+        let wallet_account_info = next_account_info(accounts_iter)?;
+
+        if wallet_account_info.owner != program_id {
+            msg!("Wallet account does not have the correct program id");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let wallet_account = Account::unpack_header_from_slice(&wallet_account_info.data.borrow())?;
+        if !wallet_account.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Self::check_instruction_buffer_layout(&wallet_account)?;
+
+        let mut sequence_instructions =
+            InstructionBuffer::unpack(&instruction_account_info.data.borrow())?;
+        if sequence_instructions.owner != Pubkey::default() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        sequence_instructions.owner = *owner_account_info.key;
+        sequence_instructions.wallet = *wallet_account_info.key;
+        // the buffer can only be staged and run by the owner set of the wallet at this point
+        sequence_instructions.owner_epoch = wallet_account.owner_epoch;
+        sequence_instructions.vault_index = vault_index;
+
+        InstructionBuffer::pack(
+            sequence_instructions,
+            &mut instruction_account_info.data.borrow_mut(),
+        )?;
+
+        Ok(())
+    }
+
+    fn process_append_partial_instruction(
+        _program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        wallet_account: &Account,
+        offset: u16,
+        data: Vec<u8>,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let instruction_buffer_account_info = next_account_info(accounts_iter)?;
+        let owner_account_info = next_account_info(accounts_iter)?;
+
+        if !owner_account_info.is_signer {
+            msg!(&format!("{} should be a signer", owner_account_info.key));
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut instruction_buffer =
+            InstructionBuffer::unpack(&instruction_buffer_account_info.data.borrow())?;
+        if instruction_buffer.owner != *owner_account_info.key {
+            msg!(&format!(
+                "buffer account owner mismatch, want: {}, got: {}",
+                instruction_buffer.owner, *owner_account_info.key
+            ));
+            return Err(WalletError::InstructionBufferOwnerMismatch.into());
+        }
+        Self::check_instruction_buffer_wallet(&instruction_buffer, wallet_account_info)?;
+
+        // a buffer can only be staged by the owner set it was initialized for
+        if instruction_buffer.owner_epoch != wallet_account.owner_epoch {
+            msg!(&format!(
+                "buffer owner epoch mismatch, want: {}, got: {}",
+                wallet_account.owner_epoch, instruction_buffer.owner_epoch
+            ));
+            return Err(WalletError::StaleInstructionBuffer.into());
+        }
+
+        instruction_buffer.data[offset as usize..offset as usize + data.len()]
+            .copy_from_slice(&data[..]);
+
+        InstructionBuffer::pack(
+            instruction_buffer,
+            &mut instruction_buffer_account_info.data.borrow_mut(),
+        )?;
+
+        Ok(())
+    }
+
+    fn process_run_insturction_buffer(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        wallet_account_data: &Account,
+        expected_instruction_count: u16,
+        vault_index: u16,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account = next_account_info(accounts_iter)?;
+        let instruction_buffer_account_info = next_account_info(accounts_iter)?;
+        let owner_account_info = next_account_info(accounts_iter)?;
+
+        if !owner_account_info.is_signer {
+            msg!(&format!("{} should be a signer", owner_account_info.key));
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let instruction_buffer =
+            InstructionBuffer::unpack(&instruction_buffer_account_info.data.borrow())?;
+        if instruction_buffer.owner != *owner_account_info.key {
+            msg!(&format!(
+                "buffer account owner mismatch, want: {}, got: {}",
+                instruction_buffer.owner, *owner_account_info.key
+            ));
+            return Err(WalletError::InstructionBufferOwnerMismatch.into());
+        }
+        Self::check_instruction_buffer_wallet(&instruction_buffer, wallet_account)?;
+        Self::check_instruction_buffer_layout(wallet_account_data)?;
+
+        // reject buffers staged by a previous owner set
+        if instruction_buffer.owner_epoch != wallet_account_data.owner_epoch {
+            msg!(&format!(
+                "buffer owner epoch mismatch, want: {}, got: {}",
+                wallet_account_data.owner_epoch, instruction_buffer.owner_epoch
+            ));
+            return Err(WalletError::StaleInstructionBuffer.into());
+        }
+
+        // the vault is part of what was approved while staging
+        if instruction_buffer.vault_index != vault_index {
+            msg!(&format!(
+                "buffer vault mismatch, want: {}, got: {}",
+                instruction_buffer.vault_index, vault_index
+            ));
+            return Err(WalletError::InstructionBufferVaultMismatch.into());
+        }
+
+        // prepare account info
+        let mut pass_accounts = Vec::new();
+        for account in accounts_iter {
+            let mut pass_account = account.clone();
+            if pass_account.key == owner_account_info.key {
+                pass_account.is_signer = false;
+            }
+            pass_accounts.push(pass_account);
+        }
+
+        let instructions = instruction_buffer.instructions()?;
+        let destinations = Self::outgoing_destinations(
+            program_id,
+            wallet_account.key,
+            vault_index,
+            &instructions,
+            wallet_account_data.address_book,
+        )?;
+        Self::check_address_book(program_id, accounts, wallet_account_data, &destinations)?;
+
+        // the buffer was approved while staging, the guard sees the owners signing the run
+        let (_, signers) = wallet_account_data.signed_weight(
+            accounts
+                .iter()
+                .filter(|account| account.is_signer)
+                .map(|account| account.key),
+        );
+
+        let (_, seeds) =
+            Self::vault_address_and_seeds(program_id, wallet_account.key, vault_index)?;
+        let seeds = seeds.iter().map(Vec::as_slice).collect::<Vec<_>>();
+
+        // execute instructions
+        let mut instruction_count = 0;
+        for instruction in instructions {
+            Self::check_nesting_depth(program_id, accounts, &instruction)?;
+            Self::call_guard(
+                accounts,
+                wallet_account_data,
+                wallet_account.key,
+                GuardPhase::PreExecution,
+                &instruction,
+                &signers,
+            )?;
+            invoke_signed(&instruction, &pass_accounts, &[&seeds])?;
+            Self::call_guard(
+                accounts,
+                wallet_account_data,
+                wallet_account.key,
+                GuardPhase::PostExecution,
+                &instruction,
+                &signers,
+            )?;
+            instruction_count += 1;
+        }
+
+        // check instruction count
+        if instruction_count != expected_instruction_count {
+            msg!(&format!(
+                "instruction count mismatch, want: {}, got: {}",
+                expected_instruction_count, instruction_count
+            ));
+            return Err(WalletError::InstructionCountMismatch.into());
+        }
+
+        // close buffer account
+        Self::close_account(instruction_buffer_account_info, owner_account_info)?;
+
+        WalletEvent::BufferExecuted {
+            wallet: *wallet_account.key,
+            instruction_buffer: *instruction_buffer_account_info.key,
+            instruction_count,
+            owner_epoch: instruction_buffer.owner_epoch,
+            signers,
+        }
+        .emit();
+
+        Ok(())
+    }
+
+    fn process_close_instruction_buffer(
+        _program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let instruction_account_info = next_account_info(accounts_iter)?;
+        let owner_account_info = next_account_info(accounts_iter)?;
+
+        if !owner_account_info.is_signer {
+            msg!(&format!("{} should be a signer", owner_account_info.key));
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let sequence_instructions =
+            InstructionBuffer::unpack(&instruction_account_info.data.borrow())?;
+
+        if sequence_instructions.owner != *owner_account_info.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Self::close_account(instruction_account_info, owner_account_info)
+    }
+
+    fn process_reclaim_instruction_buffer(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let instruction_buffer_account_info = next_account_info(accounts_iter)?;
+        let vault_account_info = next_account_info(accounts_iter)?;
+
+        let instruction_buffer =
+            InstructionBuffer::unpack(&instruction_buffer_account_info.data.borrow())?;
+        Self::check_instruction_buffer_wallet(&instruction_buffer, wallet_account_info)?;
+
+        Self::check_wallet_vault(program_id, wallet_account_info, vault_account_info)?;
+
+        Self::close_account(instruction_buffer_account_info, vault_account_info)
+    }
+
+    fn process_close_wallet(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let wallet_account_info = next_account_info(accounts_iter)?;
+        let vault_account_info = next_account_info(accounts_iter)?;
+        let destination_account_info = next_account_info(accounts_iter)?;
+
+        Self::check_wallet_vault(program_id, wallet_account_info, vault_account_info)?;
+
+        // assets left in the vault can never be moved once the wallet is gone
+        if vault_account_info.lamports() != 0 {
+            msg!(&format!(
+                "vault is not empty, lamports: {}",
+                vault_account_info.lamports()
+            ));
+            return Err(WalletError::VaultNotEmpty.into());
+        }
+        if destination_account_info.key == vault_account_info.key {
+            msg!("Wallet account cannot be closed into its own vault");
+            return Err(ProgramError::InvalidArgument);
+        }
+        // the program cannot list the vault's token accounts, the caller passes them after the
+        // destination so tokens are not stranded with the wallet
+        for account_info in accounts_iter.filter(|account| *account.owner == spl_token::id()) {
+            let token_account = TokenAccount::unpack(&account_info.data.borrow())?;
+            if token_account.owner != *vault_account_info.key {
+                msg!(&format!(
+                    "{} is not a token account of the vault",
+                    account_info.key
+                ));
+                return Err(ProgramError::InvalidArgument);
+            }
+            if token_account.amount != 0 {
+                msg!(&format!(
+                    "vault token account {} is not empty, amount: {}",
+                    account_info.key, token_account.amount
+                ));
+                return Err(WalletError::VaultNotEmpty.into());
+            }
+        }
+
+        Self::close_account(wallet_account_info, destination_account_info)
+    }
+
+    /// Check the vault account is the one derived from the wallet
+    fn check_wallet_vault(
+        program_id: &Pubkey,
+        wallet_account_info: &AccountInfo,
+        vault_account_info: &AccountInfo,
+    ) -> ProgramResult {
+        let vault_address = get_wallet_vault_address(wallet_account_info.key, program_id)?;
+        if *vault_account_info.key != vault_address {
+            msg!(&format!(
+                "vault address mismatch, want: {}, got: {}",
+                vault_address, vault_account_info.key
+            ));
+            return Err(ProgramError::InvalidSeeds);
+        }
+        Ok(())
+    }
+
+    /// Close a program owned account: move out all lamports, wipe its data and mark it closed
+    /// so it cannot be revived with stale contents within the same transaction
+    fn close_account(
+        account_info: &AccountInfo,
+        destination_account_info: &AccountInfo,
+    ) -> ProgramResult {
+        if account_info.key == destination_account_info.key {
+            msg!("Cannot close an account into itself");
+            return Err(ProgramError::InvalidArgument);
+        }
+        if !destination_account_info.is_writable {
+            msg!(&format!(
+                "{} should be writable",
+                destination_account_info.key
+            ));
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let dest_starting_lamports = destination_account_info.lamports();
+        **destination_account_info.lamports.borrow_mut() = dest_starting_lamports
+            .checked_add(account_info.lamports())
+            .ok_or(ProgramError::InvalidAccountData)?;
+        **account_info.lamports.borrow_mut() = 0;
+
+        let mut data = account_info.data.borrow_mut();
+        for i in data.iter_mut() {
+            *i = 0;
+        }
+        let discriminator_len = CLOSED_ACCOUNT_DISCRIMINATOR.len().min(data.len());
+        data[..discriminator_len]
+            .copy_from_slice(&CLOSED_ACCOUNT_DISCRIMINATOR[..discriminator_len]);
+
+        Ok(())
+    }
+
+    /// Check the instruction buffer is staged for the given wallet
+    fn check_instruction_buffer_wallet(
+        instruction_buffer: &InstructionBuffer,
+        wallet_account_info: &AccountInfo,
+    ) -> ProgramResult {
+        if instruction_buffer.wallet != *wallet_account_info.key {
+            msg!(&format!(
+                "buffer account wallet mismatch, want: {}, got: {}",
+                instruction_buffer.wallet, wallet_account_info.key
+            ));
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(())
+    }
+
+    /// Legacy wallet accounts have no owner epoch, so a buffer staged for them could not be told
+    /// apart from one staged by a previous owner set
+    fn check_instruction_buffer_layout(wallet_account: &Account) -> ProgramResult {
+        if wallet_account.layout == AccountLayout::Legacy {
+            msg!("WalletError: Legacy wallet accounts cannot use instruction buffers");
+            return Err(WalletError::UnsupportedLayout.into());
+        }
+        Ok(())
+    }
+
+    /// Process a WalletInstruction
+    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
+        let instruction = WalletInstruction::unpack(input, accounts)?;
+        let history_entry = Self::history_entry(program_id, accounts, input, &instruction)?;
+        // fail before running the instruction when it could not be recorded
+        if history_entry.is_some() {
+            Self::history_account_info(program_id, accounts)?;
+        }
+        Self::process_instruction(program_id, accounts, instruction)?;
+        if let Some(mut entry) = history_entry {
+            entry.slot = Clock::get()?.slot;
+            Self::append_history(program_id, accounts, &entry)?;
+        }
+        Ok(())
+    }
+
+    /// Processes an unpacked [WalletInstruction](enum.WalletInstruction.html).
+    fn process_instruction(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction: WalletInstruction,
+    ) -> ProgramResult {
+        match instruction {
+            WalletInstruction::AddOwner { owners } => {
+                let wallet_account = Self::load_wallet_header(program_id, accounts)?;
+                let is_wallet_initialized = wallet_account.is_initialized();
+
+                // TODO add init instruction to handle it
+                let event = if !is_wallet_initialized {
+                    msg!("Instruction: AddOwner (Initialize Wallet)");
+                    let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                    Self::process_initialize_wallet(&mut wallet_account, owners.clone())?;
+                    Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                    WalletEvent::WalletInitialized {
+                        wallet: *accounts[0].key,
+                        owners,
+                    }
+                } else {
+                    msg!("Instruction: AddOwner");
+                    let signers = Self::check_packed_signatures(accounts)?;
+                    let wallet_account_info = Self::writable_wallet_account(program_id, accounts)?;
+                    Self::process_add_owner(
+                        &mut wallet_account_info.data.borrow_mut(),
+                        owners.clone(),
+                    )?;
+                    WalletEvent::OwnerAdded {
+                        wallet: *accounts[0].key,
+                        owners,
+                        signers,
+                    }
+                };
+
+                event.emit();
+                Ok(())
+            }
+            WalletInstruction::RemoveOwner { pubkey } => {
+                msg!("Instruction: RemoveOwner");
+                let wallet_account = Self::load_wallet_header(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_packed_signatures(accounts)?;
+                let wallet_account_info = Self::writable_wallet_account(program_id, accounts)?;
+                Self::process_remove_owner(&mut wallet_account_info.data.borrow_mut(), pubkey)?;
+
+                WalletEvent::OwnerRemoved {
+                    wallet: *accounts[0].key,
+                    pubkey,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::Recovery { owners } => {
+                msg!("Instruction: Recovery");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_recovery(&mut wallet_account, owners.clone())?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::Recovered {
+                    wallet: *accounts[0].key,
+                    owners,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::UpdateOwners {
+                adds,
+                removes,
+                weight_changes,
+            } => {
+                msg!("Instruction: UpdateOwners");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_update_owners(&mut wallet_account, adds, removes, weight_changes)?;
+                let owners = wallet_account.owners.clone();
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::OwnersUpdated {
+                    wallet: *accounts[0].key,
+                    owners,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::RotateOwnerKey { new_key } => {
+                msg!("Instruction: RotateOwnerKey");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let accounts_iter = &mut accounts.iter();
+                let wallet_account_info = next_account_info(accounts_iter)?;
+                let old_key_account_info = next_account_info(accounts_iter)?;
+                let new_key_account_info = next_account_info(accounts_iter)?;
+                if *new_key_account_info.key != new_key {
+                    msg!("new key account does not match the instruction");
+                    return Err(ProgramError::InvalidArgument);
+                }
+                for key_account_info in [old_key_account_info, new_key_account_info] {
+                    if !key_account_info.is_signer {
+                        msg!(&format!("{} should be a signer", key_account_info.key));
+                        return Err(ProgramError::MissingRequiredSignature);
+                    }
+                }
+                let old_key = *old_key_account_info.key;
+                let weight = wallet_account.owners.get(&old_key).cloned().unwrap_or(0);
+                let rotated = Self::process_rotate_owner_key(
+                    &mut wallet_account,
+                    old_key,
+                    new_key,
+                    Clock::get()?.unix_timestamp,
+                )?;
+                let pending_rotation = wallet_account.pending_rotation;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                match pending_rotation {
+                    Some(rotation) if !rotated => WalletEvent::KeyRotationRequested {
+                        wallet: *wallet_account_info.key,
+                        old_key,
+                        new_key,
+                        ready_at: rotation.ready_at,
+                    },
+                    _ => WalletEvent::OwnerKeyRotated {
+                        wallet: *wallet_account_info.key,
+                        old_key,
+                        new_key,
+                        weight,
+                    },
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::SetRotationDelay { delay } => {
+                msg!("Instruction: SetRotationDelay");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                wallet_account.rotation_delay = delay;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::RotationDelaySet {
+                    wallet: *accounts[0].key,
+                    delay,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::CancelKeyRotation => {
+                msg!("Instruction: CancelKeyRotation");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                let rotation = Self::process_cancel_key_rotation(&mut wallet_account)?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::KeyRotationCancelled {
+                    wallet: *accounts[0].key,
+                    old_key: rotation.old_key,
+                    new_key: rotation.new_key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::CreateSession {
+                session_key,
+                expires_at,
+                lamport_cap,
+                token_mint,
+                token_cap,
+                allowed_programs,
+                data_prefixes,
+            } => {
+                msg!("Instruction: CreateSession");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                let session = Session {
+                    wallet: *accounts[0].key,
+                    session_key,
+                    expires_at,
+                    lamport_cap,
+                    lamports_spent: 0,
+                    token_mint,
+                    token_cap,
+                    tokens_spent: 0,
+                    allowed_programs,
+                    data_prefixes,
+                };
+                Self::process_create_session(
+                    program_id,
+                    accounts,
+                    &session,
+                    Clock::get()?.unix_timestamp,
+                    &Rent::get()?,
+                )?;
+                WalletEvent::SessionCreated {
+                    wallet: *accounts[0].key,
+                    session_key,
+                    expires_at,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::RevokeSession => {
+                msg!("Instruction: RevokeSession");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let (session_key, signers) =
+                    Self::process_revoke_session(program_id, accounts, &wallet_account)?;
+                WalletEvent::SessionRevoked {
+                    wallet: *accounts[0].key,
+                    session_key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::SessionInvoke { instruction } => {
+                msg!("Instruction: SessionInvoke");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let destinations = Self::outgoing_destinations(
+                    program_id,
+                    accounts[0].key,
+                    0,
+                    std::slice::from_ref(&instruction),
+                    wallet_account.address_book,
+                )?;
+                Self::check_address_book(program_id, accounts, &wallet_account, &destinations)?;
+                let invoked_program_id = instruction.program_id;
+                let (session, lamports, tokens) = Self::process_session_invoke(
+                    program_id,
+                    accounts,
+                    instruction,
+                    Clock::get()?.unix_timestamp,
+                    &wallet_account,
+                )?;
+                WalletEvent::SessionInvoked {
+                    wallet: *accounts[0].key,
+                    session_key: session.session_key,
+                    program_id: invoked_program_id,
+                    lamports,
+                    tokens,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::InitAddressBook {
+                capacity,
+                activation_delay,
+            } => {
+                msg!("Instruction: InitAddressBook");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_init_address_book(
+                    program_id,
+                    accounts,
+                    &mut wallet_account,
+                    capacity,
+                    activation_delay,
+                    &Rent::get()?,
+                )?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::AddressBookCreated {
+                    wallet: *accounts[0].key,
+                    activation_delay,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::AddAddressBookEntry { address } => {
+                msg!("Instruction: AddAddressBookEntry");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                let active_at = Self::process_add_address_book_entry(
+                    program_id,
+                    accounts,
+                    address,
+                    Clock::get()?.unix_timestamp,
+                )?;
+                WalletEvent::AddressBookEntryAdded {
+                    wallet: *accounts[0].key,
+                    address,
+                    active_at,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::RemoveAddressBookEntry { address } => {
+                msg!("Instruction: RemoveAddressBookEntry");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_remove_address_book_entry(program_id, accounts, address)?;
+                WalletEvent::AddressBookEntryRemoved {
+                    wallet: *accounts[0].key,
+                    address,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::Freeze => {
+                msg!("Instruction: Freeze");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_freeze_signatures(accounts, &wallet_account)?;
+                Self::process_freeze(&mut wallet_account)?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::Frozen {
+                    wallet: *accounts[0].key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::Unfreeze => {
+                msg!("Instruction: Unfreeze");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_unfreeze(&mut wallet_account)?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::Unfrozen {
+                    wallet: *accounts[0].key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::SetFreezeConfig {
+                freeze_authority,
+                freeze_weight,
+            } => {
+                msg!("Instruction: SetFreezeConfig");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                wallet_account.freeze_authority = freeze_authority;
+                wallet_account.freeze_weight = freeze_weight;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::FreezeConfigSet {
+                    wallet: *accounts[0].key,
+                    freeze_authority,
+                    freeze_weight,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::Revoke { wallet } => {
+                msg!("Instruction: Revoke");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                if wallet != *accounts[0].key {
+                    msg!("revoke confirmation does not match the wallet account");
+                    return Err(WalletError::InvalidInstruction.into());
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_revoke(&mut wallet_account)?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::Revoked {
+                    wallet: *accounts[0].key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::Invoke {
+                instruction: internal_instruction,
+            } => {
+                msg!("Instruction: Invoke");
+                let wallet_account = Self::load_wallet_header(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let signers = Self::check_packed_signatures(accounts)?;
+                Self::check_nesting_depth(program_id, accounts, &internal_instruction)?;
+                let destinations = Self::outgoing_destinations(
+                    program_id,
+                    accounts[0].key,
+                    0,
+                    std::slice::from_ref(&internal_instruction),
+                    wallet_account.address_book,
+                )?;
+                Self::check_address_book(program_id, accounts, &wallet_account, &destinations)?;
+                let invoked_program_id = internal_instruction.program_id;
+                Self::call_guard(
+                    accounts,
+                    &wallet_account,
+                    accounts[0].key,
+                    GuardPhase::PreExecution,
+                    &internal_instruction,
+                    &signers,
+                )?;
+                Self::process_invoke(program_id, accounts, 0, internal_instruction.clone())?;
+                Self::call_guard(
+                    accounts,
+                    &wallet_account,
+                    accounts[0].key,
+                    GuardPhase::PostExecution,
+                    &internal_instruction,
+                    &signers,
+                )?;
+                WalletEvent::Invoked {
+                    wallet: *accounts[0].key,
+                    program_id: invoked_program_id,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::TransferLamports { amount } => {
+                msg!("Instruction: TransferLamports");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::check_address_book(
+                    program_id,
+                    accounts,
+                    &wallet_account,
+                    &[*accounts[2].key],
+                )?;
+                Self::process_transfer_lamports(
+                    program_id,
+                    accounts,
+                    amount,
+                    &Rent::get()?,
+                    GuardContext {
+                        accounts,
+                        wallet_account: &wallet_account,
+                        signers: &signers,
+                    },
+                )?;
+                WalletEvent::LamportsTransferred {
+                    wallet: *accounts[0].key,
+                    destination: *accounts[2].key,
+                    amount,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::TokenTransfer { amount, decimals } => {
+                msg!("Instruction: TokenTransfer");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::check_address_book(
+                    program_id,
+                    accounts,
+                    &wallet_account,
+                    &[*accounts[4].key],
+                )?;
+                Self::process_token_transfer(
+                    program_id,
+                    accounts,
+                    amount,
+                    decimals,
+                    GuardContext {
+                        accounts,
+                        wallet_account: &wallet_account,
+                        signers: &signers,
+                    },
+                )?;
+                WalletEvent::TokensTransferred {
+                    wallet: *accounts[0].key,
+                    source: *accounts[2].key,
+                    destination: *accounts[4].key,
+                    mint: *accounts[3].key,
+                    amount,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::CreateAssociatedTokenAccount => {
+                msg!("Instruction: CreateAssociatedTokenAccount");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                let guard = GuardContext {
+                    accounts,
+                    wallet_account: &wallet_account,
+                    signers: &signers,
+                };
+                if Self::process_create_associated_token_account(program_id, accounts, guard)? {
+                    WalletEvent::AssociatedTokenAccountCreated {
+                        wallet: *accounts[0].key,
+                        account: *accounts[2].key,
+                        recipient: *accounts[3].key,
+                        mint: *accounts[4].key,
+                        signers,
+                    }
+                    .emit();
+                }
+                Ok(())
+            }
+            WalletInstruction::ApproveTokenDelegate { amount, decimals } => {
+                msg!("Instruction: ApproveTokenDelegate");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::check_address_book(
+                    program_id,
+                    accounts,
+                    &wallet_account,
+                    &[*accounts[4].key],
+                )?;
+                Self::process_approve_token_delegate(
+                    program_id,
+                    accounts,
+                    amount,
+                    decimals,
+                    GuardContext {
+                        accounts,
+                        wallet_account: &wallet_account,
+                        signers: &signers,
+                    },
+                )?;
+                WalletEvent::TokenDelegateApproved {
+                    wallet: *accounts[0].key,
+                    account: *accounts[2].key,
+                    delegate: *accounts[4].key,
+                    amount,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::RevokeTokenDelegate => {
+                msg!("Instruction: RevokeTokenDelegate");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_revoke_token_delegate(
+                    program_id,
+                    accounts,
+                    GuardContext {
+                        accounts,
+                        wallet_account: &wallet_account,
+                        signers: &signers,
+                    },
+                )?;
+                WalletEvent::TokenDelegateRevoked {
+                    wallet: *accounts[0].key,
+                    account: *accounts[2].key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::CloseTokenAccount => {
+                msg!("Instruction: CloseTokenAccount");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_close_token_account(
+                    program_id,
+                    accounts,
+                    GuardContext {
+                        accounts,
+                        wallet_account: &wallet_account,
+                        signers: &signers,
+                    },
+                )?;
+                WalletEvent::TokenAccountClosed {
+                    wallet: *accounts[0].key,
+                    account: *accounts[2].key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::WithReimbursement {
+                amount,
+                instruction,
+            } => {
+                msg!("Instruction: WithReimbursement");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                let relayer = Self::process_reimbursement(
+                    program_id,
+                    accounts,
+                    &wallet_account,
+                    &signers,
+                    amount,
+                    &Rent::get()?,
+                )?;
+                WalletEvent::RelayerReimbursed {
+                    wallet: *accounts[0].key,
+                    relayer,
+                    mint: wallet_account.reimbursement_mint,
+                    amount,
+                    signers,
+                }
+                .emit();
+                Self::process_instruction(program_id, accounts, *instruction)
+            }
+            WalletInstruction::SetReimbursementConfig { mint, cap } => {
+                msg!("Instruction: SetReimbursementConfig");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                wallet_account.reimbursement_mint = mint;
+                wallet_account.reimbursement_cap = cap;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::ReimbursementConfigSet {
+                    wallet: *accounts[0].key,
+                    mint,
+                    cap,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::CreateScheduledPayment {
+                id,
+                recipient,
+                mint,
+                amount,
+                interval,
+                start,
+                end,
+                count,
+            } => {
+                msg!("Instruction: CreateScheduledPayment");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                let schedule = ScheduledPayment {
+                    wallet: *accounts[0].key,
+                    id,
+                    recipient,
+                    mint,
+                    amount,
+                    interval,
+                    start,
+                    end,
+                    count,
+                    executed: 0,
+                };
+                Self::process_create_scheduled_payment(
+                    program_id,
+                    accounts,
+                    &schedule,
+                    &Rent::get()?,
+                )?;
+                WalletEvent::ScheduledPaymentCreated {
+                    wallet: *accounts[0].key,
+                    schedule: *accounts[1].key,
+                    recipient,
+                    amount,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::ExecuteScheduledPayment => {
+                msg!("Instruction: ExecuteScheduledPayment");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let schedule = Self::process_execute_scheduled_payment(
+                    program_id,
+                    accounts,
+                    Clock::get()?.unix_timestamp,
+                    &Rent::get()?,
+                    GuardContext {
+                        accounts,
+                        wallet_account: &wallet_account,
+                        signers: &[],
+                    },
+                )?;
+                Self::check_address_book(
+                    program_id,
+                    accounts,
+                    &wallet_account,
+                    &[schedule.recipient],
+                )?;
+                WalletEvent::ScheduledPaymentExecuted {
+                    wallet: *accounts[0].key,
+                    schedule: *accounts[1].key,
+                    recipient: schedule.recipient,
+                    amount: schedule.amount,
+                    executed: schedule.executed,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::CloseScheduledPayment => {
+                msg!("Instruction: CloseScheduledPayment");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_close_scheduled_payment(program_id, accounts)?;
+                WalletEvent::ScheduledPaymentClosed {
+                    wallet: *accounts[0].key,
+                    schedule: *accounts[1].key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::CreateStream {
+                id,
+                recipient,
+                mint,
+                total,
+                start,
+                cliff,
+                end,
+            } => {
+                msg!("Instruction: CreateStream");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                let stream = Stream {
+                    wallet: *accounts[0].key,
+                    id,
+                    recipient,
+                    mint,
+                    total,
+                    start,
+                    cliff,
+                    end,
+                    withdrawn: 0,
+                };
+                Self::process_create_stream(program_id, accounts, &stream, &Rent::get()?)?;
+                WalletEvent::StreamCreated {
+                    wallet: *accounts[0].key,
+                    stream: *accounts[1].key,
+                    recipient,
+                    total,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::WithdrawStream => {
+                msg!("Instruction: WithdrawStream");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let (destination, amount) = Self::process_withdraw_stream(
+                    program_id,
+                    accounts,
+                    Clock::get()?.unix_timestamp,
+                    &Rent::get()?,
+                    GuardContext {
+                        accounts,
+                        wallet_account: &wallet_account,
+                        signers: &[],
+                    },
+                )?;
+                Self::check_address_book(program_id, accounts, &wallet_account, &[destination])?;
+                WalletEvent::StreamWithdrawn {
+                    wallet: *accounts[0].key,
+                    stream: *accounts[1].key,
+                    destination,
+                    amount,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::CancelStream => {
+                msg!("Instruction: CancelStream");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                // owners can stop a stream in an emergency, the freeze keeps the vested amount
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                let amount = Self::process_cancel_stream(
+                    program_id,
+                    accounts,
+                    Clock::get()?.unix_timestamp,
+                    &Rent::get()?,
+                    wallet_account.state == AccountState::Frozen,
+                    GuardContext {
+                        accounts,
+                        wallet_account: &wallet_account,
+                        signers: &signers,
+                    },
+                )?;
+                WalletEvent::StreamCancelled {
+                    wallet: *accounts[0].key,
+                    stream: *accounts[1].key,
+                    amount,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::SetGuard { guard } => {
+                msg!("Instruction: SetGuard");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_set_guard(
+                    program_id,
+                    accounts,
+                    &mut wallet_account,
+                    guard,
+                    &signers,
+                )?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::GuardSet {
+                    wallet: *accounts[0].key,
+                    guard,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::ExecuteFromModule {
+                instruction: internal_instruction,
+            } => {
+                msg!("Instruction: ExecuteFromModule");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let module = Self::check_module(accounts, &wallet_account)?;
+                Self::check_nesting_depth(program_id, accounts, &internal_instruction)?;
+                let destinations = Self::outgoing_destinations(
+                    program_id,
+                    accounts[0].key,
+                    0,
+                    std::slice::from_ref(&internal_instruction),
+                    wallet_account.address_book,
+                )?;
+                Self::check_address_book(program_id, accounts, &wallet_account, &destinations)?;
+                let invoked_program_id = internal_instruction.program_id;
+                Self::call_guard(
+                    accounts,
+                    &wallet_account,
+                    accounts[0].key,
+                    GuardPhase::PreExecution,
+                    &internal_instruction,
+                    &[module],
+                )?;
+                Self::process_invoke(program_id, accounts, 0, internal_instruction.clone())?;
+                Self::call_guard(
+                    accounts,
+                    &wallet_account,
+                    accounts[0].key,
+                    GuardPhase::PostExecution,
+                    &internal_instruction,
+                    &[module],
+                )?;
+                WalletEvent::ModuleExecuted {
+                    wallet: *accounts[0].key,
+                    module,
+                    program_id: invoked_program_id,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::EnableModule { module } => {
+                msg!("Instruction: EnableModule");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_enable_module(program_id, &mut wallet_account, module)?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::ModuleEnabled {
+                    wallet: *accounts[0].key,
+                    module,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::DisableModule { module } => {
+                msg!("Instruction: DisableModule");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_disable_module(&mut wallet_account, module)?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::ModuleDisabled {
+                    wallet: *accounts[0].key,
+                    module,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::InvokeFromVault {
+                vault_index,
+                instruction: internal_instruction,
+            } => {
+                msg!("Instruction: InvokeFromVault");
+                let wallet_account = Self::load_wallet_header(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let signers = Self::check_packed_signatures(accounts)?;
+                Self::check_nesting_depth(program_id, accounts, &internal_instruction)?;
+                let destinations = Self::outgoing_destinations(
+                    program_id,
+                    accounts[0].key,
+                    vault_index,
+                    std::slice::from_ref(&internal_instruction),
+                    wallet_account.address_book,
+                )?;
+                Self::check_address_book(program_id, accounts, &wallet_account, &destinations)?;
+                let invoked_program_id = internal_instruction.program_id;
+                Self::call_guard(
+                    accounts,
+                    &wallet_account,
+                    accounts[0].key,
+                    GuardPhase::PreExecution,
+                    &internal_instruction,
+                    &signers,
+                )?;
+                Self::process_invoke(
+                    program_id,
+                    accounts,
+                    vault_index,
+                    internal_instruction.clone(),
+                )?;
+                Self::call_guard(
+                    accounts,
+                    &wallet_account,
+                    accounts[0].key,
+                    GuardPhase::PostExecution,
+                    &internal_instruction,
+                    &signers,
+                )?;
+                WalletEvent::VaultInvoked {
+                    wallet: *accounts[0].key,
+                    vault_index,
+                    program_id: invoked_program_id,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::InitHistory { capacity } => {
+                msg!("Instruction: InitHistory");
+                let mut wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_init_history(
+                    program_id,
+                    accounts,
+                    &mut wallet_account,
+                    capacity,
+                    &Rent::get()?,
+                )?;
+
+                Self::store_wallet_account(program_id, accounts, wallet_account)?;
+                WalletEvent::HistoryCreated {
+                    wallet: *accounts[0].key,
+                    capacity,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::Hello => {
+                msg!("Instruction: Hello");
+                let wallet_account = Self::load_wallet_header(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_packed_signatures(accounts)?;
+                Self::process_hello()
+            }
+            WalletInstruction::InitInstructionBuffer { vault_index } => {
+                msg!("Instruction: InitInstructionBuffer");
+                Self::process_init_instruction_buffer(program_id, accounts, vault_index)
+            }
+            WalletInstruction::AppendPartialInsturciton { offset, data } => {
+                msg!("Instruction: AppendPartialInsturciton");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_append_partial_instruction(
+                    program_id,
+                    accounts,
+                    &wallet_account,
+                    offset,
+                    data,
+                )
+            }
+            WalletInstruction::RunInstructionBuffer {
+                expected_instruction_count,
+                vault_index,
+            } => {
+                msg!("Instruction: RunInstructionBuffer");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                Self::process_run_insturction_buffer(
+                    program_id,
+                    accounts,
+                    &wallet_account,
+                    expected_instruction_count,
+                    vault_index,
+                )
+            }
+            WalletInstruction::CloseInstructionBuffer => {
+                msg!("Instruction: CloseInstructionBuffer");
+                Self::process_close_instruction_buffer(program_id, accounts)
+            }
+            WalletInstruction::ReclaimInstructionBuffer => {
+                msg!("Instruction: ReclaimInstructionBuffer");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_reclaim_instruction_buffer(program_id, accounts)?;
+                WalletEvent::BufferReclaimed {
+                    wallet: *accounts[0].key,
+                    instruction_buffer: *accounts[1].key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+            WalletInstruction::CloseWallet => {
+                msg!("Instruction: CloseWallet");
+                let wallet_account = Self::load_wallet_account(program_id, accounts)?;
+                if !wallet_account.is_initialized() {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                Self::check_not_frozen(&wallet_account)?;
+                let signers = Self::check_signatures(accounts, &wallet_account)?;
+                Self::process_close_wallet(program_id, accounts)?;
+                WalletEvent::WalletClosed {
+                    wallet: *accounts[0].key,
+                    signers,
+                }
+                .emit();
+                Ok(())
+            }
+        }?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::test_utils::{account_infos, new_wallet_with_vault, wallet_owned_by, TestAccount};
+    use maplit::btreemap;
+    use solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+    use std::{cell::RefCell, str::FromStr, sync::Once};
+
+    /// An invoked instruction with its signer seeds
+    type InvokedInstruction = (Instruction, Vec<Vec<Vec<u8>>>);
+
+    /// Runs in place of an invoked instruction, on top of the recording
+    type InvokeHook = fn(&Instruction, &[AccountInfo]) -> ProgramResult;
+
+    thread_local! {
+        static INVOKED_INSTRUCTIONS: RefCell<Vec<InvokedInstruction>> =
+            const { RefCell::new(Vec::new()) };
+        static INVOKE_HOOK: RefCell<Option<InvokeHook>> = const { RefCell::new(None) };
+    }
+
+    /// Records cross-program invocations of the current test thread instead of executing them
+    struct RecordingSyscallStubs;
+
+    impl SyscallStubs for RecordingSyscallStubs {
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            account_infos: &[AccountInfo],
+            signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            // like the runtime, refuse invocations whose accounts were not passed in
+            if let Some(missing) = instruction.accounts.iter().find(|meta| {
+                !account_infos
+                    .iter()
+                    .any(|account| *account.key == meta.pubkey)
+            }) {
+                msg!(&format!(
+                    "{} is missing from the account infos",
+                    missing.pubkey
+                ));
+                return Err(ProgramError::NotEnoughAccountKeys);
+            }
+            let signers_seeds = signers_seeds
+                .iter()
+                .map(|seeds| seeds.iter().map(|seed| seed.to_vec()).collect())
+                .collect();
+            INVOKED_INSTRUCTIONS.with(|invoked| {
+                invoked
+                    .borrow_mut()
+                    .push((instruction.clone(), signers_seeds))
+            });
+            if let Some(hook) = INVOKE_HOOK.with(|hook| *hook.borrow()) {
+                hook(instruction, account_infos)?;
+            }
+
+            // move the lamports of system transfers between the passed accounts
+            if instruction.program_id == system_program::id() {
+                if let Ok(SystemInstruction::Transfer { lamports }) =
+                    bincode::deserialize(&instruction.data)
+                {
+                    let find = |index: usize| {
+                        account_infos
+                            .iter()
+                            .find(|account| *account.key == instruction.accounts[index].pubkey)
+                    };
+                    if let (Some(from), Some(to)) = (find(0), find(1)) {
+                        if from.lamports() < lamports {
+                            return Err(ProgramError::InsufficientFunds);
+                        }
+                        **from.lamports.borrow_mut() -= lamports;
+                        **to.lamports.borrow_mut() += lamports;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn take_invoked_instructions() -> Vec<InvokedInstruction> {
+        INVOKED_INSTRUCTIONS.with(|invoked| invoked.borrow_mut().drain(..).collect())
+    }
+
+    fn set_invoke_hook(hook: InvokeHook) {
+        INVOKE_HOOK.with(|invoke_hook| *invoke_hook.borrow_mut() = Some(hook));
+    }
+
+    fn install_recording_syscall_stubs() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            set_syscall_stubs(Box::new(RecordingSyscallStubs));
+        });
+        take_invoked_instructions();
+        INVOKE_HOOK.with(|hook| hook.borrow_mut().take());
+    }
+
+    /// A guard context of the instruction accounts without owners approving
+    fn guard_context<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+        wallet_account: &'a Account,
+    ) -> GuardContext<'a, 'b> {
+        GuardContext {
+            accounts,
+            wallet_account,
+            signers: &[],
+        }
+    }
+
+    /// Guard program whose calls fail, see `assert_guard_blocks`
+    const REJECTING_GUARD: Pubkey = Pubkey::new_from_array([0x9a; 32]);
+
+    /// Check `invoke` calls the wallet guard before the vault signs anything, and a rejecting
+    /// guard stops it
+    fn assert_guard_blocks<F>(accounts: &[AccountInfo], invoke: F)
+    where
+        F: FnOnce(GuardContext) -> ProgramResult,
+    {
+        let wallet_account = Account {
+            state: AccountState::Initialized,
+            guard: Some(REJECTING_GUARD),
+            ..Account::default()
+        };
+        take_invoked_instructions();
+        set_invoke_hook(|instruction, _| {
+            if instruction.program_id == REJECTING_GUARD {
+                return Err(ProgramError::Custom(0x9a));
+            }
+            Ok(())
+        });
+
+        assert_eq!(
+            invoke(guard_context(accounts, &wallet_account)),
+            Err(ProgramError::Custom(0x9a))
+        );
+        let invoked = take_invoked_instructions();
+        assert_eq!(invoked.len(), 1);
+        assert_eq!(invoked[0].0.program_id, REJECTING_GUARD);
+        INVOKE_HOOK.with(|hook| hook.borrow_mut().take());
+    }
+
+    #[test]
+    fn freeze_should_block_transfers_until_unfrozen() {
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let owner1 = Pubkey::new_unique();
+        let owner2 = Pubkey::new_unique();
+        let guard = Pubkey::new_unique();
+        let wallet_account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 1,
+            freeze_authority: Some(guard),
+            freeze_weight: 400,
+            owners: btreemap! { owner1 => 600, owner2 => 400 },
+            max_owners: 2,
+            ..Account::default()
+        };
+        let wallet = TestAccount::wallet(wallet_key, program_id, &wallet_account);
+        let mut accounts = vec![
+            wallet,
+            TestAccount::new(vault_key, 100, solana_program::system_program::id()),
+            TestAccount::new(
+                Pubkey::new_unique(),
+                0,
+                solana_program::system_program::id(),
+            ),
+            TestAccount::program(solana_program::system_program::id()),
+        ];
+        for key in [owner1, owner2, guard] {
+            accounts.push(TestAccount::signer(key));
+        }
+        let mut accounts = account_infos(&mut accounts);
+        let state = |accounts: &[AccountInfo]| {
+            Account::unpack_from_slice(&accounts[0].data.borrow())
+                .unwrap()
+                .state
+        };
+
+        // the freeze weight is enough to freeze
+        accounts[4].is_signer = false;
+        accounts[6].is_signer = false;
+        assert_eq!(Processor::process(&program_id, &accounts, &[22]), Ok(()));
+        assert_eq!(state(&accounts), AccountState::Frozen);
+        assert_eq!(
+            Processor::process(&program_id, &accounts, &[12, 1, 0, 0, 0, 0, 0, 0, 0]),
+            Err(WalletError::WalletFrozen.into())
+        );
+
+        // but not to unfreeze
+        assert_eq!(
+            Processor::process(&program_id, &accounts, &[23]),
+            Err(WalletError::InsufficientWeight.into())
+        );
+        accounts[4].is_signer = true;
+        assert_eq!(Processor::process(&program_id, &accounts, &[23]), Ok(()));
+        assert_eq!(state(&accounts), AccountState::Initialized);
+
+        // the freeze authority freezes on its own
+        accounts[4].is_signer = false;
+        accounts[5].is_signer = false;
+        accounts[6].is_signer = true;
+        assert_eq!(Processor::process(&program_id, &accounts, &[22]), Ok(()));
+        assert_eq!(state(&accounts), AccountState::Frozen);
+    }
+
+    #[test]
+    fn should_fail_when_revoke_without_confirmation() {
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let wallet_account = wallet_owned_by(owner);
+        let wallet = TestAccount::wallet(wallet_key, program_id, &wallet_account);
+        let mut accounts = vec![wallet, TestAccount::signer(owner)];
+        let accounts = account_infos(&mut accounts);
+
+        let mut input = vec![4];
+        input.extend_from_slice(&Pubkey::new_unique().to_bytes());
+        assert_eq!(
+            Processor::process(&program_id, &accounts, &input),
+            Err(WalletError::InvalidInstruction.into())
+        );
+        assert_eq!(
+            Processor::process(&program_id, &accounts, &[4]),
+            Err(WalletError::InvalidInstruction.into())
+        );
+
+        let mut input = vec![4];
+        input.extend_from_slice(&wallet_key.to_bytes());
+        assert_eq!(Processor::process(&program_id, &accounts, &input), Ok(()));
+        assert!(Account::unpack_from_slice(&accounts[0].data.borrow())
+            .unwrap()
+            .owners
+            .is_empty());
+    }
+
+    #[test]
+    fn should_fail_when_init_with_key_weight_is_not_enough() {
+        let mut init_account = Account {
+            state: AccountState::Uninitialized,
+            owners: BTreeMap::new(),
+            max_owners: 101,
+            ..Account::default()
+        };
+        let init_keys = btreemap! {
+          Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1,
+          Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1,
+        };
+        let expected_account = Account {
+            state: AccountState::Uninitialized,
+            owners: BTreeMap::new(),
+            max_owners: 101,
+            ..Account::default()
+        };
+
+        assert_eq!(
+            Processor::process_initialize_wallet(&mut init_account, init_keys.clone()),
+            Err(WalletError::InsufficientWeight.into()),
+        );
+        assert_eq!(init_account, expected_account);
+    }
+
+    #[test]
+    fn process_initialize_wallet_should_success() {
+        let mut init_account = Account {
+            state: AccountState::Uninitialized,
+            owners: BTreeMap::new(),
+            max_owners: 101,
+            ..Account::default()
+        };
+        let init_keys = btreemap! {
+          Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 999,
+          Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1,
+        };
+
+        assert_eq!(
+            Processor::process_initialize_wallet(&mut init_account, init_keys.clone()),
+            Ok(()),
+        );
+        assert_eq!(
+            init_account,
+            Account {
+                state: AccountState::Initialized,
+                owner_epoch: 1,
+                owners: init_keys.clone(),
+                max_owners: 101,
+                ..Account::default()
+            },
+        );
+    }
+
+    #[test]
+    fn check_signatures_should_not_overflow_with_extreme_weights() {
+        let program_id = Pubkey::new_unique();
+        let keys: Vec<Pubkey> = (0..101).map(|_| Pubkey::new_unique()).collect();
+        let wallet_account = Account {
+            state: AccountState::Initialized,
+            owners: keys.iter().map(|key| (*key, u32::MAX)).collect(),
+            max_owners: 101,
+            ..Account::default()
+        };
+        assert_eq!(
+            Processor::is_key_weight_enough(&wallet_account.owners),
+            Ok(())
+        );
+        assert_eq!(
+            Processor::is_key_weight_enough(&btreemap! { keys[0] => 0, keys[1] => 0 }),
+            Err(WalletError::InsufficientWeight.into())
+        );
+
+        let wallet = TestAccount::wallet(Pubkey::new_unique(), program_id, &wallet_account);
+        let mut accounts = vec![wallet];
+        accounts.extend(keys.iter().map(|key| TestAccount::signer(*key)));
+        let accounts = account_infos(&mut accounts);
+
+        let mut sorted_keys = keys.clone();
+        sorted_keys.sort();
+        assert_eq!(
+            Processor::check_signatures(&accounts, &wallet_account),
+            Ok(sorted_keys)
+        );
+        assert_eq!(
+            Processor::check_packed_signatures(&accounts),
+            Processor::check_signatures(&accounts, &wallet_account)
+        );
+    }
+
+    #[test]
+    fn process_add_owner_should_success() {
+        let init_account = Account {
+            state: AccountState::Initialized,
+            owners: btreemap! {
+              Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000,
+            },
+            max_owners: 101,
+            ..Account::default()
+        };
+
+        let mut wallet_data = vec![0; Account::HEADER_LEN + 101 * Account::OWNER_LEN];
+        init_account.pack_into_slice(&mut wallet_data).unwrap();
+
+        let add_keys = btreemap! {Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1};
+        assert_eq!(
+            Processor::process_add_owner(&mut wallet_data, add_keys),
+            Ok(())
+        );
+
+        let expected_account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 1,
+            owners: btreemap! {
+              Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000,
+              Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1
+            },
+            max_owners: 101,
+            ..Account::default()
+        };
+        assert_eq!(
+            Account::unpack_from_slice(&wallet_data).unwrap(),
+            expected_account
+        );
+    }
+
+    #[test]
+    fn unpack_should_read_u32_weights() {
+        let owner = Pubkey::new_unique();
+        let mut input = vec![0];
+        input.extend_from_slice(owner.as_ref());
+        input.extend_from_slice(&100_000u32.to_le_bytes());
+        assert_eq!(
+            WalletInstruction::unpack(&input, &[]),
+            Ok(WalletInstruction::AddOwner {
+                owners: btreemap! { owner => 100_000 },
+            })
+        );
+
+        let mut freeze_input = vec![24];
+        freeze_input.extend_from_slice(owner.as_ref());
+        freeze_input.extend_from_slice(&70_000u32.to_le_bytes());
+        assert_eq!(
+            WalletInstruction::unpack(&freeze_input, &[]),
+            Ok(WalletInstruction::SetFreezeConfig {
+                freeze_authority: Some(owner),
+                freeze_weight: 70_000,
+            })
+        );
+        // a u16 weight is too short
+        freeze_input.truncate(freeze_input.len() - 2);
+        assert_eq!(
+            WalletInstruction::unpack(&freeze_input, &[]),
+            Err(WalletError::InvalidInstruction.into())
+        );
+    }
+
+    #[test]
+    fn process_update_owners_should_apply_diff_at_once() {
+        let kept = Pubkey::new_unique();
+        let removed = Pubkey::new_unique();
+        let added = Pubkey::new_unique();
+        let mut wallet_account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 1,
+            owners: btreemap! { kept => 500, removed => 500 },
+            max_owners: 101,
+            ..Account::default()
+        };
+
+        // the intermediate states drop below the threshold, only the final set counts
+        assert_eq!(
+            Processor::process_update_owners(
+                &mut wallet_account,
+                btreemap! { added => 400 },
+                vec![removed],
+                btreemap! { kept => 600 },
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            wallet_account,
+            Account {
+                state: AccountState::Initialized,
+                owner_epoch: 2,
+                owners: btreemap! { kept => 600, added => 400 },
+                max_owners: 101,
+                ..Account::default()
+            }
+        );
+    }
+
+    #[test]
+    fn should_fail_when_update_owners_is_invalid() {
+        let first = Pubkey::new_unique();
+        let second = Pubkey::new_unique();
+        let stranger = Pubkey::new_unique();
+        let wallet_account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 1,
+            owners: btreemap! { first => 500, second => 500 },
+            max_owners: 2,
+            ..Account::default()
+        };
+
+        let cases = vec![
+            // final set below the threshold
+            (
+                btreemap! {},
+                vec![],
+                btreemap! { first => 499 },
+                WalletError::InsufficientWeight,
+            ),
+            // owner touched twice
+            (
+                btreemap! {},
+                vec![first],
+                btreemap! { first => 1000 },
+                WalletError::InvalidInstruction,
+            ),
+            // remove or change a non-owner
+            (
+                btreemap! {},
+                vec![stranger],
+                btreemap! {},
+                WalletError::OwnerNotFound,
+            ),
+            (
+                btreemap! {},
+                vec![],
+                btreemap! { stranger => 1 },
+                WalletError::OwnerNotFound,
+            ),
+            // add an existing owner or a zero weight
+            (
+                btreemap! { first => 1 },
+                vec![],
+                btreemap! {},
+                WalletError::OwnerAlreadyExists,
+            ),
+            (
+                btreemap! { stranger => 0 },
+                vec![],
+                btreemap! {},
+                WalletError::ZeroKeyWeight,
+            ),
+            // too many owners
+            (
+                btreemap! { stranger => 1 },
+                vec![],
+                btreemap! {},
+                WalletError::TooManyOwners,
+            ),
+        ];
+        for (adds, removes, weight_changes, error) in cases {
+            let mut account = wallet_account.clone();
+            assert_eq!(
+                Processor::process_update_owners(&mut account, adds, removes, weight_changes),
+                Err(error.into())
+            );
+            assert_eq!(account, wallet_account);
+        }
+    }
+
+    #[test]
+    fn process_rotate_owner_key_should_keep_weight() {
+        let old_key = Pubkey::new_unique();
+        let new_key = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let mut wallet_account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 1,
+            owners: btreemap! { old_key => 400, other => 600 },
+            max_owners: 101,
+            ..Account::default()
+        };
+
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, other, old_key, 0),
+            Err(WalletError::InvalidInstruction.into())
+        );
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, new_key, other, 0),
+            Err(WalletError::InvalidOwner.into())
+        );
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, old_key, new_key, 0),
+            Ok(true)
+        );
+        assert_eq!(
+            wallet_account.owners,
+            btreemap! { new_key => 400, other => 600 }
+        );
+        assert_eq!(wallet_account.owner_epoch, 2);
+    }
+
+    #[test]
+    fn process_rotate_owner_key_should_wait_for_delay() {
+        let old_key = Pubkey::new_unique();
+        let new_key = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let mut wallet_account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 1,
+            rotation_delay: 100,
+            owners: btreemap! { old_key => 400, other => 600 },
+            max_owners: 101,
+            ..Account::default()
+        };
+        let pending_rotation = KeyRotation {
+            old_key,
+            new_key,
+            ready_at: 1100,
+        };
+
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, old_key, new_key, 1000),
+            Ok(false)
+        );
+        assert_eq!(wallet_account.pending_rotation, Some(pending_rotation));
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, old_key, new_key, 1099),
+            Err(WalletError::KeyRotationPending.into())
+        );
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, other, new_key, 1100),
+            Err(WalletError::KeyRotationConflict.into())
+        );
+        assert_eq!(
+            Processor::process_cancel_key_rotation(&mut wallet_account.clone()),
+            Ok(pending_rotation)
+        );
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, old_key, new_key, 1100),
+            Ok(true)
+        );
+        assert_eq!(
+            wallet_account.owners,
+            btreemap! { new_key => 400, other => 600 }
+        );
+        assert_eq!(wallet_account.pending_rotation, None);
+        assert_eq!(
+            Processor::process_cancel_key_rotation(&mut wallet_account),
+            Err(WalletError::InvalidState.into())
+        );
+    }
+
+    #[test]
+    fn process_rotate_owner_key_should_supersede_expired_rotation() {
+        let old_key = Pubkey::new_unique();
+        let new_key = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let other_new_key = Pubkey::new_unique();
+        let mut wallet_account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 1,
+            rotation_delay: 100,
+            pending_rotation: Some(KeyRotation {
+                old_key,
+                new_key,
+                ready_at: 1100,
+            }),
+            owners: btreemap! { old_key => 400, other => 600 },
+            max_owners: 101,
+            ..Account::default()
+        };
+
+        // the pending owner has a whole delay after it became ready to complete it
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, other, other_new_key, 1199),
+            Err(WalletError::KeyRotationConflict.into())
+        );
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, other, other_new_key, 1200),
+            Ok(false)
+        );
+        assert_eq!(
+            wallet_account.pending_rotation,
+            Some(KeyRotation {
+                old_key: other,
+                new_key: other_new_key,
+                ready_at: 1300,
+            })
+        );
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, old_key, new_key, 1200),
+            Err(WalletError::KeyRotationConflict.into())
+        );
+        assert_eq!(
+            Processor::process_rotate_owner_key(&mut wallet_account, other, other_new_key, 1300),
+            Ok(true)
+        );
+        assert_eq!(
+            wallet_account.owners,
+            btreemap! { old_key => 400, other_new_key => 600 }
+        );
+    }
+
+    #[test]
+    fn should_fail_when_recovery_with_key_weight_is_not_enough() {
+        let mut wallet_account = Account {
+            state: AccountState::Initialized,
+            owners: btreemap! {Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000},
+            max_owners: 101,
+            ..Account::default()
+        };
+        let recovery_keys = btreemap! {
+          Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1,
+        };
+        assert_eq!(
+            Processor::process_initialize_wallet(&mut wallet_account, recovery_keys),
+            Err(WalletError::InsufficientWeight.into()),
+        );
+    }
+
+    #[test]
+    fn process_recovery_should_success() {
+        let mut wallet_account = Account {
+            state: AccountState::Initialized,
+            owners: btreemap! {Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000},
+            max_owners: 101,
+            ..Account::default()
+        };
+        let recovery_keys = btreemap! {Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1000};
+        assert_eq!(
+            Processor::process_recovery(&mut wallet_account, recovery_keys.clone()),
+            Ok(())
+        );
+
+        let expected_account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 1,
+            owners: recovery_keys.clone(),
+            max_owners: 101,
+            ..Account::default()
+        };
+        assert_eq!(wallet_account, expected_account);
+    }
+
+    #[test]
+    fn process_revoke_should_success() {
+        let mut wallet_account = Account {
+            state: AccountState::Initialized,
+            owners: btreemap! {Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000},
+            max_owners: 101,
+            ..Account::default()
+        };
+        assert_eq!(Processor::process_revoke(&mut wallet_account), Ok(()));
+
+        let expected_account = Account {
+            state: AccountState::Initialized,
+            owner_epoch: 1,
+            owners: btreemap! {},
+            max_owners: 101,
+            ..Account::default()
+        };
+        assert_eq!(wallet_account, expected_account);
+    }
+
+    #[test]
+    fn process_init_instruction_buffer_should_record_owner_epoch() {
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let wallet_account = Account {
+            owner_epoch: 3,
+            ..wallet_owned_by(owner)
+        };
+
+        let mut buffer = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        buffer.data = vec![0; 128];
+        let mut accounts = vec![
+            buffer,
+            TestAccount::signer(owner),
+            TestAccount::wallet(
+                wallet_key,
+                program_id,
+                &Account {
+                    state: AccountState::Uninitialized,
+                    ..wallet_account.clone()
+                },
+            ),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_eq!(
+            Processor::process_init_instruction_buffer(&program_id, &accounts, 2),
+            Err(ProgramError::UninitializedAccount)
+        );
+
+        wallet_account
+            .pack_into_slice(&mut accounts[2].data.borrow_mut())
+            .unwrap();
+        assert_eq!(
+            Processor::process_init_instruction_buffer(&program_id, &accounts, 2),
+            Ok(())
+        );
+        let buffer = InstructionBuffer::unpack(&accounts[0].data.borrow()).unwrap();
+        assert_eq!(
+            (
+                buffer.owner,
+                buffer.wallet,
+                buffer.owner_epoch,
+                buffer.vault_index
+            ),
+            (owner, wallet_key, 3, 2)
+        );
+
+        // the buffer only runs with the vault it was initialized for
+        let run_accounts = [
+            accounts[2].clone(),
+            accounts[0].clone(),
+            accounts[1].clone(),
+        ];
+        assert_eq!(
+            Processor::process_run_insturction_buffer(
+                &program_id,
+                &run_accounts,
+                &wallet_account,
+                0,
+                0
+            ),
+            Err(WalletError::InstructionBufferVaultMismatch.into())
+        );
+
+        // legacy wallets have no owner epoch to bind the buffer to
+        let legacy_account = Account {
+            layout: AccountLayout::Legacy,
+            owner_epoch: 0,
+            ..wallet_account.clone()
+        };
+        let mut legacy_wallet = [TestAccount::wallet(wallet_key, program_id, &legacy_account)];
+        let legacy_wallet = account_infos(&mut legacy_wallet).remove(0);
+        assert_eq!(
+            Processor::process_init_instruction_buffer(
+                &program_id,
+                &[
+                    accounts[0].clone(),
+                    accounts[1].clone(),
+                    legacy_wallet.clone()
+                ],
+                0
+            ),
+            Err(WalletError::UnsupportedLayout.into())
+        );
+        assert_eq!(
+            Processor::process_run_insturction_buffer(
+                &program_id,
+                &[legacy_wallet, accounts[0].clone(), accounts[1].clone()],
+                &legacy_account,
+                0,
+                0
+            ),
+            Err(WalletError::UnsupportedLayout.into())
+        );
+
+        // once the owner set changed, the buffer can no longer be staged
+        let append_accounts = vec![
+            accounts[2].clone(),
+            accounts[0].clone(),
+            accounts[1].clone(),
+        ];
+        assert_eq!(
+            Processor::process_append_partial_instruction(
+                &program_id,
+                &append_accounts,
+                &Account {
+                    owner_epoch: 4,
+                    ..wallet_account.clone()
+                },
+                0,
+                vec![1]
+            ),
+            Err(WalletError::StaleInstructionBuffer.into())
+        );
+        assert_eq!(
+            Processor::process_append_partial_instruction(
+                &program_id,
+                &append_accounts,
+                &wallet_account,
+                0,
+                vec![1]
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn process_reclaim_instruction_buffer_should_success() {
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let buffer_key = Pubkey::new_unique();
+        let system_program_id = system_program::id();
+
+        let mut buffer_data = vec![0x00; 128];
+        InstructionBuffer::pack(
+            InstructionBuffer {
+                owner: Pubkey::new_unique(),
+                wallet: wallet_key,
+                owner_epoch: 1,
+                vault_index: 0,
+                data: vec![],
+            },
+            &mut buffer_data,
+        )
+        .unwrap();
+
+        let mut buffer = TestAccount::new(buffer_key, 42, program_id);
+        buffer.data = buffer_data;
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            buffer,
+            TestAccount::new(vault_key, 100, system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_reclaim_instruction_buffer(&program_id, &accounts),
+            Ok(())
+        );
+        assert_eq!(accounts[1].lamports(), 0);
+        assert_eq!(accounts[2].lamports(), 142);
+    }
+
+    #[test]
+    fn should_fail_when_reclaim_instruction_buffer_of_another_wallet() {
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let buffer_key = Pubkey::new_unique();
+        let vault_key = Pubkey::new_unique();
+
+        let mut buffer_data = vec![0x00; 128];
+        InstructionBuffer::pack(
+            InstructionBuffer {
+                owner: Pubkey::new_unique(),
+                wallet: Pubkey::new_unique(),
+                owner_epoch: 1,
+                vault_index: 0,
+                data: vec![],
+            },
+            &mut buffer_data,
+        )
+        .unwrap();
+
+        let mut buffer = TestAccount::new(buffer_key, 42, program_id);
+        buffer.data = buffer_data;
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            buffer,
+            TestAccount::new(vault_key, 100, program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_reclaim_instruction_buffer(&program_id, &accounts),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(accounts[1].lamports(), 42);
+    }
+
+    #[test]
+    fn close_account_should_wipe_data() {
+        let program_id = crate::id();
+        let account_key = Pubkey::new_unique();
+        let destination_key = Pubkey::new_unique();
+
+        let mut account = TestAccount::new(account_key, 42, program_id);
+        account.data = vec![0x01; 128];
+        let mut accounts = vec![account, TestAccount::new(destination_key, 100, program_id)];
+        let accounts = account_infos(&mut accounts);
+        let (account_info, destination_account_info) = (&accounts[0], &accounts[1]);
+
+        assert_eq!(
+            Processor::close_account(account_info, destination_account_info),
+            Ok(())
+        );
+        assert_eq!(account_info.lamports(), 0);
+        assert_eq!(destination_account_info.lamports(), 142);
+        assert_eq!(
+            account_info.data.borrow()[..8],
+            CLOSED_ACCOUNT_DISCRIMINATOR[..]
+        );
+        assert!(account_info.data.borrow()[8..].iter().all(|b| *b == 0));
+        assert_eq!(
+            InstructionBuffer::unpack(&account_info.data.borrow()),
+            Err(WalletError::AccountClosed.into())
+        );
+    }
+
+    #[test]
+    fn should_fail_when_close_account_into_itself() {
+        let program_id = crate::id();
+        let account_key = Pubkey::new_unique();
+
+        let mut account = TestAccount::new(account_key, 42, program_id);
+        account.data = vec![0x01; 128];
+        let mut accounts = vec![account];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::close_account(&accounts[0], &accounts[0].clone()),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(accounts[0].lamports(), 42);
+    }
+
+    #[test]
+    fn should_fail_when_close_wallet_with_funded_vault() {
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let destination_key = Pubkey::new_unique();
+        let system_program_id = system_program::id();
+
+        let mut wallet = TestAccount::new(wallet_key, 42, program_id);
+        wallet.data = vec![0x01; 43];
+        let mut accounts = vec![
+            wallet,
+            TestAccount::new(vault_key, 1, system_program_id),
+            TestAccount::new(destination_key, 0, system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_close_wallet(&program_id, &accounts),
+            Err(WalletError::VaultNotEmpty.into())
+        );
+
+        **accounts[1].lamports.borrow_mut() = 0;
+        assert_eq!(
+            Processor::process_close_wallet(&program_id, &accounts),
+            Ok(())
+        );
+        assert_eq!(accounts[0].lamports(), 0);
+        assert_eq!(accounts[2].lamports(), 42);
+    }
+
+    #[test]
+    fn should_fail_when_close_wallet_with_funded_vault_token_account() {
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let token_account = |owner: Pubkey, amount: u64| {
+            let mut account = TestAccount::new(Pubkey::new_unique(), 0, spl_token::id());
+            account.data = vec![0; TokenAccount::LEN];
+            TokenAccount::pack(
+                TokenAccount {
+                    owner,
+                    amount,
+                    state: spl_token::state::AccountState::Initialized,
+                    ..TokenAccount::default()
+                },
+                &mut account.data,
+            )
+            .unwrap();
+            account
+        };
+
+        let mut wallet = TestAccount::new(wallet_key, 42, program_id);
+        wallet.data = vec![0x01; 43];
+        let mut accounts = vec![
+            wallet,
+            TestAccount::new(vault_key, 0, system_program::id()),
+            TestAccount::new(Pubkey::new_unique(), 0, system_program::id()),
+            token_account(vault_key, 0),
+            token_account(vault_key, 1),
+            token_account(Pubkey::new_unique(), 0),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_close_wallet(&program_id, &accounts[..5]),
+            Err(WalletError::VaultNotEmpty.into())
+        );
+        let mut other_accounts = accounts[..4].to_vec();
+        other_accounts.push(accounts[5].clone());
+        assert_eq!(
+            Processor::process_close_wallet(&program_id, &other_accounts),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(
+            Processor::process_close_wallet(&program_id, &accounts[..4]),
+            Ok(())
+        );
+        assert_eq!(accounts[2].lamports(), 42);
+    }
+
+    #[test]
+    fn process_transfer_lamports_should_check_vault_balance() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let destination_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let rent = Rent::default();
+        let rent_exempt_minimum = rent.minimum_balance(0);
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(vault_key, rent_exempt_minimum + 100, system_program_id),
+            TestAccount::new(destination_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        // more than the vault holds
+        assert_eq!(
+            Processor::process_transfer_lamports(
+                &program_id,
+                &accounts,
+                rent_exempt_minimum + 101,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(WalletError::InsufficientFunds.into())
+        );
+        // leaves the vault below the rent-exempt minimum
+        assert_eq!(
+            Processor::process_transfer_lamports(
+                &program_id,
+                &accounts,
+                101,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(WalletError::NotRentExempt.into())
+        );
+        assert_eq!(take_invoked_instructions(), vec![]);
+        // keeps the vault rent-exempt
+        assert_eq!(
+            Processor::process_transfer_lamports(
+                &program_id,
+                &accounts,
+                100,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            (accounts[1].lamports(), accounts[2].lamports()),
+            (rent_exempt_minimum, 100)
+        );
+        // drains the vault
+        assert_eq!(
+            Processor::process_transfer_lamports(
+                &program_id,
+                &accounts,
+                rent_exempt_minimum,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            (accounts[1].lamports(), accounts[2].lamports()),
+            (0, rent_exempt_minimum + 100)
+        );
+
+        let (_, seeds) = Processor::vault_address_and_seeds(&program_id, &wallet_key, 0).unwrap();
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![
+                (
+                    system_instruction::transfer(&vault_key, &destination_key, 100),
+                    vec![seeds.clone()]
+                ),
+                (
+                    system_instruction::transfer(&vault_key, &destination_key, rent_exempt_minimum),
+                    vec![seeds]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_fail_when_transfer_lamports_from_wrong_vault() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, _) = new_wallet_with_vault(&program_id);
+        let vault_key = Pubkey::new_unique();
+        let destination_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(vault_key, 100, system_program_id),
+            TestAccount::new(destination_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_transfer_lamports(
+                &program_id,
+                &accounts,
+                1,
+                &Rent::default(),
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(ProgramError::InvalidSeeds)
+        );
+        assert_eq!(take_invoked_instructions(), vec![]);
+        assert_eq!(accounts[1].lamports(), 100);
+    }
+
+    #[test]
+    fn process_token_transfer_should_sign_with_vault() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let source_key = Pubkey::new_unique();
+        let mint_key = Pubkey::new_unique();
+        let destination_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(vault_key, 0, system_program_id),
+            TestAccount::new(source_key, 0, spl_token::id()),
+            TestAccount::new(mint_key, 0, spl_token::id()),
+            TestAccount::new(destination_key, 0, spl_token::id()),
+            TestAccount::program(spl_token::id()),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_token_transfer(
+                &program_id,
+                &accounts,
+                42,
+                6,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![(
+                spl_token::instruction::transfer_checked(
+                    &spl_token::id(),
+                    &source_key,
+                    &mint_key,
+                    &destination_key,
+                    &vault_key,
+                    &[],
+                    42,
+                    6,
+                )
+                .unwrap(),
+                vec![vec![wallet_key.to_bytes().to_vec()]],
+            )]
+        );
+    }
+
+    #[test]
+    fn process_reimbursement_should_pay_relayer_within_cap() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let relayer_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let rent = Rent::default();
+        let mut wallet_account = Account {
+            state: AccountState::Initialized,
+            reimbursement_cap: 5000,
+            ..Account::default()
+        };
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(Pubkey::new_unique(), 0, system_program_id),
+            TestAccount::new(vault_key, rent.minimum_balance(0) + 5000, system_program_id),
+            TestAccount::new(relayer_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_reimbursement(
+                &program_id,
+                &accounts,
+                &wallet_account,
+                &[],
+                5001,
+                &rent
+            ),
+            Err(WalletError::ReimbursementCapExceeded.into())
+        );
+        assert_eq!(
+            Processor::process_reimbursement(
+                &program_id,
+                &accounts,
+                &wallet_account,
+                &[],
+                5000,
+                &rent
+            ),
+            Ok(relayer_key)
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![(
+                system_instruction::transfer(&vault_key, &relayer_key, 5000),
+                vec![vec![wallet_key.to_bytes().to_vec()]],
+            )]
+        );
+
+        // a zero cap disables reimbursement
+        wallet_account.reimbursement_cap = 0;
+        assert_eq!(
+            Processor::process_reimbursement(
+                &program_id,
+                &accounts,
+                &wallet_account,
+                &[],
+                0,
+                &rent
+            ),
+            Err(WalletError::ReimbursementCapExceeded.into())
+        );
+    }
+
+    #[test]
+    fn process_reimbursement_should_pay_relayer_in_configured_token() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let source_key = Pubkey::new_unique();
+        let mint_key = Pubkey::new_unique();
+        let relayer_token_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let wallet_account = Account {
+            state: AccountState::Initialized,
+            reimbursement_mint: Some(mint_key),
+            reimbursement_cap: 100,
+            ..Account::default()
+        };
+
+        let mut mint = TestAccount::new(mint_key, 0, spl_token::id());
+        mint.data = vec![0; Mint::LEN];
+        Mint::pack(
+            Mint {
+                decimals: 6,
+                is_initialized: true,
+                ..Mint::default()
+            },
+            &mut mint.data,
+        )
+        .unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(vault_key, 0, system_program_id),
+            TestAccount::new(source_key, 0, spl_token::id()),
+            mint,
+            TestAccount::new(relayer_token_key, 0, spl_token::id()),
+            TestAccount::program(spl_token::id()),
+        ];
+        let accounts = account_infos(&mut accounts);
+        let rent = Rent::default();
+
+        assert_eq!(
+            Processor::process_reimbursement(
+                &program_id,
+                &accounts,
+                &wallet_account,
+                &[],
+                100,
+                &rent
+            ),
+            Ok(relayer_token_key)
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![(
+                spl_token::instruction::transfer_checked(
+                    &spl_token::id(),
+                    &source_key,
+                    &mint_key,
+                    &relayer_token_key,
+                    &vault_key,
+                    &[],
+                    100,
+                    6,
+                )
+                .unwrap(),
+                vec![vec![wallet_key.to_bytes().to_vec()]],
+            )]
+        );
+
+        // only the configured mint is reimbursed
+        let other_mint = Account {
+            reimbursement_mint: Some(Pubkey::new_unique()),
+            ..wallet_account
+        };
+        assert_eq!(
+            Processor::process_reimbursement(&program_id, &accounts, &other_mint, &[], 100, &rent),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn process_execute_scheduled_payment_should_pay_once_per_interval() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let recipient_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let rent = Rent::default();
+        let schedule = ScheduledPayment {
+            wallet: wallet_key,
+            id: 7,
+            recipient: recipient_key,
+            mint: None,
+            amount: 1_000,
+            interval: 86_400,
+            start: 1_700_000_000,
+            end: 0,
+            count: 0,
+            executed: 0,
+        };
+
+        let mut schedule_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        schedule_account.data = vec![0; ScheduledPayment::LEN];
+        schedule.pack(&mut schedule_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            schedule_account,
+            TestAccount::new(
+                vault_key,
+                rent.minimum_balance(0) + 10_000,
+                system_program_id,
+            ),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_execute_scheduled_payment(
+                &program_id,
+                &accounts,
+                1_699_999_999,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(WalletError::PaymentNotDue.into())
+        );
+        assert_eq!(
+            Processor::process_execute_scheduled_payment(
+                &program_id,
+                &accounts,
+                1_700_000_000,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok(ScheduledPayment {
+                executed: 1,
+                ..schedule.clone()
+            })
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![(
+                system_instruction::transfer(&vault_key, &recipient_key, 1_000),
+                vec![vec![wallet_key.to_bytes().to_vec()]],
+            )]
+        );
+        // the recorded execution blocks a second payment in the same interval
+        assert_eq!(
+            Processor::process_execute_scheduled_payment(
+                &program_id,
+                &accounts,
+                1_700_000_001,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(WalletError::PaymentNotDue.into())
+        );
+
+        // owners can close the schedule
+        assert_eq!(
+            Processor::process_close_scheduled_payment(&program_id, &accounts[..4]),
+            Ok(())
+        );
+        assert_eq!(
+            Processor::process_execute_scheduled_payment(
+                &program_id,
+                &accounts,
+                1_800_000_000,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(WalletError::AccountClosed.into())
+        );
+    }
+
+    #[test]
+    fn check_payment_recipient_should_accept_token_accounts_it_owns() {
+        let recipient = Pubkey::new_unique();
+        let mint = Some(Pubkey::new_unique());
+        let token_account = |key: Pubkey, owner: Pubkey| {
+            let mut account = TestAccount::new(key, 0, spl_token::id());
+            account.data = vec![0; TokenAccount::LEN];
+            TokenAccount::pack(
+                TokenAccount {
+                    owner,
+                    state: spl_token::state::AccountState::Initialized,
+                    ..TokenAccount::default()
+                },
+                &mut account.data,
+            )
+            .unwrap();
+            account
+        };
+        let payment_accounts = |destination: TestAccount| {
+            vec![
+                TestAccount::new(Pubkey::new_unique(), 0, crate::id()),
+                TestAccount::new(Pubkey::new_unique(), 0, system_program::id()),
+                TestAccount::new(Pubkey::new_unique(), 0, spl_token::id()),
+                TestAccount::new(Pubkey::new_unique(), 0, spl_token::id()),
+                destination,
+            ]
+        };
+
+        let mut accounts = payment_accounts(token_account(Pubkey::new_unique(), recipient));
+        assert_eq!(
+            Processor::check_payment_recipient(&account_infos(&mut accounts), mint, &recipient),
+            Ok(())
+        );
+        // schedules naming the token account itself keep paying it
+        let legacy_recipient = Pubkey::new_unique();
+        let mut accounts = payment_accounts(token_account(legacy_recipient, recipient));
+        assert_eq!(
+            Processor::check_payment_recipient(
+                &account_infos(&mut accounts),
+                mint,
+                &legacy_recipient
+            ),
+            Ok(())
+        );
+        let mut accounts =
+            payment_accounts(token_account(Pubkey::new_unique(), Pubkey::new_unique()));
+        assert_eq!(
+            Processor::check_payment_recipient(&account_infos(&mut accounts), mint, &recipient),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn should_fail_when_execute_scheduled_payment_to_another_recipient() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let system_program_id = solana_program::system_program::id();
+        let rent = Rent::default();
+        let schedule = ScheduledPayment {
+            wallet: wallet_key,
+            recipient: Pubkey::new_unique(),
+            amount: 1_000,
+            interval: 60,
+            ..ScheduledPayment::default()
+        };
+
+        let mut schedule_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        schedule_account.data = vec![0; ScheduledPayment::LEN];
+        schedule.pack(&mut schedule_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            schedule_account,
+            TestAccount::new(
+                vault_key,
+                rent.minimum_balance(0) + 10_000,
+                system_program_id,
+            ),
+            TestAccount::new(Pubkey::new_unique(), 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_execute_scheduled_payment(
+                &program_id,
+                &accounts,
+                0,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(take_invoked_instructions(), vec![]);
+    }
+
+    #[test]
+    fn process_withdraw_stream_should_pay_vested_amount() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let recipient_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let rent = Rent::default();
+        let stream = Stream {
+            wallet: wallet_key,
+            id: 1,
+            recipient: recipient_key,
+            mint: None,
+            total: 1_000,
+            start: 1_000,
+            cliff: 1_100,
+            end: 2_000,
+            withdrawn: 0,
+        };
+
+        let mut stream_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        stream_account.data = vec![0; Stream::LEN];
+        stream.pack(&mut stream_account.data).unwrap();
+        let recipient = TestAccount::signer(recipient_key);
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            stream_account,
+            recipient,
+            TestAccount::new(
+                vault_key,
+                rent.minimum_balance(0) + 1_000,
+                system_program_id,
+            ),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        // nothing before the cliff
+        assert_eq!(
+            Processor::process_withdraw_stream(
+                &program_id,
+                &accounts,
+                1_099,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(WalletError::NothingVested.into())
+        );
+        assert_eq!(
+            Processor::process_withdraw_stream(
+                &program_id,
+                &accounts,
+                1_250,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok((recipient_key, 250))
+        );
+        assert_eq!(
+            Processor::process_withdraw_stream(
+                &program_id,
+                &accounts,
+                1_500,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok((recipient_key, 250))
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![
+                (
+                    system_instruction::transfer(&vault_key, &recipient_key, 250),
+                    vec![vec![wallet_key.to_bytes().to_vec()]],
+                ),
+                (
+                    system_instruction::transfer(&vault_key, &recipient_key, 250),
+                    vec![vec![wallet_key.to_bytes().to_vec()]],
+                ),
+            ]
+        );
+
+        // cancelling pays what vested since and keeps the rest in the vault
+        let mut cancel_accounts = accounts.clone();
+        cancel_accounts[2] = accounts[4].clone();
+        assert_eq!(
+            Processor::process_cancel_stream(
+                &program_id,
+                &cancel_accounts,
+                1_600,
+                &rent,
+                false,
+                guard_context(&cancel_accounts, &Account::default())
+            ),
+            Ok(100)
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![(
+                system_instruction::transfer(&vault_key, &recipient_key, 100),
+                vec![vec![wallet_key.to_bytes().to_vec()]],
+            )]
+        );
+        assert_eq!(
+            Processor::process_withdraw_stream(
+                &program_id,
+                &accounts,
+                2_000,
+                &rent,
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(WalletError::AccountClosed.into())
+        );
+    }
+
+    #[test]
+    fn process_cancel_stream_should_not_pay_while_frozen() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let recipient_key = Pubkey::new_unique();
+        let destination_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let rent = Rent::default();
+        let stream = Stream {
+            wallet: wallet_key,
+            id: 1,
+            recipient: recipient_key,
+            total: 1_000,
+            start: 1_000,
+            end: 2_000,
+            ..Stream::default()
+        };
+
+        let mut stream_account = TestAccount::new(Pubkey::new_unique(), 10, program_id);
+        stream_account.data = vec![0; Stream::LEN];
+        stream.pack(&mut stream_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            stream_account,
+            TestAccount::new(destination_key, 0, system_program_id),
+            TestAccount::new(vault_key, 1_000, system_program_id),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_cancel_stream(
+                &program_id,
+                &accounts,
+                1_500,
+                &rent,
+                true,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok(0)
+        );
+        assert_eq!(take_invoked_instructions(), vec![]);
+        assert_eq!(accounts[3].lamports(), 1_000);
+        assert_eq!(accounts[2].lamports(), 10);
+        assert_eq!(
+            accounts[1].data.borrow()[..8],
+            CLOSED_ACCOUNT_DISCRIMINATOR[..]
+        );
+    }
+
+    #[test]
+    fn should_fail_when_withdraw_stream_without_recipient_signature() {
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let recipient_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let stream = Stream {
+            wallet: wallet_key,
+            recipient: recipient_key,
+            total: 1_000,
+            end: 1,
+            ..Stream::default()
+        };
+
+        let mut stream_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        stream_account.data = vec![0; Stream::LEN];
+        stream.pack(&mut stream_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            stream_account,
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::new(vault_key, 1_000, system_program_id),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_withdraw_stream(
+                &program_id,
+                &accounts,
+                10,
+                &Rent::default(),
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+
+    #[test]
+    fn vault_signed_invokes_should_pass_the_guard() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let recipient_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let rent = Rent::default();
+        let vault = || {
+            TestAccount::new(
+                vault_key,
+                rent.minimum_balance(0) + 10_000,
+                system_program_id,
+            )
+        };
+
+        // TransferLamports, the SOL leg of every vault payment
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            vault(),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+            TestAccount::program(REJECTING_GUARD),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_guard_blocks(&accounts, |guard| {
+            Processor::process_transfer_lamports(&program_id, &accounts, 1, &rent, guard)
+        });
+
+        // TokenTransfer and ApproveTokenDelegate
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            vault(),
+            TestAccount::new(Pubkey::new_unique(), 0, spl_token::id()),
+            TestAccount::new(Pubkey::new_unique(), 0, spl_token::id()),
+            TestAccount::new(Pubkey::new_unique(), 0, spl_token::id()),
+            TestAccount::program(spl_token::id()),
+            TestAccount::program(REJECTING_GUARD),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_guard_blocks(&accounts, |guard| {
+            Processor::process_token_transfer(&program_id, &accounts, 1, 6, guard)
+        });
+        assert_guard_blocks(&accounts, |guard| {
+            Processor::process_approve_token_delegate(&program_id, &accounts, 1, 6, guard)
+        });
+
+        // ExecuteScheduledPayment
+        let schedule = ScheduledPayment {
+            wallet: wallet_key,
+            recipient: recipient_key,
+            amount: 1,
+            interval: 60,
+            ..ScheduledPayment::default()
+        };
+        let mut schedule_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        schedule_account.data = vec![0; ScheduledPayment::LEN];
+        schedule.pack(&mut schedule_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            schedule_account,
+            vault(),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+            TestAccount::program(REJECTING_GUARD),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_guard_blocks(&accounts, |guard| {
+            Processor::process_execute_scheduled_payment(&program_id, &accounts, 0, &rent, guard)
+                .map(|_| ())
+        });
+
+        // WithdrawStream and CancelStream
+        let stream = Stream {
+            wallet: wallet_key,
+            recipient: recipient_key,
+            total: 1_000,
+            start: 0,
+            cliff: 0,
+            end: 100,
+            ..Stream::default()
+        };
+        let mut stream_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        stream_account.data = vec![0; Stream::LEN];
+        stream.pack(&mut stream_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            stream_account,
+            TestAccount::signer(recipient_key),
+            vault(),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+            TestAccount::program(REJECTING_GUARD),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_guard_blocks(&accounts, |guard| {
+            Processor::process_withdraw_stream(&program_id, &accounts, 50, &rent, guard).map(|_| ())
+        });
+        // the failed withdrawal is not rolled back here, cancel once more has vested
+        assert_guard_blocks(&accounts, |guard| {
+            Processor::process_cancel_stream(&program_id, &accounts, 80, &rent, false, guard)
+                .map(|_| ())
+        });
+
+        // reimbursement, with the guard among the relayed instruction accounts
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::program(REJECTING_GUARD),
+            vault(),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_guard_blocks(&accounts, |guard| {
+            let wallet_account = Account {
+                reimbursement_cap: 1,
+                ..guard.wallet_account.clone()
+            };
+            Processor::process_reimbursement(&program_id, &accounts, &wallet_account, &[], 1, &rent)
+                .map(|_| ())
+        });
+
+        // RunInstructionBuffer
+        let owner = Pubkey::new_unique();
+        let mut data = vec![0; 256];
+        crate::utils::write_instruction(
+            &mut 0,
+            &system_instruction::transfer(&vault_key, &recipient_key, 1),
+            &mut data,
+        )
+        .unwrap();
+        let mut buffer_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        buffer_account.data = vec![0; InstructionBuffer::HEADER_LEN + data.len()];
+        InstructionBuffer::pack(
+            InstructionBuffer {
+                owner,
+                wallet: wallet_key,
+                owner_epoch: 0,
+                vault_index: 0,
+                data,
+            },
+            &mut buffer_account.data,
+        )
+        .unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            buffer_account,
+            TestAccount::signer(owner),
+            vault(),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+            TestAccount::program(REJECTING_GUARD),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_guard_blocks(&accounts, |guard| {
+            Processor::process_run_insturction_buffer(
+                &program_id,
+                &accounts,
+                guard.wallet_account,
+                1,
+                0,
+            )
+        });
+
+        // SessionInvoke
+        let session_key = Pubkey::new_unique();
+        let session = new_session(wallet_key, session_key, system_program_id);
+        let mut session_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        session_account.data = vec![0; session.get_packed_len()];
+        session.pack(&mut session_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            session_account,
+            TestAccount::signer(session_key),
+            vault(),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::program(system_program_id),
+            TestAccount::program(REJECTING_GUARD),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_guard_blocks(&accounts, |guard| {
+            Processor::process_session_invoke(
+                &program_id,
+                &accounts,
+                system_instruction::transfer(&vault_key, &recipient_key, 1),
+                0,
+                guard.wallet_account,
+            )
+            .map(|_| ())
+        });
+        assert_eq!(accounts[3].lamports(), rent.minimum_balance(0) + 10_000);
+    }
+
+    #[test]
+    fn call_guard_should_pass_instruction_and_signers() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let guard_key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let instruction = system_instruction::transfer(&vault_key, &owner, 42);
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(vault_key, 0, system_program_id),
+            TestAccount::new(owner, 0, system_program_id),
+            TestAccount::program(guard_key),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        // without a guard nothing is called
+        assert_eq!(
+            Processor::call_guard(
+                &accounts,
+                &Account::default(),
+                &wallet_key,
+                GuardPhase::PreExecution,
+                &instruction,
+                &[owner]
+            ),
+            Ok(())
+        );
+        assert_eq!(take_invoked_instructions(), vec![]);
+
+        let wallet_account = Account {
+            guard: Some(guard_key),
+            ..Account::default()
+        };
+        assert_eq!(
+            Processor::call_guard(
+                &accounts[..2],
+                &wallet_account,
+                &wallet_key,
+                GuardPhase::PreExecution,
+                &instruction,
+                &[owner]
+            ),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+        assert_eq!(
+            Processor::call_guard(
+                &accounts,
+                &wallet_account,
+                &wallet_key,
+                GuardPhase::PostExecution,
+                &instruction,
+                &[owner]
+            ),
+            Ok(())
+        );
+        let (guard_call, signer_seeds) = take_invoked_instructions().remove(0);
+        assert_eq!(
+            guard_call,
+            guard_instruction(
+                &guard_key,
+                &wallet_key,
+                GuardPhase::PostExecution,
+                &instruction,
+                &[owner]
+            )
+            .unwrap()
+        );
+        assert_eq!(guard_call.data[0], 1);
+        assert!(guard_call.accounts.iter().all(|account| !account.is_signer));
+        assert_eq!(signer_seeds, Vec::<Vec<Vec<u8>>>::new());
+    }
+
+    #[test]
+    fn process_set_guard_should_pass_current_guard() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let guard_key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::program(guard_key),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        // the first guard is set without a call
+        let mut wallet_account = Account::default();
+        assert_eq!(
+            Processor::process_set_guard(
+                &program_id,
+                &accounts,
+                &mut wallet_account,
+                Some(guard_key),
+                &[owner]
+            ),
+            Ok(())
+        );
+        assert_eq!(wallet_account.guard, Some(guard_key));
+        assert_eq!(take_invoked_instructions(), vec![]);
+
+        // removing it has to pass it
+        assert_eq!(
+            Processor::process_set_guard(
+                &program_id,
+                &accounts,
+                &mut wallet_account,
+                None,
+                &[owner]
+            ),
+            Ok(())
+        );
+        assert_eq!(wallet_account.guard, None);
+        let invoked = take_invoked_instructions();
+        assert_eq!(invoked.len(), 1);
+        assert_eq!(invoked[0].0.program_id, guard_key);
+
+        assert_eq!(
+            Processor::process_set_guard(
+                &program_id,
+                &accounts,
+                &mut wallet_account,
+                Some(program_id),
+                &[owner]
+            ),
+            Err(WalletError::InvalidInstruction.into())
+        );
+    }
+
+    #[test]
+    fn process_enable_module_should_limit_modules() {
+        let program_id = crate::id();
+        let mut wallet_account = Account::default();
+
+        let modules: Vec<Pubkey> = (0..Account::MAX_MODULES)
+            .map(|_| Pubkey::new_unique())
+            .collect();
+        for module in &modules {
+            assert_eq!(
+                Processor::process_enable_module(&program_id, &mut wallet_account, *module),
+                Ok(())
+            );
+        }
+        assert_eq!(wallet_account.modules, modules);
+        assert_eq!(
+            Processor::process_enable_module(
+                &program_id,
+                &mut wallet_account,
+                Pubkey::new_unique()
+            ),
+            Err(WalletError::ModuleLimitReached.into())
+        );
+        assert_eq!(
+            Processor::process_enable_module(&program_id, &mut wallet_account, modules[0]),
+            Err(WalletError::InvalidInstruction.into())
+        );
+
+        assert_eq!(
+            Processor::process_disable_module(&mut wallet_account, modules[0]),
+            Ok(())
+        );
+        assert_eq!(
+            Processor::process_disable_module(&mut wallet_account, modules[0]),
+            Err(WalletError::ModuleNotEnabled.into())
+        );
+        assert_eq!(
+            Processor::process_enable_module(&program_id, &mut wallet_account, program_id),
+            Err(WalletError::InvalidInstruction.into())
+        );
+    }
+
+    #[test]
+    fn check_module_should_accept_enabled_module_authority() {
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let module = Pubkey::new_unique();
+        let (authority_key, _) = get_module_authority_address(&wallet_key, &module);
+        let wallet_account = Account {
+            modules: vec![module],
+            ..Account::default()
+        };
+
+        let mut authority = TestAccount::new(authority_key, 0, module);
+        authority.is_signer = true;
+        let mut accounts = vec![TestAccount::new(wallet_key, 0, program_id), authority];
+        let accounts = account_infos(&mut accounts);
+        assert_eq!(
+            Processor::check_module(&accounts, &wallet_account),
+            Ok(module)
+        );
+        assert_eq!(
+            Processor::check_module(&accounts, &Account::default()),
+            Err(WalletError::ModuleNotEnabled.into())
+        );
+
+        // the authority has to sign
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(authority_key, 0, module),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_eq!(
+            Processor::check_module(&accounts, &wallet_account),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+
+    #[test]
+    fn child_wallet_vault_should_sign_for_parent_wallet() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (child_key, child_vault_key) = new_wallet_with_vault(&program_id);
+        let parent_key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+
+        // the child vault owns the parent wallet
+        let parent_account = Account {
+            state: AccountState::Initialized,
+            owners: btreemap! { child_vault_key => 1000 },
+            ..Account::default()
+        };
+        let parent_instruction = Instruction {
+            program_id,
+            accounts: vec![
+                solana_program::instruction::AccountMeta::new(parent_key, false),
+                solana_program::instruction::AccountMeta::new_readonly(child_vault_key, true),
+            ],
+            data: vec![5],
+        };
+        let child_instruction = crate::instruction::invoke(
+            &program_id,
+            &child_key,
+            &payer,
+            &[owner],
+            &parent_instruction,
+        )
+        .unwrap();
+
+        let mut accounts: Vec<TestAccount> = child_instruction
+            .accounts
+            .iter()
+            .map(|account_meta| {
+                let mut account = TestAccount::new(account_meta.pubkey, 0, program_id);
+                account.is_signer = account_meta.is_signer;
+                account.is_writable = account_meta.is_writable;
+                account
+            })
+            .collect();
+        let accounts = account_infos(&mut accounts);
+
+        let instruction = match WalletInstruction::unpack(&child_instruction.data, &accounts) {
+            Ok(WalletInstruction::Invoke { instruction }) => instruction,
+            other => panic!("unexpected instruction {:?}", other),
+        };
+        assert_eq!(instruction, parent_instruction);
+        assert_eq!(
+            Processor::check_nesting_depth(&program_id, &accounts, &instruction),
+            Ok(())
+        );
+        assert_eq!(
+            Processor::process_invoke(&program_id, &accounts, 0, instruction),
+            Ok(())
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![(
+                parent_instruction,
+                vec![vec![child_key.to_bytes().to_vec()]]
+            )]
+        );
+
+        // within the parent call the child vault is a signer with its owner weight
+        let mut parent_accounts = vec![
+            TestAccount::new(parent_key, 0, program_id),
+            TestAccount::new(child_vault_key, 0, solana_program::system_program::id()),
+        ];
+        parent_accounts[1].is_signer = true;
+        let parent_accounts = account_infos(&mut parent_accounts);
+        assert_eq!(
+            Processor::check_signatures(&parent_accounts, &parent_account),
+            Ok(vec![child_vault_key])
+        );
+    }
+
+    #[test]
+    fn check_nesting_depth_should_bound_nested_wallets() {
+        let program_id = crate::id();
+        let payer = Pubkey::new_unique();
+        let external = system_instruction::transfer(&Pubkey::new_unique(), &payer, 1);
+
+        // each wallet invokes the next one, the last invokes the external instruction
+        let nest = |depth: usize| {
+            let mut instruction = external.clone();
+            for _ in 0..depth {
+                let (wallet_key, _) = new_wallet_with_vault(&program_id);
+                instruction = crate::instruction::invoke(
+                    &program_id,
+                    &wallet_key,
+                    &payer,
+                    &[Pubkey::new_unique()],
+                    &instruction,
                 )
+                .unwrap();
             }
-            WalletInstruction::CloseInstructionBuffer => {
-                msg!("Instruction: CloseInstructionBuffer");
-                Self::process_close_instruction_buffer(program_id, accounts)
-            }
-        }?;
-        Ok(())
+            instruction
+        };
+
+        for (depth, result) in [
+            (0, Ok(())),
+            (MAX_NESTING_DEPTH, Ok(())),
+            (
+                MAX_NESTING_DEPTH + 1,
+                Err(WalletError::NestingTooDeep.into()),
+            ),
+        ] {
+            let instruction = nest(depth);
+            let mut accounts: Vec<TestAccount> = instruction
+                .accounts
+                .iter()
+                .map(|account_meta| TestAccount::new(account_meta.pubkey, 0, program_id))
+                .collect();
+            accounts.push(TestAccount::program(instruction.program_id));
+            let accounts = account_infos(&mut accounts);
+            assert_eq!(
+                Processor::check_nesting_depth(&program_id, &accounts, &instruction),
+                result
+            );
+        }
     }
-}
 
-#[cfg(test)]
-mod test {
+    #[test]
+    fn process_invoke_should_sign_with_sub_vault() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let (sub_vault_key, bump) = get_sub_vault_address(&wallet_key, 2, &program_id);
+        let destination = Pubkey::new_unique();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(Pubkey::new_unique(), 0, program_id),
+            TestAccount::new(Pubkey::new_unique(), 0, program_id),
+            TestAccount::new(sub_vault_key, 100, solana_program::system_program::id()),
+            TestAccount::new(destination, 0, solana_program::system_program::id()),
+        ];
+        let accounts = account_infos(&mut accounts);
 
-    use super::*;
-    use maplit::btreemap;
-    use std::str::FromStr;
+        // only transfers out of the signing vault are outgoing
+        let instructions = vec![
+            system_instruction::transfer(&sub_vault_key, &destination, 10),
+            system_instruction::transfer(&vault_key, &Pubkey::new_unique(), 10),
+        ];
+        assert_eq!(
+            Processor::outgoing_destinations(&program_id, &wallet_key, 2, &instructions, true),
+            Ok(vec![destination])
+        );
+
+        assert_eq!(
+            Processor::process_invoke(&program_id, &accounts, 2, instructions[0].clone()),
+            Ok(())
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![(
+                instructions[0].clone(),
+                vec![vec![
+                    b"vault".to_vec(),
+                    wallet_key.to_bytes().to_vec(),
+                    2u16.to_le_bytes().to_vec(),
+                    vec![bump],
+                ]]
+            )]
+        );
+    }
 
     #[test]
-    fn should_fail_when_init_with_key_weight_is_not_enough() {
-        let mut init_account = Account {
-            state: AccountState::Uninitialized,
-            owners: BTreeMap::new(),
-            max_owners: 101,
-        };
-        let init_keys = btreemap! {
-          Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1,
-          Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1,
+    fn append_history_should_record_authorized_instructions() {
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let (history_key, _) = get_history_address(&wallet_key, &program_id);
+        let owner = Pubkey::new_unique();
+        let wallet_account = Account {
+            history: true,
+            ..wallet_owned_by(owner)
         };
-        let expected_account = Account {
-            state: AccountState::Uninitialized,
-            owners: BTreeMap::new(),
-            max_owners: 101,
+
+        let wallet = TestAccount::wallet(wallet_key, program_id, &wallet_account);
+        let mut history = TestAccount::new(history_key, 0, program_id);
+        history.data = vec![0; History::get_packed_len(4)];
+        History {
+            wallet: wallet_key,
+            capacity: 4,
+            total: 0,
+            entries: vec![],
+        }
+        .pack(&mut history.data)
+        .unwrap();
+        let owner_account = TestAccount::signer(owner);
+        let mut accounts = vec![
+            wallet,
+            owner_account,
+            TestAccount::new(vault_key, 100, system_program::id()),
+            history,
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        let transfer = system_instruction::transfer(&vault_key, &owner, 10);
+        let input = [3u8, 1, 2, 3];
+        let instruction = WalletInstruction::Invoke {
+            instruction: transfer.clone(),
         };
+        let mut entry = Processor::history_entry(&program_id, &accounts, &input, &instruction)
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.program_id, transfer.program_id);
+        assert_eq!(entry.data_hash, hash(&input).to_bytes());
+        assert_eq!((entry.weight, entry.signers), (1000, 1));
 
+        entry.slot = 42;
         assert_eq!(
-            Processor::process_initialize_wallet(&mut init_account, init_keys.clone()),
-            Err(WalletError::InsufficientWeight.into()),
+            Processor::append_history(&program_id, &accounts, &entry),
+            Ok(())
+        );
+        let history = History::unpack(&accounts[3].data.borrow()).unwrap();
+        assert_eq!(history.entries, vec![entry]);
+
+        // instructions not authorized by the owners are not recorded
+        assert_eq!(
+            Processor::history_entry(&program_id, &accounts, &[20], &WalletInstruction::Freeze),
+            Ok(None)
+        );
+        assert_eq!(
+            Processor::append_history(&program_id, &accounts[..3], &entry),
+            Err(ProgramError::NotEnoughAccountKeys)
         );
-        assert_eq!(init_account, expected_account);
     }
 
     #[test]
-    fn process_initialize_wallet_should_success() {
-        let mut init_account = Account {
-            state: AccountState::Uninitialized,
-            owners: BTreeMap::new(),
-            max_owners: 101,
-        };
-        let init_keys = btreemap! {
-          Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 999,
-          Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1,
+    fn should_fail_when_history_account_is_missing() {
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let wallet_account = Account {
+            history: true,
+            ..wallet_owned_by(owner)
         };
+        let wallet = TestAccount::wallet(wallet_key, program_id, &wallet_account);
+        let mut accounts = vec![wallet, TestAccount::signer(owner)];
+        let accounts = account_infos(&mut accounts);
 
+        let mut input = vec![20];
+        input.extend_from_slice(&60u64.to_le_bytes());
         assert_eq!(
-            Processor::process_initialize_wallet(&mut init_account, init_keys.clone()),
-            Ok(()),
+            Processor::process(&program_id, &accounts, &input),
+            Err(ProgramError::NotEnoughAccountKeys)
         );
+        // the instruction did not run
         assert_eq!(
-            init_account,
-            Account {
-                state: AccountState::Initialized,
-                owners: init_keys.clone(),
-                max_owners: 101,
-            },
+            Account::unpack_from_slice(&accounts[0].data.borrow())
+                .unwrap()
+                .rotation_delay,
+            0
         );
     }
 
     #[test]
-    fn process_add_owner_should_success() {
-        let mut init_account = Account {
-            state: AccountState::Initialized,
-            owners: btreemap! {
-              Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000,
+    fn should_fail_when_token_transfer_with_fake_token_program() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let system_program_id = solana_program::system_program::id();
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(vault_key, 0, system_program_id),
+            TestAccount::new(Pubkey::new_unique(), 0, spl_token::id()),
+            TestAccount::new(Pubkey::new_unique(), 0, spl_token::id()),
+            TestAccount::new(Pubkey::new_unique(), 0, spl_token::id()),
+            TestAccount::program(Pubkey::new_unique()),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_token_transfer(
+                &program_id,
+                &accounts,
+                42,
+                6,
+                guard_context(&accounts, &Account::default())
+            ),
+            Err(ProgramError::IncorrectProgramId)
+        );
+        assert_eq!(take_invoked_instructions(), vec![]);
+    }
+
+    #[test]
+    fn process_create_associated_token_account_should_skip_existing_account() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let recipient_key = Pubkey::new_unique();
+        let mint_key = Pubkey::new_unique();
+        let associated_key =
+            spl_associated_token_account::get_associated_token_address(&recipient_key, &mint_key);
+        let system_program_id = solana_program::system_program::id();
+        let token_program_id = spl_token::id();
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(vault_key, 1_000_000, system_program_id),
+            TestAccount::new(associated_key, 0, system_program_id),
+            TestAccount::new(recipient_key, 0, system_program_id),
+            TestAccount::new(mint_key, 0, spl_token::id()),
+            TestAccount::program(system_program_id),
+            TestAccount::program(spl_token::id()),
+            TestAccount::new(solana_program::sysvar::rent::id(), 1, system_program_id),
+            TestAccount::program(spl_associated_token_account::id()),
+        ];
+        let mut accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_create_associated_token_account(
+                &program_id,
+                &accounts,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok(true)
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![(
+                spl_associated_token_account::create_associated_token_account(
+                    &vault_key,
+                    &recipient_key,
+                    &mint_key,
+                ),
+                vec![vec![wallet_key.to_bytes().to_vec()]],
+            )]
+        );
+
+        // once the token program owns the account, nothing is created
+        accounts[2].owner = &token_program_id;
+        assert_eq!(
+            Processor::process_create_associated_token_account(
+                &program_id,
+                &accounts,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok(false)
+        );
+        assert_eq!(take_invoked_instructions(), vec![]);
+    }
+
+    #[test]
+    fn process_token_delegate_and_close_should_sign_with_vault() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let token_account_key = Pubkey::new_unique();
+        let mint_key = Pubkey::new_unique();
+        let delegate_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let signers_seeds = vec![vec![wallet_key.to_bytes().to_vec()]];
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(vault_key, 0, system_program_id),
+            TestAccount::new(token_account_key, 0, spl_token::id()),
+            TestAccount::new(mint_key, 0, spl_token::id()),
+            TestAccount::new(delegate_key, 0, system_program_id),
+            TestAccount::program(spl_token::id()),
+        ];
+        let approve_accounts = account_infos(&mut accounts);
+        assert_eq!(
+            Processor::process_approve_token_delegate(
+                &program_id,
+                &approve_accounts,
+                42,
+                6,
+                guard_context(&approve_accounts, &Account::default())
+            ),
+            Ok(())
+        );
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            TestAccount::new(vault_key, 0, system_program_id),
+            TestAccount::new(token_account_key, 0, spl_token::id()),
+            TestAccount::program(spl_token::id()),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_eq!(
+            Processor::process_revoke_token_delegate(
+                &program_id,
+                &accounts,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            Processor::process_close_token_account(
+                &program_id,
+                &accounts,
+                guard_context(&accounts, &Account::default())
+            ),
+            Ok(())
+        );
+
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![
+                (
+                    spl_token::instruction::approve_checked(
+                        &spl_token::id(),
+                        &token_account_key,
+                        &mint_key,
+                        &delegate_key,
+                        &vault_key,
+                        &[],
+                        42,
+                        6,
+                    )
+                    .unwrap(),
+                    signers_seeds.clone(),
+                ),
+                (
+                    spl_token::instruction::revoke(
+                        &spl_token::id(),
+                        &token_account_key,
+                        &vault_key,
+                        &[],
+                    )
+                    .unwrap(),
+                    signers_seeds.clone(),
+                ),
+                (
+                    spl_token::instruction::close_account(
+                        &spl_token::id(),
+                        &token_account_key,
+                        &vault_key,
+                        &vault_key,
+                        &[],
+                    )
+                    .unwrap(),
+                    signers_seeds,
+                ),
+            ]
+        );
+    }
+
+    fn new_session(wallet: Pubkey, session_key: Pubkey, allowed_program: Pubkey) -> Session {
+        Session {
+            wallet,
+            session_key,
+            expires_at: 1000,
+            lamport_cap: 100,
+            lamports_spent: 0,
+            token_mint: None,
+            token_cap: 0,
+            tokens_spent: 0,
+            allowed_programs: vec![allowed_program],
+            data_prefixes: vec![],
+        }
+    }
+
+    #[test]
+    fn process_create_session_should_create_session_pda() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let payer_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let session = new_session(wallet_key, Pubkey::new_unique(), Pubkey::new_unique());
+        let (session_address, bump_seed) =
+            get_session_address(&wallet_key, &session.session_key, &program_id);
+
+        let mut session_account = TestAccount::new(session_address, 0, program_id);
+        session_account.data = vec![0; session.get_packed_len()];
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            session_account,
+            TestAccount {
+                is_signer: true,
+                ..TestAccount::new(payer_key, 1_000_000_000, system_program_id)
             },
-            max_owners: 101,
-        };
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+        let rent = Rent::default();
+        let seeds = vec![
+            Session::SEED.to_vec(),
+            wallet_key.to_bytes().to_vec(),
+            session.session_key.to_bytes().to_vec(),
+            vec![bump_seed],
+        ];
 
-        let add_keys = btreemap! {Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1};
         assert_eq!(
-            Processor::process_add_owner(&mut init_account, add_keys),
+            Processor::process_create_session(&program_id, &accounts, &session, 1000, &rent),
+            Err(WalletError::InvalidInstruction.into())
+        );
+        assert_eq!(
+            Processor::process_create_session(&program_id, &accounts, &session, 999, &rent),
             Ok(())
         );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![
+                (
+                    system_instruction::transfer(
+                        &payer_key,
+                        &session_address,
+                        rent.minimum_balance(session.get_packed_len()),
+                    ),
+                    vec![],
+                ),
+                (
+                    system_instruction::allocate(&session_address, session.get_packed_len() as u64),
+                    vec![seeds.clone()],
+                ),
+                (
+                    system_instruction::assign(&session_address, &program_id),
+                    vec![seeds.clone()],
+                ),
+            ]
+        );
+        assert_eq!(
+            Session::unpack(&accounts[1].data.borrow()),
+            Ok(session.clone())
+        );
 
-        let expected_account = Account {
-            state: AccountState::Initialized,
-            owners: btreemap! {
-              Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000,
-              Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1
+        // an address funded ahead of time is only topped up to rent exemption
+        let mut session_account = TestAccount::new(session_address, 1000, program_id);
+        session_account.data = vec![0; session.get_packed_len()];
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            session_account,
+            TestAccount {
+                is_signer: true,
+                ..TestAccount::new(payer_key, 1_000_000_000, system_program_id)
             },
-            max_owners: 101,
-        };
-        assert_eq!(init_account, expected_account);
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+        assert_eq!(
+            Processor::process_create_session(&program_id, &accounts, &session, 999, &rent),
+            Ok(())
+        );
+        assert_eq!(
+            take_invoked_instructions()[0],
+            (
+                system_instruction::transfer(
+                    &payer_key,
+                    &session_address,
+                    rent.minimum_balance(session.get_packed_len()) - 1000,
+                ),
+                vec![],
+            )
+        );
+        assert_eq!(
+            accounts[1].lamports(),
+            rent.minimum_balance(session.get_packed_len())
+        );
     }
 
     #[test]
-    fn should_fail_when_recovery_with_key_weight_is_not_enough() {
-        let mut wallet_account = Account {
-            state: AccountState::Initialized,
-            owners: btreemap! {Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000},
-            max_owners: 101,
-        };
-        let recovery_keys = btreemap! {
-          Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1,
+    fn process_session_invoke_should_stay_in_scope() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let session_key = Pubkey::new_unique();
+        let allowed_program = Pubkey::new_unique();
+        let session = new_session(wallet_key, session_key, allowed_program);
+
+        let mut session_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        session_account.data = vec![0; session.get_packed_len()];
+        session.pack(&mut session_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            session_account,
+            TestAccount::signer(session_key),
+            TestAccount::new(vault_key, 100, solana_program::system_program::id()),
+            TestAccount::program(allowed_program),
+        ];
+        let accounts = account_infos(&mut accounts);
+        let instruction = |program_id| Instruction {
+            program_id,
+            accounts: vec![solana_program::instruction::AccountMeta::new(
+                vault_key, true,
+            )],
+            data: vec![1],
         };
+
         assert_eq!(
-            Processor::process_initialize_wallet(&mut wallet_account, recovery_keys),
-            Err(WalletError::InsufficientWeight.into()),
+            Processor::process_session_invoke(
+                &program_id,
+                &accounts,
+                instruction(Pubkey::new_unique()),
+                0,
+                &Account::default()
+            ),
+            Err(WalletError::SessionScopeViolation.into())
+        );
+        assert_eq!(
+            Processor::process_session_invoke(
+                &program_id,
+                &accounts,
+                instruction(program_id),
+                0,
+                &Account::default()
+            ),
+            Err(WalletError::SessionScopeViolation.into())
+        );
+        assert_eq!(
+            Processor::process_session_invoke(
+                &program_id,
+                &accounts,
+                instruction(allowed_program),
+                1000,
+                &Account::default()
+            ),
+            Err(WalletError::SessionExpired.into())
+        );
+        assert_eq!(take_invoked_instructions(), vec![]);
+
+        assert_eq!(
+            Processor::process_session_invoke(
+                &program_id,
+                &accounts,
+                instruction(allowed_program),
+                999,
+                &Account::default()
+            ),
+            Ok((session, 0, 0))
+        );
+        assert_eq!(
+            take_invoked_instructions(),
+            vec![(
+                instruction(allowed_program),
+                vec![vec![wallet_key.to_bytes().to_vec()]]
+            )]
         );
     }
 
     #[test]
-    fn process_recovery_should_success() {
-        let mut wallet_account = Account {
-            state: AccountState::Initialized,
-            owners: btreemap! {Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000},
-            max_owners: 101,
-        };
-        let recovery_keys = btreemap! {Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap() => 1000};
-        assert_eq!(
-            Processor::process_recovery(&mut wallet_account, recovery_keys.clone()),
+    fn process_session_invoke_should_not_reshape_accounts() {
+        install_recording_syscall_stubs();
+        let program_id = crate::id();
+        let system_program_id = solana_program::system_program::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let session_key = Pubkey::new_unique();
+        let session = new_session(wallet_key, session_key, system_program_id);
+        let new_account = Pubkey::new_unique();
+
+        let mut session_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        session_account.data = vec![0; session.get_packed_len()];
+        session.pack(&mut session_account.data).unwrap();
+        let mut vault_account = TestAccount::new(vault_key, 100, system_program_id);
+        vault_account.data = vec![0; 8];
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            session_account,
+            TestAccount::signer(session_key),
+            vault_account,
+            TestAccount::new(new_account, 0, system_program_id),
+            TestAccount::program(system_program_id),
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        for instruction in [
+            system_instruction::assign(&vault_key, &Pubkey::new_unique()),
+            system_instruction::assign_with_seed(
+                &new_account,
+                &vault_key,
+                "seed",
+                &Pubkey::new_unique(),
+            ),
+            system_instruction::allocate(&vault_key, 100),
+            system_instruction::create_account(
+                &vault_key,
+                &new_account,
+                1,
+                0,
+                &Pubkey::new_unique(),
+            ),
+            system_instruction::create_account_with_seed(
+                &vault_key,
+                &new_account,
+                &vault_key,
+                "seed",
+                1,
+                0,
+                &Pubkey::new_unique(),
+            ),
+        ] {
+            assert_eq!(
+                Processor::process_session_invoke(
+                    &program_id,
+                    &accounts,
+                    instruction,
+                    0,
+                    &Account::default()
+                ),
+                Err(WalletError::SessionScopeViolation.into())
+            );
+        }
+        assert_eq!(take_invoked_instructions(), vec![]);
+
+        // a program resizing an account behind the session's back
+        set_invoke_hook(|_, accounts| {
+            let mut data = accounts[3].data.borrow_mut();
+            let len = data.len();
+            let resized = std::mem::take(&mut *data);
+            *data = &mut resized[..len - 1];
             Ok(())
+        });
+        assert_eq!(
+            Processor::process_session_invoke(
+                &program_id,
+                &accounts,
+                system_instruction::transfer(&vault_key, &new_account, 1),
+                0,
+                &Account::default()
+            ),
+            Err(WalletError::SessionScopeViolation.into())
         );
+    }
 
-        let expected_account = Account {
-            state: AccountState::Initialized,
-            owners: recovery_keys.clone(),
-            max_owners: 101,
-        };
-        assert_eq!(wallet_account, expected_account);
+    #[test]
+    fn process_revoke_session_should_accept_owner_or_session_key() {
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let session_key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let wallet_account = wallet_owned_by(owner);
+        let session = new_session(wallet_key, session_key, Pubkey::new_unique());
+
+        let mut session_account = TestAccount::new(Pubkey::new_unique(), 42, program_id);
+        session_account.data = vec![0; session.get_packed_len()];
+        session.pack(&mut session_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            session_account,
+            TestAccount::new(
+                Pubkey::new_unique(),
+                0,
+                solana_program::system_program::id(),
+            ),
+            TestAccount::signer(Pubkey::new_unique()),
+        ];
+        let mut accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_revoke_session(&program_id, &accounts, &wallet_account),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+
+        let mut session_key_account = vec![TestAccount::signer(session_key)];
+        accounts[3] = account_infos(&mut session_key_account).remove(0);
+        assert_eq!(
+            Processor::process_revoke_session(&program_id, &accounts, &wallet_account),
+            Ok((session_key, vec![session_key]))
+        );
+        assert_eq!(accounts[2].lamports(), 42);
+        assert_eq!(
+            Session::unpack(&accounts[1].data.borrow()),
+            Err(WalletError::AccountClosed.into())
+        );
     }
 
     #[test]
-    fn process_revoke_should_success() {
-        let mut wallet_account = Account {
-            state: AccountState::Initialized,
-            owners: btreemap! {Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000},
-            max_owners: 101,
+    fn outgoing_destinations_should_find_vault_transfers() {
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let destination = Pubkey::new_unique();
+        let token_account = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let instructions = vec![
+            system_instruction::transfer(&vault_key, &destination, 1),
+            // not paid by the vault
+            system_instruction::transfer(&Pubkey::new_unique(), &Pubkey::new_unique(), 1),
+            spl_token::instruction::transfer_checked(
+                &spl_token::id(),
+                &token_account,
+                &mint,
+                &destination,
+                &vault_key,
+                &[],
+                1,
+                6,
+            )
+            .unwrap(),
+            spl_token::instruction::approve(
+                &spl_token::id(),
+                &token_account,
+                &destination,
+                &vault_key,
+                &[],
+                1,
+            )
+            .unwrap(),
+            spl_token::instruction::set_authority(
+                &spl_token::id(),
+                &token_account,
+                Some(&destination),
+                spl_token::instruction::AuthorityType::AccountOwner,
+                &vault_key,
+                &[],
+            )
+            .unwrap(),
+            spl_token::instruction::close_account(
+                &spl_token::id(),
+                &token_account,
+                &destination,
+                &vault_key,
+                &[],
+            )
+            .unwrap(),
+            system_instruction::create_account(
+                &vault_key,
+                &destination,
+                1,
+                0,
+                &Pubkey::new_unique(),
+            ),
+            system_instruction::create_account_with_seed(
+                &vault_key,
+                &destination,
+                &Pubkey::new_unique(),
+                "seed",
+                1,
+                0,
+                &Pubkey::new_unique(),
+            ),
+            // gives nothing away
+            spl_token::instruction::revoke(&spl_token::id(), &token_account, &vault_key, &[])
+                .unwrap(),
+            spl_token::instruction::set_authority(
+                &spl_token::id(),
+                &token_account,
+                None,
+                spl_token::instruction::AuthorityType::CloseAccount,
+                &vault_key,
+                &[],
+            )
+            .unwrap(),
+        ];
+
+        assert_eq!(
+            Processor::outgoing_destinations(&program_id, &wallet_key, 0, &instructions, true),
+            Ok(vec![destination; 7])
+        );
+    }
+
+    #[test]
+    fn outgoing_destinations_should_deny_unknown_vault_instructions() {
+        let program_id = crate::id();
+        let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+        let token_account = Pubkey::new_unique();
+
+        for instruction in [
+            system_instruction::assign(&vault_key, &Pubkey::new_unique()),
+            system_instruction::assign_with_seed(
+                &Pubkey::new_unique(),
+                &vault_key,
+                "seed",
+                &Pubkey::new_unique(),
+            ),
+            system_instruction::allocate(&vault_key, 100),
+            system_instruction::allocate_with_seed(
+                &Pubkey::new_unique(),
+                &vault_key,
+                "seed",
+                100,
+                &Pubkey::new_unique(),
+            ),
+            // the vault is the new account rather than the funder
+            system_instruction::create_account(
+                &Pubkey::new_unique(),
+                &vault_key,
+                1,
+                0,
+                &Pubkey::new_unique(),
+            ),
+            spl_token::instruction::burn(
+                &spl_token::id(),
+                &token_account,
+                &Pubkey::new_unique(),
+                &vault_key,
+                &[],
+                1,
+            )
+            .unwrap(),
+        ] {
+            let instructions = [instruction];
+            assert_eq!(
+                Processor::outgoing_destinations(&program_id, &wallet_key, 0, &instructions, true),
+                Err(WalletError::DestinationNotApproved.into())
+            );
+            // without an address book there is nothing to check them against
+            assert_eq!(
+                Processor::outgoing_destinations(&program_id, &wallet_key, 0, &instructions, false),
+                Ok(vec![])
+            );
+        }
+
+        // instructions to other programs, or not signed by the vault, are left alone
+        let instructions = [
+            Instruction {
+                program_id: Pubkey::new_unique(),
+                accounts: vec![solana_program::instruction::AccountMeta::new(
+                    vault_key, true,
+                )],
+                data: vec![],
+            },
+            system_instruction::assign(&Pubkey::new_unique(), &Pubkey::new_unique()),
+        ];
+        assert_eq!(
+            Processor::outgoing_destinations(&program_id, &wallet_key, 0, &instructions, true),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn check_address_book_should_only_accept_active_entries() {
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let (address_book_key, _) = get_address_book_address(&wallet_key, &program_id);
+        let active = Pubkey::new_unique();
+        let pending = Pubkey::new_unique();
+        let token_account_key = Pubkey::new_unique();
+
+        let address_book = AddressBook {
+            wallet: wallet_key,
+            activation_delay: 100,
+            capacity: 2,
+            entries: btreemap! { active => 1000, pending => 1001 },
         };
-        assert_eq!(Processor::process_revoke(&mut wallet_account), Ok(()));
+        let mut address_book_account = TestAccount::new(address_book_key, 0, program_id);
+        address_book_account.data = vec![0; AddressBook::get_packed_len(2)];
+        address_book.pack(&mut address_book_account.data).unwrap();
 
-        let expected_account = Account {
-            state: AccountState::Initialized,
-            owners: btreemap! {},
-            max_owners: 101,
+        let mut token_account = TestAccount::new(token_account_key, 0, spl_token::id());
+        token_account.data = vec![0; TokenAccount::LEN];
+        TokenAccount::pack(
+            TokenAccount {
+                owner: active,
+                state: spl_token::state::AccountState::Initialized,
+                ..TokenAccount::default()
+            },
+            &mut token_account.data,
+        )
+        .unwrap();
+
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            address_book_account,
+            token_account,
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::check_address_book_destinations(
+                &program_id,
+                &accounts,
+                &[active, token_account_key],
+                1000
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            Processor::check_address_book_destinations(&program_id, &accounts, &[pending], 1000),
+            Err(WalletError::DestinationNotApproved.into())
+        );
+        assert_eq!(
+            Processor::check_address_book_destinations(
+                &program_id,
+                &accounts[..1],
+                &[active],
+                1000
+            ),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+        assert_eq!(
+            Processor::check_address_book(
+                &program_id,
+                &accounts[..1],
+                &Account::default(),
+                &[pending]
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn process_add_address_book_entry_should_delay_activation() {
+        let program_id = crate::id();
+        let wallet_key = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        let address_book = AddressBook {
+            wallet: wallet_key,
+            activation_delay: 100,
+            capacity: 1,
+            entries: BTreeMap::new(),
         };
-        assert_eq!(wallet_account, expected_account);
+        let mut address_book_account = TestAccount::new(Pubkey::new_unique(), 0, program_id);
+        address_book_account.data = vec![0; AddressBook::get_packed_len(1)];
+        address_book.pack(&mut address_book_account.data).unwrap();
+        let mut accounts = vec![
+            TestAccount::new(wallet_key, 0, program_id),
+            address_book_account,
+        ];
+        let accounts = account_infos(&mut accounts);
+
+        assert_eq!(
+            Processor::process_add_address_book_entry(&program_id, &accounts, address, 1000),
+            Ok(1100)
+        );
+        assert_eq!(
+            Processor::process_add_address_book_entry(
+                &program_id,
+                &accounts,
+                Pubkey::new_unique(),
+                1000
+            ),
+            Err(WalletError::AddressBookFull.into())
+        );
+        assert_eq!(
+            Processor::process_remove_address_book_entry(&program_id, &accounts, address),
+            Ok(())
+        );
+        assert_eq!(
+            AddressBook::unpack(&accounts[1].data.borrow()),
+            Ok(address_book)
+        );
     }
 }
@@ -1,7 +1,14 @@
 //! State transition types
-use crate::utils::{write_pubkey, write_u16};
+use crate::{
+    error::WalletError,
+    utils::{
+        read_bool, read_instruction, read_u32, read_u64, write_bool, write_pubkey, write_u16,
+        write_u32, write_u64,
+    },
+};
 use num_enum::TryFromPrimitive;
 use solana_program::{
+    instruction::Instruction,
     msg,
     program_error::ProgramError,
     program_pack::{IsInitialized, Sealed},
@@ -11,7 +18,19 @@ use solana_program::{
 use std::collections::BTreeMap;
 
 /// Maximum signature weight for instructions
-pub const MIN_WEIGHT: u16 = 1000;
+pub const MIN_WEIGHT: u32 = 1000;
+
+/// Maximum number of wallets an invoked instruction may pass through when wallets own other
+/// wallets, a child wallet calling its parent is one level
+pub const MAX_NESTING_DEPTH: usize = 2;
+
+/// Written at the start of an account's data when the program closes it
+pub const CLOSED_ACCOUNT_DISCRIMINATOR: [u8; 8] = [0xff; 8];
+
+/// Check whether the account data has been closed by the program
+pub fn is_closed_account(data: &[u8]) -> bool {
+    data.starts_with(&CLOSED_ACCOUNT_DISCRIMINATOR)
+}
 
 /// Account data.
 #[repr(C)]
@@ -19,42 +38,238 @@ pub const MIN_WEIGHT: u16 = 1000;
 pub struct Account {
     /// The account's state
     pub state: AccountState,
+    /// owner set epoch, increased every time the owner set changes
+    pub owner_epoch: u64,
+    /// seconds an owner has to wait before a key rotation can be completed, 0 rotates at once
+    pub rotation_delay: u64,
+    /// key rotation waiting for the rotation delay to pass
+    pub pending_rotation: Option<KeyRotation>,
+    /// key allowed to freeze the wallet on its own
+    pub freeze_authority: Option<Pubkey>,
+    /// owner weight allowed to freeze the wallet, 0 requires the full threshold
+    pub freeze_weight: u32,
+    /// whether outgoing transfers are restricted to the wallet's address book
+    pub address_book: bool,
+    /// token mint relayers are reimbursed in, None reimburses SOL
+    pub reimbursement_mint: Option<Pubkey>,
+    /// most a single instruction may reimburse its relayer, 0 disables reimbursement
+    pub reimbursement_cap: u64,
+    /// program called before and after every instruction the wallet invokes, failing aborts it
+    pub guard: Option<Pubkey>,
+    /// module programs allowed to invoke instructions through the wallet without owner signatures
+    pub modules: Vec<Pubkey>,
+    /// whether authorized instructions are recorded in the wallet's history account
+    pub history: bool,
+    /// layout the account is packed with
+    pub layout: AccountLayout,
     /// owners is a map (public key => weight)
-    pub owners: BTreeMap<Pubkey, u16>,
+    pub owners: BTreeMap<Pubkey, u32>,
     /// only use in program, not pack into account
     pub max_owners: usize,
 }
 
+/// Layout of the packed wallet account. Wallets packed before the account header existed hold only
+/// the state byte and the owners and are told apart by their length, wallets with the header keep
+/// version 1 in the high nibble of the state byte.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AccountLayout {
+    /// state byte followed by u16 owner weights, settings beyond the owners keep their defaults.
+    /// There is no owner epoch, so these wallets cannot use instruction buffers
+    Legacy,
+    /// account header followed by u32 owner weights
+    #[default]
+    Header,
+}
+
+impl AccountLayout {
+    /// Length of the account data before the owner list
+    pub fn header_len(self) -> usize {
+        match self {
+            AccountLayout::Legacy => 1,
+            AccountLayout::Header => Account::HEADER_LEN,
+        }
+    }
+
+    /// Length of each owner entry
+    pub fn owner_len(self) -> usize {
+        match self {
+            AccountLayout::Legacy => Account::LEGACY_OWNER_LEN,
+            AccountLayout::Header => Account::OWNER_LEN,
+        }
+    }
+
+    /// Largest weight the layout can pack
+    pub fn max_weight(self) -> u32 {
+        match self {
+            AccountLayout::Legacy => u32::from(u16::MAX),
+            AccountLayout::Header => u32::MAX,
+        }
+    }
+
+    /// Layout of packed account data. An uninitialized account takes the header layout when its
+    /// length fits it
+    pub fn of(data: &[u8]) -> Result<Self, ProgramError> {
+        let state = *data.first().ok_or(ProgramError::InvalidAccountData)?;
+        match state >> 4 {
+            _ if state == 0 && AccountLayout::Header.fits(data.len()) => Ok(AccountLayout::Header),
+            0 => Ok(AccountLayout::Legacy),
+            1 => Ok(AccountLayout::Header),
+            _ => Err(ProgramError::InvalidAccountData),
+        }
+    }
+
+    /// Version kept in the high nibble of the state byte
+    fn version(self) -> u8 {
+        match self {
+            AccountLayout::Legacy => 0,
+            AccountLayout::Header => 1,
+        }
+    }
+
+    fn fits(self, len: usize) -> bool {
+        len >= self.header_len() && (len - self.header_len()).is_multiple_of(self.owner_len())
+    }
+
+    fn read_weight(self, current: &mut usize, src: &[u8]) -> Result<u32, ProgramError> {
+        let invalid = |_| ProgramError::InvalidAccountData;
+        match self {
+            AccountLayout::Legacy => read_u16(current, src).map(u32::from).map_err(invalid),
+            AccountLayout::Header => read_u32(current, src).map_err(invalid),
+        }
+    }
+
+    fn write_weight(
+        self,
+        current: &mut usize,
+        weight: u32,
+        dst: &mut [u8],
+    ) -> Result<(), ProgramError> {
+        if weight > self.max_weight() {
+            msg!("WalletError: Weight does not fit the account layout");
+            return Err(WalletError::WeightOverflow.into());
+        }
+        match self {
+            AccountLayout::Legacy => write_u16(current, weight as u16, dst),
+            AccountLayout::Header => write_u32(current, weight, dst),
+        }
+    }
+}
+
 impl Account {
     /*
-        Account Len = state   + (pubkey_key + key_weight) * MAX_OWNERS
-                    =    1    + (    32     +      2    ) * MAX_OWNERS
+        Account Len = state + owner_epoch + rotation_delay + pending_rotation + freeze_authority + freeze_weight + address_book + reimbursement_mint + reimbursement_cap + guard + modules * MAX_MODULES + history + (pubkey_key + key_weight) * MAX_OWNERS
+                    =   1   +      8      +       8        +        72        +        32        +       4       +      1       +         32         +         8         +   32  +    32   *      4      +    1    + (    32     +      4    ) * MAX_OWNERS
+             Legacy =   1   +                                                                                                                                                                                + (    32     +      2    ) * MAX_OWNERS
     */
 
+    /// Length of the account data before the owner list
+    pub const HEADER_LEN: usize = 327;
+    /// Maximum number of enabled modules
+    pub const MAX_MODULES: usize = 4;
+    /// Seed prefix of the sub-vaults, derived with the wallet address and the vault index
+    pub const SUB_VAULT_SEED: &'static [u8] = b"vault";
+    /// Seed of the authority a module program signs with, derived with the wallet address
+    pub const MODULE_AUTHORITY_SEED: &'static [u8] = b"wallet_module";
+    /// Length of each owner entry
+    pub const OWNER_LEN: usize = 36;
+    /// Length of each owner entry of legacy wallet accounts
+    pub const LEGACY_OWNER_LEN: usize = 34;
+
     /// give data and parse it as an account
     pub fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        if src.len() == 0 || (src.len() - 1) % 34 != 0 {
+        let mut account = Self::unpack_header_from_slice(src)?;
+        for (pubkey, weight) in Owners::from_account_data(src)?.iter() {
+            account.owners.insert(pubkey, weight);
+        }
+        Ok(account)
+    }
+
+    /// check the account data length and that the program has not closed it, returns the layout
+    fn check_account_data(src: &[u8]) -> Result<AccountLayout, ProgramError> {
+        if is_closed_account(src) {
+            msg!("wallet account has been closed");
+            return Err(WalletError::AccountClosed.into());
+        }
+        let layout = AccountLayout::of(src)?;
+        if !layout.fits(src.len()) {
             msg!(&format!("check account length falied, len: {}", src.len()));
             return Err(ProgramError::InvalidAccountData);
         }
+        Ok(layout)
+    }
 
-        let mut current = 0;
-        let state = read_u8(&mut current, src).unwrap();
+    /// parse the account fields before the owner list, leaving the owners empty. Read the owners
+    /// in place with `Owners`, and only store the result back with `pack_header_into_slice`
+    pub fn unpack_header_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let layout = Self::check_account_data(src)?;
+        if layout == AccountLayout::Legacy {
+            return Ok(Account {
+                state: AccountState::try_from_primitive(src[0])
+                    .or(Err(ProgramError::InvalidAccountData))?,
+                layout,
+                max_owners: (src.len() - 1) / Self::LEGACY_OWNER_LEN,
+                ..Account::default()
+            });
+        }
 
-        let mut owners = BTreeMap::new();
-        while current < src.len() {
-            let pubkey = read_pubkey(&mut current, src).unwrap();
-            let weight = read_u16(&mut current, src).unwrap();
-            if weight == 0 {
-                break;
+        let mut current = 0;
+        let state = read_u8(&mut current, src).unwrap() & 0x0f;
+        let owner_epoch = read_u64(&mut current, src).unwrap();
+        let rotation_delay = read_u64(&mut current, src).unwrap();
+        let pending_rotation = KeyRotation {
+            old_key: read_pubkey(&mut current, src).unwrap(),
+            new_key: read_pubkey(&mut current, src).unwrap(),
+            ready_at: read_u64(&mut current, src).unwrap() as i64,
+        };
+        let freeze_authority = read_pubkey(&mut current, src).unwrap();
+        let freeze_weight = layout.read_weight(&mut current, src)?;
+        let address_book =
+            read_bool(&mut current, src).or(Err(ProgramError::InvalidAccountData))?;
+        let reimbursement_mint = read_pubkey(&mut current, src).unwrap();
+        let reimbursement_cap = read_u64(&mut current, src).unwrap();
+        let guard = read_pubkey(&mut current, src).unwrap();
+        let mut modules = Vec::new();
+        for _ in 0..Self::MAX_MODULES {
+            let module = read_pubkey(&mut current, src).unwrap();
+            if module != Pubkey::default() {
+                modules.push(module);
             }
-            owners.insert(pubkey, weight);
         }
+        let history = read_bool(&mut current, src).or(Err(ProgramError::InvalidAccountData))?;
+
         Ok(Account {
             state: AccountState::try_from_primitive(state)
                 .or(Err(ProgramError::InvalidAccountData))?,
-            owners: owners,
-            max_owners: (src.len() - 1) / 34,
+            owner_epoch,
+            rotation_delay,
+            pending_rotation: if pending_rotation == KeyRotation::default() {
+                None
+            } else {
+                Some(pending_rotation)
+            },
+            freeze_authority: if freeze_authority == Pubkey::default() {
+                None
+            } else {
+                Some(freeze_authority)
+            },
+            freeze_weight,
+            address_book,
+            reimbursement_mint: if reimbursement_mint == Pubkey::default() {
+                None
+            } else {
+                Some(reimbursement_mint)
+            },
+            reimbursement_cap,
+            guard: if guard == Pubkey::default() {
+                None
+            } else {
+                Some(guard)
+            },
+            modules,
+            history,
+            layout,
+            owners: BTreeMap::new(),
+            max_owners: (src.len() - layout.header_len()) / layout.owner_len(),
         })
     }
 
@@ -65,18 +280,313 @@ impl Account {
             *i = 0;
         }
 
-        let mut current = 0;
-        dst[current] = (self.state as u8).into();
-        current += 1;
+        self.pack_header_into_slice(dst)?;
+        let mut current = self.layout.header_len();
         for (pubkey, weight) in &self.owners {
             // pubkey
             write_pubkey(&mut current, pubkey, dst)?;
             // key weight
-            write_u16(&mut current, *weight, dst)?;
+            self.layout.write_weight(&mut current, *weight, dst)?;
         }
 
         Ok(())
     }
+
+    /// store the account fields before the owner list, leaving the packed owners untouched
+    pub fn pack_header_into_slice(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < self.layout.header_len() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if self.layout == AccountLayout::Legacy {
+            if !self.fits_legacy_layout() {
+                msg!("WalletError: Legacy wallet accounts only store the state and the owners");
+                return Err(WalletError::UnsupportedLayout.into());
+            }
+            dst[0] = self.state as u8;
+            return Ok(());
+        }
+
+        let mut current = 0;
+        dst[current] = self.layout.version() << 4 | self.state as u8;
+        current += 1;
+        write_u64(&mut current, self.owner_epoch, dst)?;
+        write_u64(&mut current, self.rotation_delay, dst)?;
+        let pending_rotation = self.pending_rotation.unwrap_or_default();
+        write_pubkey(&mut current, &pending_rotation.old_key, dst)?;
+        write_pubkey(&mut current, &pending_rotation.new_key, dst)?;
+        write_u64(&mut current, pending_rotation.ready_at as u64, dst)?;
+        write_pubkey(
+            &mut current,
+            &self.freeze_authority.unwrap_or_default(),
+            dst,
+        )?;
+        self.layout
+            .write_weight(&mut current, self.freeze_weight, dst)?;
+        write_bool(&mut current, self.address_book, dst)?;
+        write_pubkey(
+            &mut current,
+            &self.reimbursement_mint.unwrap_or_default(),
+            dst,
+        )?;
+        write_u64(&mut current, self.reimbursement_cap, dst)?;
+        write_pubkey(&mut current, &self.guard.unwrap_or_default(), dst)?;
+        if self.modules.len() > Self::MAX_MODULES {
+            return Err(WalletError::ModuleLimitReached.into());
+        }
+        for index in 0..Self::MAX_MODULES {
+            let module = self.modules.get(index).copied().unwrap_or_default();
+            write_pubkey(&mut current, &module, dst)?;
+        }
+        write_bool(&mut current, self.history, dst)?;
+
+        Ok(())
+    }
+
+    /// Whether the settings beyond the state and the owners hold their defaults
+    fn fits_legacy_layout(&self) -> bool {
+        self.rotation_delay == 0
+            && self.pending_rotation.is_none()
+            && self.freeze_authority.is_none()
+            && self.freeze_weight == 0
+            && !self.address_book
+            && self.reimbursement_mint.is_none()
+            && self.reimbursement_cap == 0
+            && self.guard.is_none()
+            && self.modules.is_empty()
+            && !self.history
+    }
+
+    /// Sum the weight of the given signers. Keys which are not owners are ignored and each owner
+    /// is counted once. Returns the weight and the owners that were counted.
+    pub fn signed_weight<'a, I>(&self, signers: I) -> (u64, Vec<Pubkey>)
+    where
+        I: IntoIterator<Item = &'a Pubkey>,
+    {
+        sum_signed_weight(signers, |signer| self.owners.get(signer).copied())
+    }
+
+    /// mark the owner set as changed, invalidating anything staged by the previous owners
+    pub fn increase_owner_epoch(&mut self) {
+        self.owner_epoch = self.owner_epoch.wrapping_add(1);
+        self.pending_rotation = None;
+    }
+}
+
+/// Sum the weight of the given signers, looking each one up once with `weight`
+fn sum_signed_weight<'a, I, F>(signers: I, weight: F) -> (u64, Vec<Pubkey>)
+where
+    I: IntoIterator<Item = &'a Pubkey>,
+    F: Fn(&Pubkey) -> Option<u32>,
+{
+    let mut total_key_weight = 0u64;
+    let mut counted = BTreeMap::new();
+
+    for signer in signers {
+        if counted.contains_key(signer) {
+            continue;
+        }
+        if let Some(weight) = weight(signer) {
+            counted.insert(signer, true);
+            total_key_weight = total_key_weight.saturating_add(u64::from(weight));
+        }
+    }
+
+    (
+        total_key_weight,
+        counted.keys().map(|pubkey| **pubkey).collect(),
+    )
+}
+
+/// Zero-copy view over the owners packed in wallet account data. Owners are packed sorted by
+/// public key and end at the first entry with a zero weight, so lookups binary search in place
+/// instead of decoding the whole owner set.
+#[derive(Clone, Copy, Debug)]
+pub struct Owners<'a> {
+    data: &'a [u8],
+    layout: AccountLayout,
+}
+
+impl<'a> Owners<'a> {
+    /// View the owners of packed wallet account data
+    pub fn from_account_data(data: &'a [u8]) -> Result<Self, ProgramError> {
+        let layout = Account::check_account_data(data)?;
+        Ok(Owners {
+            data: &data[layout.header_len()..],
+            layout,
+        })
+    }
+
+    /// Number of owner entries the account has room for
+    pub fn capacity(&self) -> usize {
+        self.data.len() / self.layout.owner_len()
+    }
+
+    /// Number of owners
+    pub fn len(&self) -> usize {
+        // entries with a weight come first, empty entries fill the rest
+        let (mut low, mut high) = (0, self.capacity());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.weight_at(mid) == 0 {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        low
+    }
+
+    /// Whether the wallet has no owners
+    pub fn is_empty(&self) -> bool {
+        self.capacity() == 0 || self.weight_at(0) == 0
+    }
+
+    /// Owner and weight at the given position
+    pub fn get(&self, index: usize) -> (Pubkey, u32) {
+        let offset = index * self.layout.owner_len();
+        (
+            Pubkey::new(&self.data[offset..offset + 32]),
+            self.weight_at(index),
+        )
+    }
+
+    /// Iterate the owners in public key order
+    pub fn iter(&self) -> impl Iterator<Item = (Pubkey, u32)> + 'a {
+        let owners = *self;
+        (0..owners.len()).map(move |index| owners.get(index))
+    }
+
+    /// Weight of the owner, None when the key is not an owner
+    pub fn weight(&self, pubkey: &Pubkey) -> Option<u32> {
+        self.position(pubkey)
+            .ok()
+            .map(|index| self.weight_at(index))
+    }
+
+    /// Sum of all owner weights
+    pub fn total_weight(&self) -> u64 {
+        self.iter().fold(0u64, |total, (_, weight)| {
+            total.saturating_add(u64::from(weight))
+        })
+    }
+
+    /// Same as `Account::signed_weight`, looking each signer up in place
+    pub fn signed_weight<'b, I>(&self, signers: I) -> (u64, Vec<Pubkey>)
+    where
+        I: IntoIterator<Item = &'b Pubkey>,
+    {
+        sum_signed_weight(signers, |signer| self.weight(signer))
+    }
+
+    /// Position of the owner, or where it would be inserted
+    fn position(&self, pubkey: &Pubkey) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let offset = mid * self.layout.owner_len();
+            let key = &self.data[offset..offset + 32];
+            match key.cmp(pubkey.as_ref()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    fn weight_at(&self, index: usize) -> u32 {
+        let mut current = index * self.layout.owner_len() + 32;
+        self.layout
+            .read_weight(&mut current, self.data)
+            .unwrap_or_default()
+    }
+}
+
+/// Mutable zero-copy view over the owners packed in wallet account data, keeping them sorted
+#[derive(Debug)]
+pub struct OwnersMut<'a> {
+    data: &'a mut [u8],
+    layout: AccountLayout,
+}
+
+impl<'a> OwnersMut<'a> {
+    /// View the owners of packed wallet account data for updates in place
+    pub fn from_account_data(data: &'a mut [u8]) -> Result<Self, ProgramError> {
+        let layout = Account::check_account_data(data)?;
+        Ok(OwnersMut {
+            data: &mut data[layout.header_len()..],
+            layout,
+        })
+    }
+
+    /// Read-only view of the owners
+    pub fn as_owners(&self) -> Owners<'_> {
+        Owners {
+            data: self.data,
+            layout: self.layout,
+        }
+    }
+
+    /// Insert a new owner, shifting the owners after it
+    pub fn insert(&mut self, pubkey: Pubkey, weight: u32) -> Result<(), ProgramError> {
+        if weight == 0 {
+            msg!("WalletError: Key weight cannot be 0");
+            return Err(WalletError::ZeroKeyWeight.into());
+        }
+        let owners = self.as_owners();
+        let len = owners.len();
+        let index = match owners.position(&pubkey) {
+            Ok(_) => {
+                msg!("WalletError: Owner already exists");
+                return Err(WalletError::OwnerAlreadyExists.into());
+            }
+            Err(index) => index,
+        };
+        if len >= owners.capacity() {
+            msg!("WalletError: too many owners");
+            return Err(WalletError::TooManyOwners.into());
+        }
+        if weight > self.layout.max_weight() {
+            msg!("WalletError: Weight does not fit the account layout");
+            return Err(WalletError::WeightOverflow.into());
+        }
+
+        let owner_len = self.layout.owner_len();
+        self.data
+            .copy_within(index * owner_len..len * owner_len, (index + 1) * owner_len);
+        let mut current = index * owner_len;
+        write_pubkey(&mut current, &pubkey, self.data)?;
+        self.layout.write_weight(&mut current, weight, self.data)
+    }
+
+    /// Remove an owner, shifting the owners after it. Returns the removed weight, None when the
+    /// key is not an owner
+    pub fn remove(&mut self, pubkey: &Pubkey) -> Option<u32> {
+        let owners = self.as_owners();
+        let len = owners.len();
+        let index = owners.position(pubkey).ok()?;
+        let weight = owners.weight_at(index);
+
+        let owner_len = self.layout.owner_len();
+        self.data
+            .copy_within((index + 1) * owner_len..len * owner_len, index * owner_len);
+        for byte in &mut self.data[(len - 1) * owner_len..len * owner_len] {
+            *byte = 0;
+        }
+        Some(weight)
+    }
+}
+
+/// An owner key rotation waiting for the wallet's rotation delay
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyRotation {
+    /// owner key being replaced
+    pub old_key: Pubkey,
+    /// key taking over the owner's weight
+    pub new_key: Pubkey,
+    /// unix timestamp from which the rotation can be completed
+    pub ready_at: i64,
 }
 
 impl Sealed for Account {}
@@ -89,19 +599,16 @@ impl IsInitialized for Account {
 
 /// Account state.
 #[repr(u8)]
-#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, TryFromPrimitive)]
 pub enum AccountState {
     /// Account is not yet initialized
+    #[default]
     Uninitialized,
     /// Account is initialized; the account owner and/or delegate may perform permitted operations
     /// on this account
     Initialized,
-}
-
-impl Default for AccountState {
-    fn default() -> Self {
-        AccountState::Uninitialized
-    }
+    /// Account is frozen; nothing can leave the wallet vault until the owners unfreeze it
+    Frozen,
 }
 
 #[cfg(test)]
@@ -118,13 +625,29 @@ mod test {
 
         let mut account = Account {
             state: AccountState::Initialized,
-            owners: BTreeMap::<Pubkey, u16>::new(),
+            owner_epoch: 3,
+            rotation_delay: 86400,
+            pending_rotation: Some(KeyRotation {
+                old_key: pubkey1,
+                new_key: pubkey2,
+                ready_at: 1_700_000_000,
+            }),
+            freeze_authority: Some(pubkey2),
+            freeze_weight: 300,
+            address_book: true,
+            reimbursement_mint: Some(pubkey1),
+            reimbursement_cap: 5000,
+            guard: Some(pubkey2),
+            modules: vec![pubkey1],
+            history: true,
+            layout: AccountLayout::Header,
+            owners: BTreeMap::<Pubkey, u32>::new(),
             max_owners: 101,
         };
         account.owners.insert(pubkey1, 999);
         account.owners.insert(pubkey2, 1);
 
-        let mut dst = vec![0x00; 3435];
+        let mut dst = vec![0x00; 3963];
 
         assert_eq!(account.pack_into_slice(&mut dst), Ok(()));
 
@@ -133,19 +656,152 @@ mod test {
         assert_eq!(account, unpack_account);
     }
 
+    #[test]
+    fn test_owners_lookup_and_update_in_place() {
+        let keys = [
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        ];
+        let account = Account {
+            state: AccountState::Initialized,
+            owners: btreemap! { keys[0] => 500, keys[2] => 300, keys[3] => 200 },
+            ..Account::default()
+        };
+        let mut dst = vec![0x00; Account::HEADER_LEN + 4 * Account::OWNER_LEN];
+        assert_eq!(account.pack_into_slice(&mut dst), Ok(()));
+
+        let owners = Owners::from_account_data(&dst).unwrap();
+        assert_eq!((owners.len(), owners.capacity()), (3, 4));
+        assert_eq!(owners.weight(&keys[2]), Some(300));
+        assert_eq!(owners.weight(&keys[1]), None);
+        assert_eq!(
+            owners.signed_weight(&[keys[3], keys[1], keys[3], keys[0]]),
+            (700, vec![keys[0], keys[3]])
+        );
+        assert_eq!(
+            account.signed_weight(&[keys[3], keys[1], keys[3], keys[0]]),
+            owners.signed_weight(&[keys[3], keys[1], keys[3], keys[0]])
+        );
+
+        let mut owners = OwnersMut::from_account_data(&mut dst).unwrap();
+        assert_eq!(owners.insert(keys[1], 100), Ok(()));
+        assert_eq!(
+            owners.insert(keys[1], 100),
+            Err(WalletError::OwnerAlreadyExists.into())
+        );
+        assert_eq!(
+            owners.insert(Pubkey::new_unique(), 100),
+            Err(WalletError::TooManyOwners.into())
+        );
+        assert_eq!(owners.remove(&keys[2]), Some(300));
+        assert_eq!(owners.remove(&keys[2]), None);
+
+        let mut expected = vec![0x00; dst.len()];
+        Account {
+            owners: btreemap! { keys[0] => 500, keys[1] => 100, keys[3] => 200 },
+            ..account
+        }
+        .pack_into_slice(&mut expected)
+        .unwrap();
+        assert_eq!(dst, expected);
+    }
+
+    #[test]
+    fn test_extreme_owner_weights() {
+        for (layout, weight) in [
+            (AccountLayout::Legacy, u32::from(u16::MAX)),
+            (AccountLayout::Header, u32::MAX),
+        ] {
+            let keys: Vec<Pubkey> = (0..101).map(|_| Pubkey::new_unique()).collect();
+            let account = Account {
+                state: AccountState::Initialized,
+                // legacy wallets keep no freeze weight
+                freeze_weight: if layout == AccountLayout::Header {
+                    weight
+                } else {
+                    0
+                },
+                layout,
+                owners: keys.iter().map(|key| (*key, weight)).collect(),
+                max_owners: 101,
+                ..Account::default()
+            };
+            let mut dst = vec![0x00; layout.header_len() + 101 * layout.owner_len()];
+            assert_eq!(account.pack_into_slice(&mut dst), Ok(()));
+            assert_eq!(AccountLayout::of(&dst), Ok(layout));
+            assert_eq!(Account::unpack_from_slice(&dst), Ok(account.clone()));
+
+            let total = 101 * u64::from(weight);
+            let owners = Owners::from_account_data(&dst).unwrap();
+            assert_eq!(owners.total_weight(), total);
+            assert_eq!(owners.signed_weight(&keys).0, total);
+            assert_eq!(account.signed_weight(&keys).0, total);
+        }
+
+        let account = Account {
+            state: AccountState::Initialized,
+            layout: AccountLayout::Legacy,
+            owners: btreemap! { Pubkey::new_unique() => u32::from(u16::MAX) + 1 },
+            ..Account::default()
+        };
+        let mut dst = vec![0x00; 1 + Account::LEGACY_OWNER_LEN];
+        assert_eq!(
+            account.pack_into_slice(&mut dst),
+            Err(WalletError::WeightOverflow.into())
+        );
+    }
+
+    #[test]
+    fn test_legacy_account_unpack() {
+        let pubkey1 = Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap();
+        let pubkey2 = Pubkey::from_str("EvN4kgKmCmYzdbd5kL8Q8YgkUW5RoqMTpBczrfLExtx7").unwrap();
+        // state + (pubkey + u16 weight) * 101 owners, as packed before the account header
+        let mut src = vec![0x00; 1 + 34 * 101];
+        src[0] = 1;
+        src[1..33].copy_from_slice(pubkey1.as_ref());
+        src[33..35].copy_from_slice(&2000u16.to_le_bytes());
+        src[35..67].copy_from_slice(pubkey2.as_ref());
+        src[67..69].copy_from_slice(&1000u16.to_le_bytes());
+
+        assert_eq!(AccountLayout::of(&src), Ok(AccountLayout::Legacy));
+        let mut account = Account::unpack_from_slice(&src).unwrap();
+        assert_eq!(account.state, AccountState::Initialized);
+        assert_eq!(account.layout, AccountLayout::Legacy);
+        assert_eq!(account.owner_epoch, 0);
+        assert_eq!(account.max_owners, 101);
+        assert_eq!(
+            account.owners,
+            btreemap! { pubkey1 => 2000, pubkey2 => 1000 }
+        );
+
+        let mut dst = vec![0x00; src.len()];
+        assert_eq!(account.pack_into_slice(&mut dst), Ok(()));
+        assert_eq!(dst, src);
+
+        account.guard = Some(Pubkey::new_unique());
+        assert_eq!(
+            account.pack_into_slice(&mut dst),
+            Err(WalletError::UnsupportedLayout.into())
+        );
+    }
+
     #[test]
     fn test_account_pack_into_exist_data() {
-        let mut account_dst1 = vec![0x00; 3435];
-        let mut account_dst2 = vec![0x00; 3435];
+        let mut account_dst1 = vec![0x00; 3759];
+        let mut account_dst2 = vec![0x00; 3759];
 
         // create a init account
         let mut account = Account {
             state: AccountState::Initialized,
+            owner_epoch: 1,
             owners: btreemap! {
               Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap() => 1000,
               Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap() => 1000,
             },
             max_owners: 101,
+            ..Account::default()
         };
         assert_eq!(account.pack_into_slice(&mut account_dst1), Ok(()));
 
@@ -161,6 +817,296 @@ mod test {
         // compare
         assert_eq!(account_dst1, account_dst2)
     }
+
+    #[test]
+    fn test_session_pack() {
+        let session = Session {
+            wallet: Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap(),
+            session_key: Pubkey::from_str("EvN4kgKmCmYzdbd5kL8Q8YgkUW5RoqMTpBczrfLExtx7").unwrap(),
+            expires_at: 1_700_003_600,
+            lamport_cap: 1_000_000,
+            lamports_spent: 10,
+            token_mint: Some(
+                Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap(),
+            ),
+            token_cap: 500,
+            tokens_spent: 0,
+            allowed_programs: vec![Pubkey::from_str(
+                "65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u",
+            )
+            .unwrap()],
+            data_prefixes: vec![vec![3], vec![12, 0]],
+        };
+
+        let mut dst = vec![0x00; session.get_packed_len()];
+        assert_eq!(session.pack(&mut dst), Ok(()));
+        assert_eq!(Session::unpack(&dst).unwrap(), session);
+
+        // a wallet account is not a session record
+        assert_eq!(
+            Session::unpack(&[0x01; Account::HEADER_LEN]),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_session_scope_and_caps() {
+        let program_id = Pubkey::new_unique();
+        let mut session = Session {
+            lamport_cap: 100,
+            token_cap: 0,
+            allowed_programs: vec![program_id],
+            data_prefixes: vec![vec![2, 0]],
+            ..Session::default()
+        };
+        let instruction = |program_id, data| Instruction {
+            program_id,
+            accounts: vec![],
+            data,
+        };
+
+        assert_eq!(
+            session.check_scope(&instruction(program_id, vec![2, 0, 0, 1])),
+            Ok(())
+        );
+        assert_eq!(
+            session.check_scope(&instruction(program_id, vec![3, 0])),
+            Err(WalletError::SessionScopeViolation.into())
+        );
+        assert_eq!(
+            session.check_scope(&instruction(Pubkey::new_unique(), vec![2, 0])),
+            Err(WalletError::SessionScopeViolation.into())
+        );
+
+        assert_eq!(session.record_spending(60, 0), Ok(()));
+        assert_eq!(
+            session.record_spending(41, 0),
+            Err(WalletError::SessionCapExceeded.into())
+        );
+        assert_eq!(
+            session.record_spending(0, 1),
+            Err(WalletError::SessionCapExceeded.into())
+        );
+        assert_eq!(session.record_spending(40, 0), Ok(()));
+        assert_eq!(session.lamports_spent, 100);
+    }
+
+    #[test]
+    fn test_address_book_pack() {
+        let address1 = Pubkey::from_str("EvN4kgKmCmYzdbd5kL8Q8YgkUW5RoqMTpBczrfLExtx7").unwrap();
+        let address2 = Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap();
+        let address_book = AddressBook {
+            wallet: Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap(),
+            activation_delay: 3600,
+            capacity: 3,
+            entries: btreemap! { address1 => 100, address2 => 3700 },
+        };
+
+        let mut dst = vec![0x00; AddressBook::get_packed_len(3)];
+        assert_eq!(address_book.pack(&mut dst), Ok(()));
+        assert_eq!(AddressBook::unpack(&dst).unwrap(), address_book);
+
+        assert!(address_book.is_active(&address1, 100));
+        assert!(!address_book.is_active(&address2, 3699));
+        assert!(!address_book.is_active(&Pubkey::new_unique(), 3700));
+    }
+
+    #[test]
+    fn test_scheduled_payment_pack_and_due() {
+        let mut schedule = ScheduledPayment {
+            wallet: Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap(),
+            id: 1,
+            recipient: Pubkey::from_str("EvN4kgKmCmYzdbd5kL8Q8YgkUW5RoqMTpBczrfLExtx7").unwrap(),
+            mint: None,
+            amount: 1_000,
+            interval: 100,
+            start: 1_000,
+            end: 0,
+            count: 2,
+            executed: 0,
+        };
+
+        let mut dst = vec![0x00; ScheduledPayment::LEN];
+        assert_eq!(schedule.pack(&mut dst), Ok(()));
+        assert_eq!(ScheduledPayment::unpack(&dst).unwrap(), schedule);
+
+        // not before the start, once per interval, at most count times
+        assert_eq!(
+            schedule.record_execution(999),
+            Err(WalletError::PaymentNotDue.into())
+        );
+        assert_eq!(schedule.record_execution(1_000), Ok(()));
+        assert_eq!(
+            schedule.record_execution(1_099),
+            Err(WalletError::PaymentNotDue.into())
+        );
+        // a late payment does not leave the missed ones due
+        schedule.count = 3;
+        assert_eq!(schedule.record_execution(5_000), Ok(()));
+        assert_eq!(schedule.start, 4_900);
+        assert_eq!(schedule.next_due(), Some(5_100));
+        assert_eq!(
+            schedule.record_execution(5_099),
+            Err(WalletError::PaymentNotDue.into())
+        );
+        assert_eq!(schedule.record_execution(5_100), Ok(()));
+        assert_eq!(
+            schedule.record_execution(10_000),
+            Err(WalletError::ScheduleFinished.into())
+        );
+
+        // no payment is due past the end
+        schedule.count = 0;
+        schedule.end = 1_150;
+        assert_eq!(schedule.next_due(), None);
+
+        // a payment later than the schedule can represent fails instead of overflowing
+        schedule.end = 0;
+        schedule.start = i64::MIN;
+        schedule.executed = 0;
+        assert_eq!(
+            schedule.record_execution(i64::MAX),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_stream_pack_and_vesting() {
+        let stream = Stream {
+            wallet: Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap(),
+            id: 1,
+            recipient: Pubkey::from_str("EvN4kgKmCmYzdbd5kL8Q8YgkUW5RoqMTpBczrfLExtx7").unwrap(),
+            mint: Some(Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap()),
+            total: 1_000,
+            start: 1_000,
+            cliff: 1_250,
+            end: 2_000,
+            withdrawn: 100,
+        };
+
+        let mut dst = vec![0x00; Stream::LEN];
+        assert_eq!(stream.pack(&mut dst), Ok(()));
+        assert_eq!(Stream::unpack(&dst).unwrap(), stream);
+
+        assert_eq!(stream.vested(1_249), 0);
+        assert_eq!(stream.vested(1_250), 250);
+        assert_eq!(stream.withdrawable(1_500), 400);
+        assert_eq!(stream.vested(3_000), 1_000);
+        assert_eq!(stream.withdrawable(3_000), 900);
+    }
+
+    #[test]
+    fn test_history_append_wraps_around() {
+        let wallet = Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap();
+        let history = History {
+            wallet,
+            capacity: 2,
+            total: 0,
+            entries: vec![],
+        };
+        let mut dst = vec![0x00; History::get_packed_len(2)];
+        assert_eq!(history.pack(&mut dst), Ok(()));
+
+        let entry = |slot: u64| HistoryEntry {
+            slot,
+            kind: 3,
+            program_id: wallet,
+            data_hash: [slot as u8; 32],
+            weight: 1000,
+            signers: 1,
+        };
+        for slot in 1..=3 {
+            assert_eq!(History::append(&mut dst, &wallet, &entry(slot)), Ok(()));
+        }
+        let history = History::unpack(&dst).unwrap();
+        assert_eq!(history.total, 3);
+        assert_eq!(history.entries, vec![entry(2), entry(3)]);
+
+        let mut repacked = vec![0x00; History::get_packed_len(2)];
+        assert_eq!(history.pack(&mut repacked), Ok(()));
+        assert_eq!(repacked, dst);
+
+        assert_eq!(
+            History::append(&mut dst, &Pubkey::new_unique(), &entry(4)),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_history_weight_versions() {
+        let wallet = Pubkey::new_unique();
+        let entry = |weight: u64| HistoryEntry {
+            slot: 1,
+            kind: 3,
+            program_id: wallet,
+            data_hash: [7; 32],
+            weight,
+            signers: 2,
+        };
+
+        let mut dst = vec![0x00; History::get_packed_len(2)];
+        History {
+            wallet,
+            capacity: 2,
+            total: 0,
+            entries: vec![],
+        }
+        .pack(&mut dst)
+        .unwrap();
+        let weight = 2 * u64::from(u32::MAX);
+        assert_eq!(History::append(&mut dst, &wallet, &entry(weight)), Ok(()));
+        assert_eq!(History::unpack(&dst).unwrap().entries, vec![entry(weight)]);
+    }
+
+    #[test]
+    fn test_instruction_buffer_pack() {
+        let instruction_buffer = InstructionBuffer {
+            owner: Pubkey::from_str("EvN4kgKmCmYzdbd5kL8Q8YgkUW5RoqMTpBczrfLExtx7").unwrap(),
+            wallet: Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap(),
+            owner_epoch: 7,
+            vault_index: 2,
+            data: vec![0x01; 24],
+        };
+
+        let mut dst = vec![0x00; 98];
+        assert_eq!(
+            InstructionBuffer::pack(instruction_buffer.clone(), &mut dst),
+            Ok(())
+        );
+
+        assert_eq!(InstructionBuffer::unpack(&dst).unwrap(), instruction_buffer);
+        assert_eq!(
+            InstructionBuffer::unpack(&dst[..InstructionBuffer::HEADER_LEN - 1]),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_instruction_buffer_filter_by_wallet() {
+        let wallet = Pubkey::from_str("A4iUVr5KjmsLymUcv4eSKPedUtoaBceiPeGipKMYc69b").unwrap();
+        let other_wallet =
+            Pubkey::from_str("EmPaWGCw48Sxu9Mu9pVrxe4XL2JeXUNTfoTXLuLz31gv").unwrap();
+        let buffer1 = Pubkey::from_str("EvN4kgKmCmYzdbd5kL8Q8YgkUW5RoqMTpBczrfLExtx7").unwrap();
+        let buffer2 = Pubkey::from_str("65JQyZBU2RzNpP9vTdW5zSzujZR5JHZyChJsDWvkbM8u").unwrap();
+
+        let mut data1 = vec![0x00; 96];
+        let mut data2 = vec![0x00; 96];
+        for (dst, wallet) in [(&mut data1, wallet), (&mut data2, other_wallet)] {
+            let instruction_buffer = InstructionBuffer {
+                wallet,
+                ..InstructionBuffer::default()
+            };
+            InstructionBuffer::pack(instruction_buffer, dst).unwrap();
+        }
+
+        assert_eq!(
+            InstructionBuffer::filter_by_wallet(
+                &wallet,
+                vec![(&buffer1, &data1[..]), (&buffer2, &data2[..])]
+            ),
+            vec![buffer1]
+        );
+    }
 }
 
 /// InstructionBuffer
@@ -170,22 +1116,96 @@ pub struct InstructionBuffer {
     /// instruction buffer owner
     pub owner: Pubkey,
 
+    /// the wallet this buffer is staged for
+    pub wallet: Pubkey,
+
+    /// wallet owner epoch when the buffer was initialized, only that owner set can stage and run it
+    pub owner_epoch: u64,
+
+    /// the vault signing the staged instructions, 0 is the wallet vault
+    pub vault_index: u16,
+
     /// data
     pub data: Vec<u8>,
 }
 
 impl InstructionBuffer {
+    /*
+        InstructionBuffer Len = owner + wallet + owner_epoch + vault_index + data
+                              =  32   +   32   +      8      +      2      + data
+    */
+
+    /// Offset of the wallet public key in the account data
+    pub const WALLET_OFFSET: usize = 32;
+
+    /// Length of the account data before the staged instructions
+    pub const HEADER_LEN: usize = 74;
+
+    /// Pick out the instruction buffers staged for a wallet from a list of (address, data).
+    /// Clients can use `WALLET_OFFSET` with a memcmp filter to fetch the candidates.
+    pub fn filter_by_wallet<'a, I>(wallet: &Pubkey, accounts: I) -> Vec<Pubkey>
+    where
+        I: IntoIterator<Item = (&'a Pubkey, &'a [u8])>,
+    {
+        accounts
+            .into_iter()
+            .filter(|(_, data)| {
+                data.len() >= Self::WALLET_OFFSET + 32
+                    && data[Self::WALLET_OFFSET..Self::WALLET_OFFSET + 32] == wallet.to_bytes()
+            })
+            .map(|(pubkey, _)| *pubkey)
+            .collect()
+    }
+
     /// Unpack from slice
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        if is_closed_account(input) {
+            msg!("instruction buffer account has been closed");
+            return Err(WalletError::AccountClosed.into());
+        }
+
+        let invalid = |_| ProgramError::InvalidAccountData;
         let mut current = 0;
 
         // parse owner
-        let owner = read_pubkey(&mut current, input).unwrap();
+        let owner = read_pubkey(&mut current, input).map_err(invalid)?;
+
+        // parse wallet
+        let wallet = read_pubkey(&mut current, input).map_err(invalid)?;
+
+        // parse owner epoch
+        let owner_epoch = read_u64(&mut current, input).map_err(invalid)?;
+
+        // parse vault index
+        let vault_index = read_u16(&mut current, input).map_err(invalid)?;
 
         // parse data
-        let data: Vec<u8> = input[current..].iter().cloned().collect();
+        let data = input[current..].to_vec();
+
+        Ok(InstructionBuffer {
+            owner,
+            wallet,
+            owner_epoch,
+            vault_index,
+            data,
+        })
+    }
 
-        Ok(InstructionBuffer { owner, data })
+    /// Parse the staged instructions, stopping at the first empty instruction
+    pub fn instructions(&self) -> Result<Vec<Instruction>, ProgramError> {
+        let mut current = 0;
+        let mut instructions = Vec::new();
+        while current < self.data.len() {
+            let instruction = read_instruction(&mut current, &self.data[..])?;
+            if instruction.program_id == Pubkey::default()
+                && instruction.accounts.is_empty()
+                && instruction.data.is_empty()
+            {
+                break;
+            }
+            instructions.push(instruction);
+        }
+        Ok(instructions)
     }
 
     /// Pack into slice
@@ -200,9 +1220,751 @@ impl InstructionBuffer {
         // write owner
         write_pubkey(&mut current, &src.owner, dst)?;
 
+        // write wallet
+        write_pubkey(&mut current, &src.wallet, dst)?;
+
+        // write owner epoch
+        write_u64(&mut current, src.owner_epoch, dst)?;
+
+        // write vault index
+        write_u16(&mut current, src.vault_index, dst)?;
+
         // write data
         dst[current..current + src.data.len()].clone_from_slice(&src.data);
 
         Ok(())
     }
 }
+
+/// Session record, a PDA of the wallet letting a session key invoke programs within a scope
+#[repr(C)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Session {
+    /// the wallet the session acts for
+    pub wallet: Pubkey,
+    /// key allowed to sign for the session
+    pub session_key: Pubkey,
+    /// unix timestamp the session expires at
+    pub expires_at: i64,
+    /// lamports the session may move out of the wallet vault
+    pub lamport_cap: u64,
+    /// lamports moved out of the wallet vault so far
+    pub lamports_spent: u64,
+    /// the only token mint the session may spend, None allows no token spending
+    pub token_mint: Option<Pubkey>,
+    /// tokens the session may move out of the wallet vault token accounts
+    pub token_cap: u64,
+    /// tokens moved out of the wallet vault token accounts so far
+    pub tokens_spent: u64,
+    /// programs the session may invoke
+    pub allowed_programs: Vec<Pubkey>,
+    /// instruction data must start with one of the prefixes, empty allows any data
+    pub data_prefixes: Vec<Vec<u8>>,
+}
+
+impl Session {
+    /*
+        Session Len = discriminator + wallet + session_key + expires_at + lamport_cap + lamports_spent
+                    =       8       +   32   +     32      +     8      +      8      +       8
+                    + token_mint + token_cap + tokens_spent + allowed_programs + data_prefixes
+                    +     32     +     8     +      8       +  2 + 32 * N      + 2 + (2 + len) * M
+    */
+
+    /// Written at the start of session records so they cannot be mistaken for other accounts
+    pub const DISCRIMINATOR: [u8; 8] = *b"session\0";
+
+    /// Seed prefix of the session record address
+    pub const SEED: &'static [u8] = b"session";
+
+    /// Length of the packed session record
+    pub fn get_packed_len(&self) -> usize {
+        8 + 32
+            + 32
+            + 8
+            + 8
+            + 8
+            + 32
+            + 8
+            + 8
+            + 2
+            + self.allowed_programs.len() * 32
+            + 2
+            + self
+                .data_prefixes
+                .iter()
+                .map(|prefix| 2 + prefix.len())
+                .sum::<usize>()
+    }
+
+    /// Unpack from slice
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        if is_closed_account(input) {
+            msg!("session account has been closed");
+            return Err(WalletError::AccountClosed.into());
+        }
+        if !input.starts_with(&Self::DISCRIMINATOR) {
+            msg!("account is not a session record");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let invalid = |_| ProgramError::InvalidAccountData;
+        let mut current = Self::DISCRIMINATOR.len();
+        let wallet = read_pubkey(&mut current, input).map_err(invalid)?;
+        let session_key = read_pubkey(&mut current, input).map_err(invalid)?;
+        let expires_at = read_u64(&mut current, input).map_err(invalid)? as i64;
+        let lamport_cap = read_u64(&mut current, input).map_err(invalid)?;
+        let lamports_spent = read_u64(&mut current, input).map_err(invalid)?;
+        let token_mint = read_pubkey(&mut current, input).map_err(invalid)?;
+        let token_cap = read_u64(&mut current, input).map_err(invalid)?;
+        let tokens_spent = read_u64(&mut current, input).map_err(invalid)?;
+
+        let mut allowed_programs = Vec::new();
+        for _ in 0..read_u16(&mut current, input).map_err(invalid)? {
+            allowed_programs.push(read_pubkey(&mut current, input).map_err(invalid)?);
+        }
+
+        let mut data_prefixes = Vec::new();
+        for _ in 0..read_u16(&mut current, input).map_err(invalid)? {
+            let len = usize::from(read_u16(&mut current, input).map_err(invalid)?);
+            if input.len() < current + len {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            data_prefixes.push(input[current..current + len].to_vec());
+            current += len;
+        }
+
+        Ok(Session {
+            wallet,
+            session_key,
+            expires_at,
+            lamport_cap,
+            lamports_spent,
+            token_mint: if token_mint == Pubkey::default() {
+                None
+            } else {
+                Some(token_mint)
+            },
+            token_cap,
+            tokens_spent,
+            allowed_programs,
+            data_prefixes,
+        })
+    }
+
+    /// Pack into slice
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < self.get_packed_len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        let mut current = 0;
+        dst[..Self::DISCRIMINATOR.len()].copy_from_slice(&Self::DISCRIMINATOR);
+        current += Self::DISCRIMINATOR.len();
+        write_pubkey(&mut current, &self.wallet, dst)?;
+        write_pubkey(&mut current, &self.session_key, dst)?;
+        write_u64(&mut current, self.expires_at as u64, dst)?;
+        write_u64(&mut current, self.lamport_cap, dst)?;
+        write_u64(&mut current, self.lamports_spent, dst)?;
+        write_pubkey(&mut current, &self.token_mint.unwrap_or_default(), dst)?;
+        write_u64(&mut current, self.token_cap, dst)?;
+        write_u64(&mut current, self.tokens_spent, dst)?;
+
+        write_u16(&mut current, self.allowed_programs.len() as u16, dst)?;
+        for program_id in &self.allowed_programs {
+            write_pubkey(&mut current, program_id, dst)?;
+        }
+
+        write_u16(&mut current, self.data_prefixes.len() as u16, dst)?;
+        for prefix in &self.data_prefixes {
+            write_u16(&mut current, prefix.len() as u16, dst)?;
+            dst[current..current + prefix.len()].copy_from_slice(prefix);
+            current += prefix.len();
+        }
+
+        Ok(())
+    }
+
+    /// Check that an instruction is within the session scope
+    pub fn check_scope(&self, instruction: &Instruction) -> Result<(), ProgramError> {
+        if !self.allowed_programs.contains(&instruction.program_id) {
+            msg!(&format!(
+                "session may not invoke {}",
+                instruction.program_id
+            ));
+            return Err(WalletError::SessionScopeViolation.into());
+        }
+        if !self.data_prefixes.is_empty()
+            && !self
+                .data_prefixes
+                .iter()
+                .any(|prefix| instruction.data.starts_with(prefix))
+        {
+            msg!("session may not invoke the instruction data");
+            return Err(WalletError::SessionScopeViolation.into());
+        }
+        Ok(())
+    }
+
+    /// Record lamports and tokens moved out of the wallet vault, failing once a cap is exceeded
+    pub fn record_spending(&mut self, lamports: u64, tokens: u64) -> Result<(), ProgramError> {
+        let lamports_spent = self
+            .lamports_spent
+            .checked_add(lamports)
+            .filter(|spent| *spent <= self.lamport_cap);
+        let tokens_spent = self
+            .tokens_spent
+            .checked_add(tokens)
+            .filter(|spent| *spent <= self.token_cap);
+        match (lamports_spent, tokens_spent) {
+            (Some(lamports_spent), Some(tokens_spent)) => {
+                self.lamports_spent = lamports_spent;
+                self.tokens_spent = tokens_spent;
+                Ok(())
+            }
+            _ => {
+                msg!("WalletError: Session spending cap exceeded");
+                Err(WalletError::SessionCapExceeded.into())
+            }
+        }
+    }
+}
+
+/// Address book, a PDA of the wallet listing the counterparties outgoing transfers may go to
+#[repr(C)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AddressBook {
+    /// the wallet the address book belongs to
+    pub wallet: Pubkey,
+    /// seconds before a new entry becomes active
+    pub activation_delay: u64,
+    /// number of entries the account has room for
+    pub capacity: u16,
+    /// address => unix timestamp the entry becomes active at
+    pub entries: BTreeMap<Pubkey, i64>,
+}
+
+impl AddressBook {
+    /*
+        AddressBook Len = discriminator + wallet + activation_delay + capacity + (address + active_at) * capacity
+                        =       8       +   32   +        8         +    2     + (   32    +     8    ) * capacity
+    */
+
+    /// Written at the start of address books so they cannot be mistaken for other accounts
+    pub const DISCRIMINATOR: [u8; 8] = *b"addrbook";
+
+    /// Seed prefix of the address book address
+    pub const SEED: &'static [u8] = b"address_book";
+
+    /// Length of each entry
+    pub const ENTRY_LEN: usize = 40;
+
+    /// Length of an address book with room for `capacity` entries
+    pub fn get_packed_len(capacity: u16) -> usize {
+        8 + 32 + 8 + 2 + usize::from(capacity) * Self::ENTRY_LEN
+    }
+
+    /// Whether the address is an entry past its activation delay
+    pub fn is_active(&self, address: &Pubkey, now: i64) -> bool {
+        matches!(self.entries.get(address), Some(active_at) if *active_at <= now)
+    }
+
+    /// Unpack from slice
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        if !input.starts_with(&Self::DISCRIMINATOR) {
+            msg!("account is not an address book");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let invalid = |_| ProgramError::InvalidAccountData;
+        let mut current = Self::DISCRIMINATOR.len();
+        let wallet = read_pubkey(&mut current, input).map_err(invalid)?;
+        let activation_delay = read_u64(&mut current, input).map_err(invalid)?;
+        let capacity = read_u16(&mut current, input).map_err(invalid)?;
+
+        let mut entries = BTreeMap::new();
+        for _ in 0..capacity {
+            let address = read_pubkey(&mut current, input).map_err(invalid)?;
+            let active_at = read_u64(&mut current, input).map_err(invalid)? as i64;
+            if address == Pubkey::default() {
+                break;
+            }
+            entries.insert(address, active_at);
+        }
+
+        Ok(AddressBook {
+            wallet,
+            activation_delay,
+            capacity,
+            entries,
+        })
+    }
+
+    /// Pack into slice
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if self.entries.len() > usize::from(self.capacity)
+            || dst.len() < Self::get_packed_len(self.capacity)
+        {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        // reset all byte to 0
+        for i in dst.iter_mut() {
+            *i = 0;
+        }
+
+        let mut current = 0;
+        dst[..Self::DISCRIMINATOR.len()].copy_from_slice(&Self::DISCRIMINATOR);
+        current += Self::DISCRIMINATOR.len();
+        write_pubkey(&mut current, &self.wallet, dst)?;
+        write_u64(&mut current, self.activation_delay, dst)?;
+        write_u16(&mut current, self.capacity, dst)?;
+        for (address, active_at) in &self.entries {
+            write_pubkey(&mut current, address, dst)?;
+            write_u64(&mut current, *active_at as u64, dst)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Scheduled payment, a PDA of the wallet paying a recipient from the wallet vault every interval
+#[repr(C)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScheduledPayment {
+    /// the wallet paying
+    pub wallet: Pubkey,
+    /// id telling the wallet schedules apart
+    pub id: u64,
+    /// key receiving the payments, paid into a token account it owns when paying tokens
+    pub recipient: Pubkey,
+    /// token mint paid, None pays SOL
+    pub mint: Option<Pubkey>,
+    /// amount paid every interval
+    pub amount: u64,
+    /// seconds between payments
+    pub interval: u64,
+    /// unix timestamp the first payment is due at, moved forward by late payments
+    pub start: i64,
+    /// no payment is due after this unix timestamp, 0 never ends
+    pub end: i64,
+    /// number of payments, 0 pays until the end
+    pub count: u64,
+    /// number of payments made so far
+    pub executed: u64,
+}
+
+impl ScheduledPayment {
+    /*
+        ScheduledPayment Len = discriminator + wallet + id + recipient + mint + amount + interval + start + end + count + executed
+                             =       8       +   32   +  8 +    32     +  32  +   8    +    8     +   8   +  8  +   8   +    8
+    */
+
+    /// Written at the start of scheduled payments so they cannot be mistaken for other accounts
+    pub const DISCRIMINATOR: [u8; 8] = *b"schedule";
+
+    /// Seed prefix of the scheduled payment address
+    pub const SEED: &'static [u8] = b"schedule";
+
+    /// Length of the packed scheduled payment
+    pub const LEN: usize = 160;
+
+    /// Unix timestamp the next payment is due at, None once the schedule is finished
+    pub fn next_due(&self) -> Option<i64> {
+        if self.count != 0 && self.executed >= self.count {
+            return None;
+        }
+        let elapsed = self.executed.checked_mul(self.interval)?;
+        let due = self
+            .start
+            .checked_add(elapsed.min(i64::MAX as u64) as i64)?;
+        if self.end != 0 && due > self.end {
+            return None;
+        }
+        Some(due)
+    }
+
+    /// Record a payment made at `now`, failing when no payment is due yet. A late payment moves
+    /// the schedule forward so the next one is due an interval after it, rather than the
+    /// payments missed in between firing back to back
+    pub fn record_execution(&mut self, now: i64) -> Result<(), ProgramError> {
+        match self.next_due() {
+            None => {
+                msg!("WalletError: Scheduled payment is finished");
+                Err(WalletError::ScheduleFinished.into())
+            }
+            Some(due) if due > now => {
+                msg!(&format!("next payment is due at {}", due));
+                Err(WalletError::PaymentNotDue.into())
+            }
+            Some(due) => {
+                let late = now.checked_sub(due).ok_or_else(|| {
+                    msg!(&format!("payment due at {} cannot be made at {}", due, now));
+                    ProgramError::InvalidAccountData
+                })?;
+                self.start = self.start.saturating_add(late);
+                self.executed = self.executed.saturating_add(1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Unpack from slice
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        if is_closed_account(input) {
+            msg!("scheduled payment account has been closed");
+            return Err(WalletError::AccountClosed.into());
+        }
+        if !input.starts_with(&Self::DISCRIMINATOR) {
+            msg!("account is not a scheduled payment");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let invalid = |_| ProgramError::InvalidAccountData;
+        let mut current = Self::DISCRIMINATOR.len();
+        let wallet = read_pubkey(&mut current, input).map_err(invalid)?;
+        let id = read_u64(&mut current, input).map_err(invalid)?;
+        let recipient = read_pubkey(&mut current, input).map_err(invalid)?;
+        let mint = read_pubkey(&mut current, input).map_err(invalid)?;
+        let amount = read_u64(&mut current, input).map_err(invalid)?;
+        let interval = read_u64(&mut current, input).map_err(invalid)?;
+        let start = read_u64(&mut current, input).map_err(invalid)? as i64;
+        let end = read_u64(&mut current, input).map_err(invalid)? as i64;
+        let count = read_u64(&mut current, input).map_err(invalid)?;
+        let executed = read_u64(&mut current, input).map_err(invalid)?;
+
+        Ok(ScheduledPayment {
+            wallet,
+            id,
+            recipient,
+            mint: if mint == Pubkey::default() {
+                None
+            } else {
+                Some(mint)
+            },
+            amount,
+            interval,
+            start,
+            end,
+            count,
+            executed,
+        })
+    }
+
+    /// Pack into slice
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < Self::LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        let mut current = 0;
+        dst[..Self::DISCRIMINATOR.len()].copy_from_slice(&Self::DISCRIMINATOR);
+        current += Self::DISCRIMINATOR.len();
+        write_pubkey(&mut current, &self.wallet, dst)?;
+        write_u64(&mut current, self.id, dst)?;
+        write_pubkey(&mut current, &self.recipient, dst)?;
+        write_pubkey(&mut current, &self.mint.unwrap_or_default(), dst)?;
+        write_u64(&mut current, self.amount, dst)?;
+        write_u64(&mut current, self.interval, dst)?;
+        write_u64(&mut current, self.start as u64, dst)?;
+        write_u64(&mut current, self.end as u64, dst)?;
+        write_u64(&mut current, self.count, dst)?;
+        write_u64(&mut current, self.executed, dst)?;
+
+        Ok(())
+    }
+}
+
+/// Stream, a PDA of the wallet vesting a total to a recipient linearly between start and end
+#[repr(C)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stream {
+    /// the wallet paying
+    pub wallet: Pubkey,
+    /// id telling the wallet streams apart
+    pub id: u64,
+    /// key allowed to withdraw the vested amount
+    pub recipient: Pubkey,
+    /// token mint paid, None pays SOL
+    pub mint: Option<Pubkey>,
+    /// amount vested at the end
+    pub total: u64,
+    /// unix timestamp vesting starts at
+    pub start: i64,
+    /// nothing can be withdrawn before this unix timestamp
+    pub cliff: i64,
+    /// unix timestamp the total is vested at
+    pub end: i64,
+    /// amount withdrawn so far
+    pub withdrawn: u64,
+}
+
+impl Stream {
+    /*
+        Stream Len = discriminator + wallet + id + recipient + mint + total + start + cliff + end + withdrawn
+                   =       8       +   32   +  8 +    32     +  32  +   8   +   8   +   8   +  8  +     8
+    */
+
+    /// Written at the start of streams so they cannot be mistaken for other accounts
+    pub const DISCRIMINATOR: [u8; 8] = *b"stream\0\0";
+
+    /// Seed prefix of the stream address
+    pub const SEED: &'static [u8] = b"stream";
+
+    /// Length of the packed stream
+    pub const LEN: usize = 152;
+
+    /// Amount vested at `now`
+    pub fn vested(&self, now: i64) -> u64 {
+        if now < self.cliff {
+            return 0;
+        }
+        if now >= self.end {
+            return self.total;
+        }
+        let elapsed = (now as i128 - self.start as i128).max(0) as u128;
+        let duration = (self.end as i128 - self.start as i128) as u128;
+        (u128::from(self.total) * elapsed / duration) as u64
+    }
+
+    /// Amount vested at `now` and not withdrawn yet
+    pub fn withdrawable(&self, now: i64) -> u64 {
+        self.vested(now).saturating_sub(self.withdrawn)
+    }
+
+    /// Unpack from slice
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        if is_closed_account(input) {
+            msg!("stream account has been closed");
+            return Err(WalletError::AccountClosed.into());
+        }
+        if !input.starts_with(&Self::DISCRIMINATOR) {
+            msg!("account is not a stream");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let invalid = |_| ProgramError::InvalidAccountData;
+        let mut current = Self::DISCRIMINATOR.len();
+        let wallet = read_pubkey(&mut current, input).map_err(invalid)?;
+        let id = read_u64(&mut current, input).map_err(invalid)?;
+        let recipient = read_pubkey(&mut current, input).map_err(invalid)?;
+        let mint = read_pubkey(&mut current, input).map_err(invalid)?;
+        let total = read_u64(&mut current, input).map_err(invalid)?;
+        let start = read_u64(&mut current, input).map_err(invalid)? as i64;
+        let cliff = read_u64(&mut current, input).map_err(invalid)? as i64;
+        let end = read_u64(&mut current, input).map_err(invalid)? as i64;
+        let withdrawn = read_u64(&mut current, input).map_err(invalid)?;
+
+        Ok(Stream {
+            wallet,
+            id,
+            recipient,
+            mint: if mint == Pubkey::default() {
+                None
+            } else {
+                Some(mint)
+            },
+            total,
+            start,
+            cliff,
+            end,
+            withdrawn,
+        })
+    }
+
+    /// Pack into slice
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < Self::LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        let mut current = 0;
+        dst[..Self::DISCRIMINATOR.len()].copy_from_slice(&Self::DISCRIMINATOR);
+        current += Self::DISCRIMINATOR.len();
+        write_pubkey(&mut current, &self.wallet, dst)?;
+        write_u64(&mut current, self.id, dst)?;
+        write_pubkey(&mut current, &self.recipient, dst)?;
+        write_pubkey(&mut current, &self.mint.unwrap_or_default(), dst)?;
+        write_u64(&mut current, self.total, dst)?;
+        write_u64(&mut current, self.start as u64, dst)?;
+        write_u64(&mut current, self.cliff as u64, dst)?;
+        write_u64(&mut current, self.end as u64, dst)?;
+        write_u64(&mut current, self.withdrawn, dst)?;
+
+        Ok(())
+    }
+}
+
+/// An authorized instruction recorded in the wallet history
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HistoryEntry {
+    /// slot the instruction executed in
+    pub slot: u64,
+    /// wallet instruction tag
+    pub kind: u8,
+    /// program the instruction targeted, the wallet program unless it invoked another program
+    pub program_id: Pubkey,
+    /// sha256 hash of the wallet instruction data
+    pub data_hash: [u8; 32],
+    /// owner weight that signed the instruction
+    pub weight: u64,
+    /// number of owners that signed the instruction
+    pub signers: u16,
+}
+
+/// Execution history, a PDA of the wallet keeping its latest authorized instructions in a ring
+#[repr(C)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct History {
+    /// the wallet the history belongs to
+    pub wallet: Pubkey,
+    /// number of entries the ring has room for
+    pub capacity: u16,
+    /// number of entries ever recorded, the next one goes to `total % capacity`
+    pub total: u64,
+    /// entries still in the ring, oldest first
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    /*
+        History Len = discriminator + wallet + capacity + total + (slot + kind + program_id + data_hash + weight + signers) * capacity
+                    =       8       +   32   +    2     +   8   + (  8  +  1   +     32     +     32    +   8    +    2   ) * capacity
+    */
+
+    /// Written at the start of histories so they cannot be mistaken for other accounts
+    pub const DISCRIMINATOR: [u8; 8] = *b"history\0";
+
+    /// Seed prefix of the history address
+    pub const SEED: &'static [u8] = b"history";
+
+    /// Length of the ring before the entries
+    pub const HEADER_LEN: usize = 50;
+
+    /// Length of each entry
+    pub const ENTRY_LEN: usize = 83;
+
+    /// Length of a history with room for `capacity` entries
+    pub fn get_packed_len(capacity: u16) -> usize {
+        Self::HEADER_LEN + usize::from(capacity) * Self::ENTRY_LEN
+    }
+
+    /// Unpack from slice
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        if !input.starts_with(&Self::DISCRIMINATOR) {
+            msg!("account is not a history");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let invalid = |_| ProgramError::InvalidAccountData;
+        let mut current = Self::DISCRIMINATOR.len();
+        let wallet = read_pubkey(&mut current, input).map_err(invalid)?;
+        let capacity = read_u16(&mut current, input).map_err(invalid)?;
+        let total = read_u64(&mut current, input).map_err(invalid)?;
+        if capacity == 0 || input.len() < Self::get_packed_len(capacity) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let len = total.min(u64::from(capacity));
+        let mut entries = Vec::new();
+        for position in total - len..total {
+            let mut current =
+                Self::HEADER_LEN + (position % u64::from(capacity)) as usize * Self::ENTRY_LEN;
+            let slot = read_u64(&mut current, input).map_err(invalid)?;
+            let kind = read_u8(&mut current, input).map_err(invalid)?;
+            let program_id = read_pubkey(&mut current, input).map_err(invalid)?;
+            let data_hash = read_pubkey(&mut current, input)
+                .map_err(invalid)?
+                .to_bytes();
+            let weight = read_u64(&mut current, input).map_err(invalid)?;
+            let signers = read_u16(&mut current, input).map_err(invalid)?;
+            entries.push(HistoryEntry {
+                slot,
+                kind,
+                program_id,
+                data_hash,
+                weight,
+                signers,
+            });
+        }
+
+        Ok(History {
+            wallet,
+            capacity,
+            total,
+            entries,
+        })
+    }
+
+    /// Pack into slice
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if self.capacity == 0
+            || self.entries.len() > usize::from(self.capacity)
+            || (self.entries.len() as u64) > self.total
+            || dst.len() < Self::get_packed_len(self.capacity)
+        {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        // reset all byte to 0
+        for i in dst.iter_mut() {
+            *i = 0;
+        }
+
+        let mut current = 0;
+        dst[..Self::DISCRIMINATOR.len()].copy_from_slice(&Self::DISCRIMINATOR);
+        current += Self::DISCRIMINATOR.len();
+        write_pubkey(&mut current, &self.wallet, dst)?;
+        write_u16(&mut current, self.capacity, dst)?;
+        write_u64(&mut current, self.total, dst)?;
+        let first = self.total - self.entries.len() as u64;
+        for (position, entry) in (first..).zip(&self.entries) {
+            Self::write_entry(position, self.capacity, entry, dst)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record an entry in place, overwriting the oldest one once the ring is full
+    pub fn append(
+        dst: &mut [u8],
+        wallet: &Pubkey,
+        entry: &HistoryEntry,
+    ) -> Result<(), ProgramError> {
+        if !dst.starts_with(&Self::DISCRIMINATOR) {
+            msg!("account is not a history");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let invalid = |_| ProgramError::InvalidAccountData;
+        let mut current = Self::DISCRIMINATOR.len();
+        if read_pubkey(&mut current, dst).map_err(invalid)? != *wallet {
+            msg!("history does not belong to the wallet");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let capacity = read_u16(&mut current, dst).map_err(invalid)?;
+        let total_offset = current;
+        let total = read_u64(&mut current, dst).map_err(invalid)?;
+        if capacity == 0 || dst.len() < Self::get_packed_len(capacity) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Self::write_entry(total, capacity, entry, dst)?;
+        let mut current = total_offset;
+        write_u64(&mut current, total.wrapping_add(1), dst)
+    }
+
+    fn write_entry(
+        position: u64,
+        capacity: u16,
+        entry: &HistoryEntry,
+        dst: &mut [u8],
+    ) -> Result<(), ProgramError> {
+        let mut current =
+            Self::HEADER_LEN + (position % u64::from(capacity)) as usize * Self::ENTRY_LEN;
+        write_u64(&mut current, entry.slot, dst)?;
+        dst[current] = entry.kind;
+        current += 1;
+        write_pubkey(&mut current, &entry.program_id, dst)?;
+        write_pubkey(&mut current, &Pubkey::new_from_array(entry.data_hash), dst)?;
+        write_u64(&mut current, entry.weight, dst)?;
+        write_u16(&mut current, entry.signers, dst)
+    }
+}
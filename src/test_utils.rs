@@ -0,0 +1,107 @@
+//! Account fixtures shared by the unit tests and the integration tests
+
+use crate::{
+    state::{Account, AccountState},
+    utils::get_wallet_vault_address,
+};
+use maplit::btreemap;
+use solana_program::{account_info::AccountInfo, bpf_loader, pubkey::Pubkey, system_program};
+
+/// An account owning the data an `AccountInfo` borrows
+pub struct TestAccount {
+    /// public key of the account
+    pub key: Pubkey,
+    /// whether the account signs the instruction
+    pub is_signer: bool,
+    /// whether the account is writable
+    pub is_writable: bool,
+    /// lamports held by the account
+    pub lamports: u64,
+    /// data held by the account
+    pub data: Vec<u8>,
+    /// program owning the account
+    pub owner: Pubkey,
+    /// whether the account holds a program
+    pub executable: bool,
+}
+
+impl TestAccount {
+    /// A writable account that does not sign
+    pub fn new(key: Pubkey, lamports: u64, owner: Pubkey) -> Self {
+        TestAccount {
+            key,
+            is_signer: false,
+            is_writable: true,
+            lamports,
+            data: vec![],
+            owner,
+            executable: false,
+        }
+    }
+
+    /// A system account signing the instruction
+    pub fn signer(key: Pubkey) -> Self {
+        TestAccount {
+            is_signer: true,
+            ..TestAccount::new(key, 0, system_program::id())
+        }
+    }
+
+    /// A wallet account holding `wallet_account`, sized for its maximum number of owners
+    pub fn wallet(key: Pubkey, program_id: Pubkey, wallet_account: &Account) -> Self {
+        let layout = wallet_account.layout;
+        let mut wallet = TestAccount::new(key, 0, program_id);
+        wallet.data = vec![0; layout.header_len() + wallet_account.max_owners * layout.owner_len()];
+        wallet_account.pack_into_slice(&mut wallet.data).unwrap();
+        wallet
+    }
+
+    /// A read-only program account
+    pub fn program(key: Pubkey) -> Self {
+        TestAccount {
+            is_writable: false,
+            executable: true,
+            ..TestAccount::new(key, 1, bpf_loader::id())
+        }
+    }
+}
+
+/// Borrow the test accounts as the account infos of an instruction
+pub fn account_infos(accounts: &mut [TestAccount]) -> Vec<AccountInfo<'_>> {
+    accounts
+        .iter_mut()
+        .map(|account| {
+            AccountInfo::new(
+                &account.key,
+                account.is_signer,
+                account.is_writable,
+                &mut account.lamports,
+                &mut account.data,
+                &account.owner,
+                account.executable,
+                0,
+            )
+        })
+        .collect()
+}
+
+/// A wallet address with a valid vault address, returns both
+pub fn new_wallet_with_vault(program_id: &Pubkey) -> (Pubkey, Pubkey) {
+    loop {
+        let wallet_key = Pubkey::new_unique();
+        if let Ok(vault_key) = get_wallet_vault_address(&wallet_key, program_id) {
+            return (wallet_key, vault_key);
+        }
+    }
+}
+
+/// An initialized wallet with `owner` as its only owner, holding the full threshold
+pub fn wallet_owned_by(owner: Pubkey) -> Account {
+    Account {
+        state: AccountState::Initialized,
+        owner_epoch: 1,
+        owners: btreemap! { owner => 1000 },
+        max_owners: 1,
+        ..Account::default()
+    }
+}
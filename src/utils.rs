@@ -1,12 +1,88 @@
 //! utils
+use crate::state::{Account, AddressBook, History, ScheduledPayment, Session, Stream};
 use solana_program::{
     instruction::{AccountMeta, Instruction},
     program_error::ProgramError,
-    pubkey::Pubkey,
+    pubkey::{Pubkey, PubkeyError},
     sanitize::SanitizeError,
     serialize_utils::{read_pubkey, read_u16, read_u8},
 };
 
+/// derive the vault address which signs on behalf of a wallet
+pub fn get_wallet_vault_address(
+    wallet: &Pubkey,
+    program_id: &Pubkey,
+) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[&wallet.to_bytes()], program_id)
+}
+
+/// derive the sub-vault address with the given index, with its bump seed. Index 0 is reserved
+/// for the wallet vault from `get_wallet_vault_address`
+pub fn get_sub_vault_address(wallet: &Pubkey, index: u16, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            Account::SUB_VAULT_SEED,
+            &wallet.to_bytes(),
+            &index.to_le_bytes(),
+        ],
+        program_id,
+    )
+}
+
+/// derive the session record address of a session key, with its bump seed
+pub fn get_session_address(
+    wallet: &Pubkey,
+    session_key: &Pubkey,
+    program_id: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[Session::SEED, &wallet.to_bytes(), &session_key.to_bytes()],
+        program_id,
+    )
+}
+
+/// derive the scheduled payment address of a schedule id, with its bump seed
+pub fn get_scheduled_payment_address(
+    wallet: &Pubkey,
+    id: u64,
+    program_id: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            ScheduledPayment::SEED,
+            &wallet.to_bytes(),
+            &id.to_le_bytes(),
+        ],
+        program_id,
+    )
+}
+
+/// derive the stream address of a stream id, with its bump seed
+pub fn get_stream_address(wallet: &Pubkey, id: u64, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[Stream::SEED, &wallet.to_bytes(), &id.to_le_bytes()],
+        program_id,
+    )
+}
+
+/// derive the authority a module program signs with for a wallet, with its bump seed
+pub fn get_module_authority_address(wallet: &Pubkey, module: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[Account::MODULE_AUTHORITY_SEED, &wallet.to_bytes()],
+        module,
+    )
+}
+
+/// derive the address book address of a wallet, with its bump seed
+pub fn get_address_book_address(wallet: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[AddressBook::SEED, &wallet.to_bytes()], program_id)
+}
+
+/// derive the history address of a wallet, with its bump seed
+pub fn get_history_address(wallet: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[History::SEED, &wallet.to_bytes()], program_id)
+}
+
 /// read a bool
 pub fn read_bool(current: &mut usize, data: &[u8]) -> Result<bool, SanitizeError> {
     if data.len() < *current + 1 {
@@ -33,6 +109,40 @@ pub fn write_bool(current: &mut usize, b: bool, dst: &mut [u8]) -> Result<(), Pr
     Ok(())
 }
 
+/// read a u64
+pub fn read_u64(current: &mut usize, data: &[u8]) -> Result<u64, SanitizeError> {
+    if data.len() < *current + 8 {
+        return Err(SanitizeError::IndexOutOfBounds);
+    }
+    let mut fixed_data = [0u8; 8];
+    fixed_data.copy_from_slice(&data[*current..*current + 8]);
+    let e = u64::from_le_bytes(fixed_data);
+    *current += 8;
+    Ok(e)
+}
+
+/// read a u32
+pub fn read_u32(current: &mut usize, data: &[u8]) -> Result<u32, SanitizeError> {
+    if data.len() < *current + 4 {
+        return Err(SanitizeError::IndexOutOfBounds);
+    }
+    let mut fixed_data = [0u8; 4];
+    fixed_data.copy_from_slice(&data[*current..*current + 4]);
+    let e = u32::from_le_bytes(fixed_data);
+    *current += 4;
+    Ok(e)
+}
+
+/// write a u32
+pub fn write_u32(current: &mut usize, src: u32, dst: &mut [u8]) -> Result<(), ProgramError> {
+    if dst.len() < *current + 4 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    dst[*current..*current + 4].copy_from_slice(&src.to_le_bytes());
+    *current += 4;
+    Ok(())
+}
+
 /// write a u16
 pub fn write_u16(current: &mut usize, src: u16, dst: &mut [u8]) -> Result<(), ProgramError> {
     if dst.len() < *current + 2 {
@@ -43,6 +153,16 @@ pub fn write_u16(current: &mut usize, src: u16, dst: &mut [u8]) -> Result<(), Pr
     Ok(())
 }
 
+/// write a u64
+pub fn write_u64(current: &mut usize, src: u64, dst: &mut [u8]) -> Result<(), ProgramError> {
+    if dst.len() < *current + 8 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    dst[*current..*current + 8].copy_from_slice(&src.to_le_bytes());
+    *current += 8;
+    Ok(())
+}
+
 /// write a pubkey
 pub fn write_pubkey(
     current: &mut usize,
@@ -59,11 +179,14 @@ pub fn write_pubkey(
 
 /// read an instruction
 pub fn read_instruction(current: &mut usize, input: &[u8]) -> Result<Instruction, ProgramError> {
-    let account_len = usize::from(read_u16(current, &input).unwrap());
+    let account_len =
+        usize::from(read_u16(current, input).or(Err(ProgramError::InvalidInstructionData))?);
     let mut accounts = Vec::new();
     for _ in 0..account_len {
-        let account_metadata = read_u8(current, &input).unwrap();
-        let account_pubkey = read_pubkey(current, &input).unwrap();
+        let account_metadata =
+            read_u8(current, input).or(Err(ProgramError::InvalidInstructionData))?;
+        let account_pubkey =
+            read_pubkey(current, input).or(Err(ProgramError::InvalidInstructionData))?;
 
         let account_meta = AccountMeta {
             pubkey: account_pubkey,
@@ -73,16 +196,20 @@ pub fn read_instruction(current: &mut usize, input: &[u8]) -> Result<Instruction
         accounts.push(account_meta);
     }
 
-    let program_id = read_pubkey(current, input).unwrap();
+    let program_id = read_pubkey(current, input).or(Err(ProgramError::InvalidInstructionData))?;
 
-    let data_len = usize::from(read_u16(current, &input).unwrap());
+    let data_len =
+        usize::from(read_u16(current, input).or(Err(ProgramError::InvalidInstructionData))?);
+    if input.len() < *current + data_len {
+        return Err(ProgramError::InvalidInstructionData);
+    }
     let data = input[*current..*current + data_len].to_vec();
     *current += data_len;
 
     Ok(Instruction {
-        program_id: program_id,
-        accounts: accounts,
-        data: data,
+        program_id,
+        accounts,
+        data,
     })
 }
 
@@ -0,0 +1,508 @@
+//! Token instructions run against the spl-token and associated token account programs.
+//!
+//! `solana-program-test` 1.8 no longer builds with current compilers, so cross-program
+//! invocations are dispatched in process by syscall stubs instead: the callee gets the accounts
+//! of the instruction with the signer privileges the runtime would grant, PDA signatures
+//! included, and the system program is reduced to the instructions the wallet and the
+//! associated token account program issue.
+
+use solana_contract_wallet::{
+    error::WalletError,
+    processor::Processor,
+    test_utils::{self, new_wallet_with_vault, wallet_owned_by, TestAccount},
+};
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::{ProgramResult, SUCCESS},
+    instruction::Instruction,
+    program_error::ProgramError,
+    program_option::COption,
+    program_pack::Pack,
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::SystemInstruction,
+    system_program, sysvar,
+};
+use spl_associated_token_account::get_associated_token_address;
+use spl_token::{
+    error::TokenError,
+    state::{Account as TokenAccount, AccountState as TokenAccountState, Mint},
+};
+use std::{cell::RefCell, sync::Once};
+
+const DECIMALS: u8 = 6;
+
+thread_local! {
+    /// programs currently executing, the innermost last
+    static CALL_STACK: RefCell<Vec<Pubkey>> = const { RefCell::new(Vec::new()) };
+    /// where the owners of the test accounts are stored, the system program assigns through them
+    static OWNERS: RefCell<Vec<(Pubkey, *mut Pubkey)>> = const { RefCell::new(Vec::new()) };
+}
+
+struct CpiSyscallStubs;
+
+impl SyscallStubs for CpiSyscallStubs {
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        let caller = CALL_STACK.with(|stack| *stack.borrow().last().unwrap());
+        let pda_signers = signers_seeds
+            .iter()
+            .map(|seeds| Pubkey::create_program_address(seeds, &caller))
+            .collect::<Result<Vec<_>, _>>()
+            .or(Err(ProgramError::InvalidSeeds))?;
+
+        let mut callee_account_infos = Vec::with_capacity(instruction.accounts.len());
+        for account_meta in &instruction.accounts {
+            let account_info = account_infos
+                .iter()
+                .find(|account| *account.key == account_meta.pubkey)
+                .ok_or(ProgramError::NotEnoughAccountKeys)?;
+            if account_meta.is_signer
+                && !account_info.is_signer
+                && !pda_signers.contains(account_info.key)
+            {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            if account_meta.is_writable && !account_info.is_writable {
+                return Err(ProgramError::InvalidArgument);
+            }
+            callee_account_infos.push(AccountInfo {
+                is_signer: account_meta.is_signer,
+                is_writable: account_meta.is_writable,
+                ..account_info.clone()
+            });
+        }
+
+        process_instruction(
+            &instruction.program_id,
+            &callee_account_infos,
+            &instruction.data,
+        )
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        SUCCESS
+    }
+}
+
+fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    input: &[u8],
+) -> ProgramResult {
+    CALL_STACK.with(|stack| stack.borrow_mut().push(*program_id));
+    let result = if *program_id == solana_contract_wallet::id() {
+        Processor::process(program_id, accounts, input)
+    } else if *program_id == spl_token::id() {
+        spl_token::processor::Processor::process(program_id, accounts, input)
+    } else if *program_id == spl_associated_token_account::id() {
+        spl_associated_token_account::processor::process_instruction(program_id, accounts, input)
+    } else if *program_id == system_program::id() {
+        process_system_instruction(accounts, input)
+    } else {
+        Err(ProgramError::IncorrectProgramId)
+    };
+    CALL_STACK.with(|stack| stack.borrow_mut().pop());
+    result
+}
+
+/// The system instructions used to pay and create accounts
+fn process_system_instruction(accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
+    let instruction = bincode::deserialize(input).or(Err(ProgramError::InvalidInstructionData))?;
+    let account_info = accounts.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+    if !account_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    match instruction {
+        SystemInstruction::Transfer { lamports } => {
+            let destination_account_info =
+                accounts.get(1).ok_or(ProgramError::NotEnoughAccountKeys)?;
+            let remaining_lamports = account_info
+                .lamports()
+                .checked_sub(lamports)
+                .ok_or(ProgramError::InsufficientFunds)?;
+            **account_info.try_borrow_mut_lamports()? = remaining_lamports;
+            **destination_account_info.try_borrow_mut_lamports()? += lamports;
+        }
+        SystemInstruction::Allocate { space } => {
+            if *account_info.owner != system_program::id() || !account_info.data_is_empty() {
+                return Err(ProgramError::AccountAlreadyInitialized);
+            }
+            *account_info.try_borrow_mut_data()? =
+                Box::leak(vec![0; space as usize].into_boxed_slice());
+        }
+        SystemInstruction::Assign { owner } => {
+            if *account_info.owner != system_program::id() {
+                return Err(ProgramError::IllegalOwner);
+            }
+            // the owner is not behind a RefCell, so it is written in place in the test account
+            let account_owner = OWNERS
+                .with(|owners| {
+                    owners
+                        .borrow()
+                        .iter()
+                        .find(|(key, _)| key == account_info.key)
+                        .map(|(_, owner)| *owner)
+                })
+                .ok_or(ProgramError::NotEnoughAccountKeys)?;
+            unsafe { std::ptr::write_volatile(account_owner, owner) };
+        }
+        _ => return Err(ProgramError::InvalidInstructionData),
+    }
+    Ok(())
+}
+
+fn install_cpi_syscall_stubs() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        set_syscall_stubs(Box::new(CpiSyscallStubs));
+    });
+}
+
+fn rent_sysvar() -> TestAccount {
+    TestAccount {
+        is_writable: false,
+        data: bincode::serialize(&Rent::default()).unwrap(),
+        ..TestAccount::new(sysvar::rent::id(), 1, sysvar::id())
+    }
+}
+
+fn mint(key: Pubkey, supply: u64) -> TestAccount {
+    let mut mint = TestAccount::new(key, 1, spl_token::id());
+    mint.data = vec![0; Mint::LEN];
+    Mint::pack(
+        Mint {
+            mint_authority: COption::Some(Pubkey::new_unique()),
+            supply,
+            decimals: DECIMALS,
+            is_initialized: true,
+            freeze_authority: COption::None,
+        },
+        &mut mint.data,
+    )
+    .unwrap();
+    mint
+}
+
+fn token_account(key: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) -> TestAccount {
+    let mut account = TestAccount::new(
+        key,
+        Rent::default().minimum_balance(TokenAccount::LEN),
+        spl_token::id(),
+    );
+    account.data = vec![0; TokenAccount::LEN];
+    TokenAccount::pack(
+        TokenAccount {
+            mint,
+            owner,
+            amount,
+            state: TokenAccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &mut account.data,
+    )
+    .unwrap();
+    account
+}
+
+/// The account infos of the test accounts, remembering where their owners are kept
+fn account_infos(accounts: &mut [TestAccount]) -> Vec<AccountInfo<'_>> {
+    OWNERS.with(|owners| {
+        *owners.borrow_mut() = accounts
+            .iter_mut()
+            .map(|account| (account.key, &mut account.owner as *mut Pubkey))
+            .collect()
+    });
+    test_utils::account_infos(accounts)
+}
+
+fn token_account_state(account_info: &AccountInfo) -> TokenAccount {
+    TokenAccount::unpack(&account_info.data.borrow()).unwrap()
+}
+
+/// A wallet owned by a single owner with a funded vault, returns the wallet, the vault and the
+/// owner accounts
+fn new_wallet() -> (TestAccount, TestAccount, TestAccount) {
+    let program_id = solana_contract_wallet::id();
+    let (wallet_key, vault_key) = new_wallet_with_vault(&program_id);
+    let owner_key = Pubkey::new_unique();
+    (
+        TestAccount::wallet(wallet_key, program_id, &wallet_owned_by(owner_key)),
+        TestAccount::new(vault_key, 1_000_000_000, system_program::id()),
+        TestAccount::signer(owner_key),
+    )
+}
+
+fn process(accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
+    install_cpi_syscall_stubs();
+    process_instruction(&solana_contract_wallet::id(), accounts, input)
+}
+
+fn token_instruction_data(tag: u8, amount: u64, decimals: u8) -> Vec<u8> {
+    let mut data = vec![tag];
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.push(decimals);
+    data
+}
+
+#[test]
+fn transfer_lamports_should_pay_from_the_vault() {
+    let (wallet, vault, owner) = new_wallet();
+    let destination_key = Pubkey::new_unique();
+    let mut accounts = vec![
+        wallet,
+        vault,
+        TestAccount::new(destination_key, 0, system_program::id()),
+        TestAccount::program(system_program::id()),
+        owner,
+    ];
+    let account_infos = account_infos(&mut accounts);
+
+    let mut data = vec![12];
+    data.extend_from_slice(&400_000_000u64.to_le_bytes());
+    assert_eq!(process(&account_infos, &data), Ok(()));
+    assert_eq!(account_infos[1].lamports(), 600_000_000);
+    assert_eq!(account_infos[2].lamports(), 400_000_000);
+}
+
+#[test]
+fn token_transfer_should_transfer_checked_from_the_vault() {
+    let (wallet, vault, owner) = new_wallet();
+    let mint_key = Pubkey::new_unique();
+    let vault_key = vault.key;
+    let mut accounts = vec![
+        wallet,
+        vault,
+        token_account(Pubkey::new_unique(), mint_key, vault_key, 100),
+        mint(mint_key, 100),
+        token_account(Pubkey::new_unique(), mint_key, Pubkey::new_unique(), 0),
+        TestAccount::program(spl_token::id()),
+        owner,
+    ];
+    let account_infos = account_infos(&mut accounts);
+
+    assert_eq!(
+        process(&account_infos, &token_instruction_data(13, 40, DECIMALS)),
+        Ok(())
+    );
+    assert_eq!(token_account_state(&account_infos[2]).amount, 60);
+    assert_eq!(token_account_state(&account_infos[4]).amount, 40);
+
+    // transfer_checked holds the amount to the mint decimals
+    assert_eq!(
+        process(
+            &account_infos,
+            &token_instruction_data(13, 40, DECIMALS + 1)
+        ),
+        Err(TokenError::MintDecimalsMismatch.into())
+    );
+    assert_eq!(
+        process(&account_infos, &token_instruction_data(13, 61, DECIMALS)),
+        Err(TokenError::InsufficientFunds.into())
+    );
+    assert_eq!(token_account_state(&account_infos[2]).amount, 60);
+}
+
+#[test]
+fn token_transfer_should_require_owner_signatures() {
+    let (wallet, vault, mut owner) = new_wallet();
+    owner.is_signer = false;
+    let mint_key = Pubkey::new_unique();
+    let vault_key = vault.key;
+    let mut accounts = vec![
+        wallet,
+        vault,
+        token_account(Pubkey::new_unique(), mint_key, vault_key, 100),
+        mint(mint_key, 100),
+        token_account(Pubkey::new_unique(), mint_key, Pubkey::new_unique(), 0),
+        TestAccount::program(spl_token::id()),
+        owner,
+    ];
+    let account_infos = account_infos(&mut accounts);
+
+    assert_eq!(
+        process(&account_infos, &token_instruction_data(13, 40, DECIMALS)),
+        Err(WalletError::InsufficientWeight.into())
+    );
+    assert_eq!(token_account_state(&account_infos[2]).amount, 100);
+}
+
+#[test]
+fn token_transfer_should_not_spend_tokens_the_vault_does_not_own() {
+    let (wallet, vault, owner) = new_wallet();
+    let mint_key = Pubkey::new_unique();
+    let mut accounts = vec![
+        wallet,
+        vault,
+        token_account(Pubkey::new_unique(), mint_key, Pubkey::new_unique(), 100),
+        mint(mint_key, 100),
+        token_account(Pubkey::new_unique(), mint_key, Pubkey::new_unique(), 0),
+        TestAccount::program(spl_token::id()),
+        owner,
+    ];
+    let account_infos = account_infos(&mut accounts);
+
+    assert_eq!(
+        process(&account_infos, &token_instruction_data(13, 40, DECIMALS)),
+        Err(TokenError::OwnerMismatch.into())
+    );
+}
+
+#[test]
+fn approve_token_delegate_should_approve_and_revoke() {
+    let (wallet, vault, owner) = new_wallet();
+    let mint_key = Pubkey::new_unique();
+    let delegate_key = Pubkey::new_unique();
+    let vault_key = vault.key;
+    let mut accounts = vec![
+        wallet,
+        vault,
+        token_account(Pubkey::new_unique(), mint_key, vault_key, 100),
+        mint(mint_key, 100),
+        TestAccount::new(delegate_key, 0, system_program::id()),
+        TestAccount::program(spl_token::id()),
+        owner,
+    ];
+    let account_infos = account_infos(&mut accounts);
+
+    assert_eq!(
+        process(&account_infos, &token_instruction_data(15, 25, DECIMALS)),
+        Ok(())
+    );
+    let token_account = token_account_state(&account_infos[2]);
+    assert_eq!(token_account.delegate, COption::Some(delegate_key));
+    assert_eq!(token_account.delegated_amount, 25);
+
+    // wallet, vault, token account, token program, owner
+    let revoke_account_infos = [
+        account_infos[0].clone(),
+        account_infos[1].clone(),
+        account_infos[2].clone(),
+        account_infos[5].clone(),
+        account_infos[6].clone(),
+    ];
+    assert_eq!(process(&revoke_account_infos, &[16]), Ok(()));
+    let token_account = token_account_state(&account_infos[2]);
+    assert_eq!(token_account.delegate, COption::None);
+    assert_eq!(token_account.delegated_amount, 0);
+}
+
+#[test]
+fn create_associated_token_account_should_create_and_fund_the_recipient_account() {
+    let (wallet, vault, owner) = new_wallet();
+    let mint_key = Pubkey::new_unique();
+    let recipient_key = Pubkey::new_unique();
+    let associated_key = get_associated_token_address(&recipient_key, &mint_key);
+    let vault_key = vault.key;
+    let mut accounts = vec![
+        wallet,
+        vault,
+        TestAccount::new(associated_key, 0, system_program::id()),
+        TestAccount::new(recipient_key, 0, system_program::id()),
+        mint(mint_key, 100),
+        TestAccount::program(system_program::id()),
+        TestAccount::program(spl_token::id()),
+        rent_sysvar(),
+        TestAccount::program(spl_associated_token_account::id()),
+        owner,
+        token_account(Pubkey::new_unique(), mint_key, vault_key, 100),
+    ];
+    let account_infos = account_infos(&mut accounts);
+
+    let rent_exempt_lamports = Rent::default().minimum_balance(TokenAccount::LEN);
+    assert_eq!(process(&account_infos[..10], &[14]), Ok(()));
+    assert_eq!(*account_infos[2].owner, spl_token::id());
+    assert_eq!(account_infos[2].lamports(), rent_exempt_lamports);
+    assert_eq!(
+        account_infos[1].lamports(),
+        1_000_000_000 - rent_exempt_lamports
+    );
+    let associated_account = token_account_state(&account_infos[2]);
+    assert_eq!(associated_account.mint, mint_key);
+    assert_eq!(associated_account.owner, recipient_key);
+    assert_eq!(associated_account.amount, 0);
+
+    // the existing account is kept as is
+    assert_eq!(process(&account_infos[..10], &[14]), Ok(()));
+    assert_eq!(
+        account_infos[1].lamports(),
+        1_000_000_000 - rent_exempt_lamports
+    );
+
+    // and can receive tokens from the vault
+    let transfer_account_infos = [
+        account_infos[0].clone(),
+        account_infos[1].clone(),
+        account_infos[10].clone(),
+        account_infos[4].clone(),
+        account_infos[2].clone(),
+        account_infos[6].clone(),
+        account_infos[9].clone(),
+    ];
+    assert_eq!(
+        process(
+            &transfer_account_infos,
+            &token_instruction_data(13, 30, DECIMALS)
+        ),
+        Ok(())
+    );
+    assert_eq!(token_account_state(&account_infos[2]).amount, 30);
+    assert_eq!(token_account_state(&account_infos[10]).amount, 70);
+}
+
+#[test]
+fn create_associated_token_account_should_reject_another_address() {
+    let (wallet, vault, owner) = new_wallet();
+    let mint_key = Pubkey::new_unique();
+    let recipient_key = Pubkey::new_unique();
+    let mut accounts = vec![
+        wallet,
+        vault,
+        TestAccount::new(Pubkey::new_unique(), 0, system_program::id()),
+        TestAccount::new(recipient_key, 0, system_program::id()),
+        mint(mint_key, 100),
+        TestAccount::program(system_program::id()),
+        TestAccount::program(spl_token::id()),
+        rent_sysvar(),
+        TestAccount::program(spl_associated_token_account::id()),
+        owner,
+    ];
+    let account_infos = account_infos(&mut accounts);
+
+    assert_eq!(
+        process(&account_infos, &[14]),
+        Err(ProgramError::InvalidSeeds)
+    );
+    assert_eq!(account_infos[1].lamports(), 1_000_000_000);
+}
+
+#[test]
+fn close_token_account_should_return_rent_to_the_vault() {
+    let (wallet, vault, owner) = new_wallet();
+    let mint_key = Pubkey::new_unique();
+    let vault_key = vault.key;
+    let token_account = token_account(Pubkey::new_unique(), mint_key, vault_key, 0);
+    let token_account_lamports = token_account.lamports;
+    let mut accounts = vec![
+        wallet,
+        vault,
+        token_account,
+        TestAccount::program(spl_token::id()),
+        owner,
+    ];
+    let account_infos = account_infos(&mut accounts);
+
+    assert_eq!(process(&account_infos, &[17]), Ok(()));
+    assert_eq!(account_infos[2].lamports(), 0);
+    assert_eq!(
+        account_infos[1].lamports(),
+        1_000_000_000 + token_account_lamports
+    );
+}